@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+
+use crate::ParseError;
+use crate::meter::Meter;
+use crate::score::{Part, Score, ScoreNote, dynamic_volume, midi_to_frequency};
+
+/*
+ABC notation (https://abcnotation.com/wiki/abc:standard:v2.1), covering the
+X: T: M: L: Q: K: and V: fields, notes with accidentals, octaves, lengths,
+broken rhythms and tuplets, rests, ties, chords, bar lines, repeats with first
+and second endings, and dynamics. Grace notes, chord symbols, lyrics and other
+decorations are skipped.
+*/
+
+/// One thing in a voice, before the repeats are played out.
+enum Item {
+    Note{duration: f64, pitches: Vec<i32>, tie: bool, volume: f64}, // Beats and MIDI note numbers, no pitches for a rest
+    DoubleBar,
+    RepeatStart,
+    RepeatEnd,
+    Ending(u32),
+}
+
+/// Sharps (positive) or flats of each letter from C to B in a key like `G`, `Bb`, `Am` or `Ddor`.
+fn key_signature (key: &str) -> [i32; 7] {
+    // Like `G`, `G major` or `Gmaj clef=bass`
+    let key: String = key.split_whitespace().take_while(|e| !e.contains('=')).collect();
+    let mut chars = key.chars();
+    let tonic: i32 = match chars.next() {
+	Option::Some('C') => { 0 }, Option::Some('G') => { 1 }, Option::Some('D') => { 2 }, Option::Some('A') => { 3 },
+	Option::Some('E') => { 4 }, Option::Some('B') => { 5 }, Option::Some('F') => { -1 },
+	_ => { return [0; 7]; } // No key, or HP bagpipe tunings
+    };
+    let rest: String = chars.collect::<String>().to_lowercase();
+    let (accidental, mode): (i32, &str) = if let Option::Some(mode) = rest.strip_prefix('#') {
+	(7, mode)
+    } else if let Option::Some(mode) = rest.strip_prefix('b') {
+	(-7, mode)
+    } else {
+	(0, rest.as_str())
+    };
+    let shift: i32 = match mode.get(..3.min(mode.len())).unwrap_or("") {
+	"" | "maj" | "ion" => { 0 },
+	"m" | "min" | "aeo" => { -3 },
+	"mix" => { -1 },
+	"dor" => { -2 },
+	"phr" => { -4 },
+	"lyd" => { 1 },
+	"loc" => { -5 },
+	_ => { if mode.starts_with('m') { -3 } else { 0 } }
+    };
+    let fifths: i32 = tonic + accidental + shift;
+    let mut signature: [i32; 7] = [0; 7];
+    // Sharps go F C G D A E B, and flats the other way
+    let order: [usize; 7] = [3, 0, 4, 1, 5, 2, 6];
+    for i in 0..fifths.unsigned_abs().min(7) as usize {
+	if fifths > 0 { signature[order[i]] = 1; } else { signature[order[6 - i]] = -1; }
+    }
+    signature
+}
+
+/// A fraction like `3/4`, `C` or `C|`, as a number of whole notes.
+fn parse_fraction (s: &str) -> Option<(u32, u32)> {
+    match s.trim() {
+	"C" => { Option::Some((4, 4)) },
+	"C|" => { Option::Some((2, 2)) },
+	s => { Meter::parse_signature(s).ok() }
+    }
+}
+
+/// Quarter notes per minute from a tempo like `1/4=120`, `3/8=60` or `"Allegro" 1/4=120`.
+fn parse_tempo (s: &str, unit: f64) -> Option<f64> {
+    let s: &str = s.rsplit('"').next().unwrap_or(s).trim();
+    match s.split_once('=') {
+	Option::Some((beat, bpm)) => {
+	    let beat: f64 = beat.split_whitespace().filter_map(|e| parse_fraction(e)).map(|(n, d)| n as f64 / d as f64).sum();
+	    bpm.trim().parse::<f64>().ok().map(|bpm| bpm * beat * 4.0)
+	},
+	// Old style, in units of the note length
+	Option::None => { s.parse::<f64>().ok().map(|bpm| bpm * unit * 4.0) }
+    }
+}
+
+struct Reader {
+    unit: f64, // The L: length, in whole notes
+    meter: Option<(u32, u32)>,
+    tempo: Option<f64>,
+    key: [i32; 7],
+    accidentals: HashMap<(usize, i32), i32>, // Accidentals written in the bar so far, by letter and octave
+    volume: f64,
+    voices: Vec<(String, Vec<Item>)>,
+    voice: usize,
+    broken: f64, // Scalar for the next note, after a > or <
+    tuplet: Option<(f64, u32)>, // Scalar for the next few notes, and how many are left
+}
+
+impl Reader {
+    fn items (&mut self) -> &mut Vec<Item> {
+	&mut self.voices[self.voice].1
+    }
+    fn field (&mut self, name: char, value: &str) -> () {
+	match name {
+	    'T' => {},
+	    'M' => {
+		self.meter = parse_fraction(value);
+		if self.unit == 0.0 {
+		    // The default length depends on the meter
+		    let (n, d): (u32, u32) = self.meter.unwrap_or((4, 4));
+		    self.unit = if (n as f64 / d as f64) < 0.75 { 1.0 / 16.0 } else { 1.0 / 8.0 };
+		}
+	    },
+	    'L' => {
+		if let Option::Some((n, d)) = parse_fraction(value) { self.unit = n as f64 / d as f64; }
+	    },
+	    'Q' => {
+		if self.tempo.is_none() {
+		    self.tempo = parse_tempo(value, if self.unit == 0.0 { 1.0 / 8.0 } else { self.unit });
+		}
+	    },
+	    'K' => {
+		self.key = key_signature(value);
+		if self.unit == 0.0 { self.unit = 1.0 / 8.0; }
+	    },
+	    'V' => {
+		let name: &str = value.split_whitespace().next().unwrap_or("1");
+		self.voice = match self.voices.iter().position(|(e, _)| e == name) {
+		    Option::Some(index) => { index },
+		    Option::None => {
+			// The first voice to be named takes over the notes from before any V: line
+			if self.voices.len() == 1 && self.voices[0].0.is_empty() {
+			    self.voices[0].0 = name.to_string();
+			} else {
+			    self.voices.push((name.to_string(), Vec::<Item>::new()));
+			}
+			self.voices.len() - 1
+		    }
+		};
+	    },
+	    _ => {}
+	}
+    }
+    /// Reads a length after a note, as a multiple of the unit.
+    fn length (chars: &[char], i: &mut usize) -> f64 {
+	let number = |i: &mut usize| -> Option<f64> {
+	    let start: usize = *i;
+	    while *i < chars.len() && chars[*i].is_ascii_digit() { *i += 1; }
+	    chars[start..*i].iter().collect::<String>().parse().ok()
+	};
+	let mut length: f64 = number(i).unwrap_or(1.0);
+	if *i < chars.len() && chars[*i] == '/' {
+	    let mut slashes: i32 = 0;
+	    while *i < chars.len() && chars[*i] == '/' { slashes += 1; *i += 1; }
+	    length /= match (slashes, number(i)) {
+		(1, Option::Some(d)) => { d },
+		_ => { 2.0_f64.powi(slashes) }
+	    };
+	}
+	length
+    }
+    /// Reads a pitch, like `^c'`, and returns its MIDI note number.
+    fn pitch (&mut self, chars: &[char], i: &mut usize) -> Option<i32> {
+	let mut accidental: Option<i32> = Option::None;
+	while *i < chars.len() && matches!(chars[*i], '^' | '_' | '=') {
+	    accidental = Option::Some(accidental.unwrap_or(0) + match chars[*i] { '^' => { 1 }, '_' => { -1 }, _ => { 0 } });
+	    *i += 1;
+	}
+	let c: char = *chars.get(*i)?;
+	let letter: usize = "CDEFGAB".find(c.to_ascii_uppercase())?;
+	*i += 1;
+	let mut octave: i32 = if c.is_ascii_lowercase() { 5 } else { 4 };
+	while *i < chars.len() && matches!(chars[*i], '\'' | ',') {
+	    octave += if chars[*i] == '\'' { 1 } else { -1 };
+	    *i += 1;
+	}
+	let alter: i32 = match accidental {
+	    Option::Some(alter) => {
+		self.accidentals.insert((letter, octave), alter);
+		alter
+	    },
+	    Option::None => { *self.accidentals.get(&(letter, octave)).unwrap_or(&self.key[letter]) }
+	};
+	Option::Some(12 * (octave + 1) + [0, 2, 4, 5, 7, 9, 11][letter] + alter)
+    }
+    /// Scales a note by any broken rhythm or tuplet it's part of.
+    fn scale (&mut self) -> f64 {
+	let mut scale: f64 = self.broken;
+	self.broken = 1.0;
+	if let Option::Some((tuplet, left)) = self.tuplet {
+	    scale *= tuplet;
+	    self.tuplet = if left > 1 { Option::Some((tuplet, left - 1)) } else { Option::None };
+	}
+	scale
+    }
+    fn push_note (&mut self, duration: f64, pitches: Vec<i32>, tie: bool) -> () {
+	let volume: f64 = self.volume;
+	self.items().push(Item::Note{duration: duration, pitches: pitches, tie: tie, volume: volume});
+    }
+    fn bar (&mut self) -> () {
+	self.accidentals.clear();
+    }
+    /// Reads a line of music.
+    fn music (&mut self, line: &str) -> Result<(), ParseError> {
+	let chars: Vec<char> = line.chars().collect();
+	let mut i: usize = 0;
+	while i < chars.len() {
+	    let c: char = chars[i];
+	    match c {
+		'A'..='G' | 'a'..='g' | '^' | '_' | '=' => {
+		    let pitch: i32 = self.pitch(&chars, &mut i).ok_or(ParseError)?;
+		    let duration: f64 = Self::length(&chars, &mut i) * self.unit * 4.0 * self.scale();
+		    let tie: bool = chars.get(i) == Option::Some(&'-');
+		    if tie { i += 1; }
+		    self.push_note(duration, vec![pitch], tie);
+		},
+		'z' | 'x' => {
+		    i += 1;
+		    let duration: f64 = Self::length(&chars, &mut i) * self.unit * 4.0 * self.scale();
+		    self.push_note(duration, Vec::<i32>::new(), false);
+		},
+		'Z' | 'X' => {
+		    // Whole bars of rest
+		    i += 1;
+		    let (n, d): (u32, u32) = self.meter.unwrap_or((4, 4));
+		    let duration: f64 = Self::length(&chars, &mut i) * n as f64 * 4.0 / d as f64;
+		    self.push_note(duration, Vec::<i32>::new(), false);
+		},
+		'[' if chars.get(i + 2) == Option::Some(&':') && chars.get(i + 1).is_some_and(|e| e.is_ascii_alphabetic()) => {
+		    // An inline field like [K:G]
+		    let end: usize = chars[i..].iter().position(|e| *e == ']').map(|e| e + i).unwrap_or(chars.len());
+		    let value: String = chars[i + 3..end].iter().collect();
+		    self.field(chars[i + 1], value.trim());
+		    i = end + 1;
+		},
+		'[' if chars.get(i + 1).is_some_and(|e| e.is_ascii_digit()) => {
+		    self.items().push(Item::Ending(chars[i + 1].to_digit(10).unwrap()));
+		    i += 2;
+		},
+		'[' if chars.get(i + 1) == Option::Some(&'|') => {
+		    self.bar();
+		    self.items().push(Item::DoubleBar);
+		    i += 2;
+		},
+		'[' => {
+		    // A chord, its length is the first note's
+		    i += 1;
+		    let mut pitches: Vec<i32> = Vec::<i32>::new();
+		    let mut first: Option<f64> = Option::None;
+		    let mut tie: bool = false;
+		    while i < chars.len() && chars[i] != ']' {
+			match self.pitch(&chars, &mut i) {
+			    Option::Some(pitch) => {
+				pitches.push(pitch);
+				let length: f64 = Self::length(&chars, &mut i);
+				first = first.or(Option::Some(length));
+				if chars.get(i) == Option::Some(&'-') { tie = true; i += 1; }
+			    },
+			    Option::None => { i += 1; }
+			}
+		    }
+		    i += 1;
+		    let multiplier: f64 = Self::length(&chars, &mut i);
+		    if chars.get(i) == Option::Some(&'-') { tie = true; i += 1; }
+		    let duration: f64 = first.unwrap_or(1.0) * multiplier * self.unit * 4.0 * self.scale();
+		    self.push_note(duration, pitches, tie);
+		},
+		'|' | ':' => {
+		    // Bar lines, repeats and endings
+		    let start: usize = i;
+		    while i < chars.len() && matches!(chars[i], '|' | ':' | ']') { i += 1; }
+		    let bar: String = chars[start..i].iter().collect();
+		    self.bar();
+		    if bar.starts_with(':') { self.items().push(Item::RepeatEnd); }
+		    if bar.ends_with(':') { self.items().push(Item::RepeatStart); }
+		    if !bar.contains(':') && (bar.len() > 1) { self.items().push(Item::DoubleBar); }
+		    if let Option::Some(ending) = chars.get(i).and_then(|e| e.to_digit(10)) {
+			self.items().push(Item::Ending(ending));
+			i += 1;
+		    }
+		},
+		'>' | '<' => {
+		    let mut count: i32 = 0;
+		    while i < chars.len() && chars[i] == c { count += 1; i += 1; }
+		    let short: f64 = 0.5_f64.powi(count);
+		    let (previous, next): (f64, f64) = if c == '>' { (2.0 - short, short) } else { (short, 2.0 - short) };
+		    if let Option::Some(Item::Note{duration, ..}) = self.items().last_mut() { *duration *= previous; }
+		    self.broken = next;
+		},
+		'(' if chars.get(i + 1).is_some_and(|e| e.is_ascii_digit()) => {
+		    // A tuplet, (p:q:r puts p notes in the time of q for the next r notes
+		    i += 1;
+		    let end: usize = chars[i..].iter().position(|e| !(e.is_ascii_digit() || *e == ':')).map(|e| e + i).unwrap_or(chars.len());
+		    let numbers: Vec<Option<u32>> = chars[i..end].iter().collect::<String>().split(':').map(|e| e.parse().ok()).collect();
+		    i = end;
+		    let p: u32 = numbers[0].unwrap_or(3);
+		    let q: u32 = numbers.get(1).copied().flatten().unwrap_or(match p { 2 | 4 | 8 => { 3 }, 3 | 6 => { 2 }, _ => { 2 } });
+		    let r: u32 = numbers.get(2).copied().flatten().unwrap_or(p);
+		    self.tuplet = Option::Some((q as f64 / p as f64, r));
+		},
+		'!' | '+' => {
+		    // Decorations, of which only dynamics change the sound
+		    let end: usize = chars[i + 1..].iter().position(|e| *e == c).map(|e| e + i + 1).unwrap_or(chars.len());
+		    let decoration: String = chars[i + 1..end].iter().collect();
+		    if let Option::Some(volume) = dynamic_volume(decoration.as_str()) { self.volume = volume; }
+		    i = end + 1;
+		},
+		'"' => {
+		    // Chord symbols and annotations
+		    i = chars[i + 1..].iter().position(|e| *e == '"').map(|e| e + i + 2).unwrap_or(chars.len());
+		},
+		'{' => {
+		    // Grace notes
+		    i = chars[i..].iter().position(|e| *e == '}').map(|e| e + i + 1).unwrap_or(chars.len());
+		},
+		'%' => { break; },
+		_ => { i += 1; } // Spaces, ties between chords, and other decorations
+	    }
+	}
+	Ok(())
+    }
+}
+
+/// Plays out the repeats of a voice.
+fn unfold (items: &[Item]) -> Vec<ScoreNote> {
+    let mut notes: Vec<ScoreNote> = Vec::<ScoreNote>::new();
+    let mut time: f64 = 0.0;
+    let mut start: usize = 0; // Where the current repeat goes back to
+    let mut pass: u32 = 1;
+    let mut skipping: bool = false; // In an ending for another pass
+    let mut tied: Vec<(i32, usize)> = Vec::<(i32, usize)>::new(); // Pitches tied over from the last note, and their notes
+    let mut i: usize = 0;
+    while i < items.len() {
+	match &items[i] {
+	    Item::RepeatStart => {
+		start = i + 1;
+		pass = 1;
+	    },
+	    Item::RepeatEnd => {
+		skipping = false;
+		if pass == 1 {
+		    pass = 2;
+		    i = start;
+		    continue;
+		}
+	    },
+	    Item::Ending(n) => { skipping = *n != pass; },
+	    Item::DoubleBar => {
+		if !skipping && pass == 2 {
+		    start = i + 1;
+		    pass = 1;
+		}
+		skipping = false;
+	    },
+	    Item::Note{duration, pitches, tie, volume} => {
+		if skipping {
+		    i += 1;
+		    continue;
+		}
+		let mut next_tied: Vec<(i32, usize)> = Vec::<(i32, usize)>::new();
+		for pitch in pitches {
+		    let index: usize = match tied.iter().find(|(p, _)| p == pitch) {
+			Option::Some((_, index)) => {
+			    notes[*index].duration += duration;
+			    *index
+			},
+			Option::None => {
+			    notes.push(ScoreNote{time: time, duration: *duration, frequency: midi_to_frequency(*pitch as f64), volume: *volume});
+			    notes.len() - 1
+			}
+		    };
+		    if *tie { next_tied.push((*pitch, index)); }
+		}
+		tied = next_tied;
+		time += duration;
+	    }
+	}
+	i += 1;
+    }
+    notes
+}
+
+/**
+Reads a tune from an ABC file, which can hold many.
+@param number The X: number of the tune to read, or the first if none
+*/
+pub fn parse (text: &str, number: Option<u32>) -> Result<Score, ParseError> {
+    let mut reader: Reader = Reader{unit: 0.0, meter: Option::None, tempo: Option::None, key: [0; 7], accidentals: HashMap::<(usize, i32), i32>::new(), volume: 1.0, voices: vec![(String::new(), Vec::<Item>::new())], voice: 0, broken: 1.0, tuplet: Option::None};
+    let mut title: Option<String> = Option::None;
+    let mut found: bool = false;
+    let mut in_body: bool = false;
+    for line in text.lines() {
+	let line: &str = line.trim_end();
+	let field: Option<(char, &str)> = match line.as_bytes() {
+	    [name, b':', ..] if name.is_ascii_alphabetic() => { Option::Some((*name as char, line[2..].trim())) },
+	    _ => { Option::None }
+	};
+	match field {
+	    Option::Some(('X', value)) => {
+		if found { break; } // The next tune
+		found = number.map_or(true, |n| value.parse::<u32>().ok() == Option::Some(n));
+	    },
+	    _ if !found => {},
+	    Option::Some(('T', value)) => { title = title.or(Option::Some(value.to_string())); },
+	    Option::Some(('K', value)) => {
+		reader.field('K', value);
+		in_body = true;
+	    },
+	    Option::Some((name, value)) => { reader.field(name, value); },
+	    Option::None if in_body => {
+		if line.is_empty() { break; } // A blank line ends the tune
+		reader.music(line)?;
+	    },
+	    Option::None => {}
+	}
+    }
+    if !found { return Err(ParseError); }
+
+    let mut meter: Meter = Meter::new();
+    if let Option::Some((n, d)) = reader.meter { meter.set(1, n, d); }
+    let parts: Vec<Part> = reader.voices.iter().enumerate().map(|(i, (name, items))| Part{
+	name: if name.is_empty() { format!("voice_{}", i + 1) } else { format!("voice_{}", name) },
+	notes: unfold(items),
+    }).collect();
+    Ok(Score{title: title.unwrap_or(String::new()), tempo: reader.tempo.unwrap_or(120.0), meter: meter, parts: parts})
+}
@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+use crate::{ParseError, parse_note_value, pitch_to_frequency};
+
+/// Semitones above the root of each chord quality.
+fn intervals (quality: &str) -> Result<&'static [i32], ParseError> {
+    match quality {
+	"" | "maj" | "M" => { Ok(&[0, 4, 7]) },
+	"m" | "min" | "-" => { Ok(&[0, 3, 7]) },
+	"dim" | "o" => { Ok(&[0, 3, 6]) },
+	"aug" | "+" => { Ok(&[0, 4, 8]) },
+	"sus2" => { Ok(&[0, 2, 7]) },
+	"sus4" | "sus" => { Ok(&[0, 5, 7]) },
+	"5" => { Ok(&[0, 7]) },
+	"6" => { Ok(&[0, 4, 7, 9]) },
+	"m6" | "min6" => { Ok(&[0, 3, 7, 9]) },
+	"7" | "dom7" => { Ok(&[0, 4, 7, 10]) },
+	"maj7" | "M7" => { Ok(&[0, 4, 7, 11]) },
+	"m7" | "min7" | "-7" => { Ok(&[0, 3, 7, 10]) },
+	"mmaj7" | "mM7" => { Ok(&[0, 3, 7, 11]) },
+	"m7b5" | "ø" => { Ok(&[0, 3, 6, 10]) },
+	"dim7" | "o7" => { Ok(&[0, 3, 6, 9]) },
+	"7sus4" | "7sus" => { Ok(&[0, 5, 7, 10]) },
+	"add9" => { Ok(&[0, 4, 7, 14]) },
+	"9" => { Ok(&[0, 4, 7, 10, 14]) },
+	"maj9" | "M9" => { Ok(&[0, 4, 7, 11, 14]) },
+	"m9" | "min9" => { Ok(&[0, 3, 7, 10, 14]) },
+	"11" => { Ok(&[0, 4, 7, 10, 14, 17]) },
+	"13" => { Ok(&[0, 4, 7, 10, 14, 21]) },
+	_ => { Err(ParseError) }
+    }
+}
+
+/// Splits a pitch name like `F#` off the front of a string.
+fn split_pitch_class (s: &str) -> Result<(&str, &str), ParseError> {
+    if !s.starts_with(|c: char| ('A'..='G').contains(&c)) { return Err(ParseError); }
+    let length: usize = if s[1..].starts_with(|c: char| c == '#' || c == 'b') { 2 } else { 1 };
+    Ok(s.split_at(length))
+}
+
+/// The frequency of a pitch class in the octave at or below some frequency.
+fn below (pitch_class: &str, frequency: f64) -> Result<f64, ParseError> {
+    let mut f: f64 = pitch_to_frequency(&format!("{}4", pitch_class))?;
+    while f > frequency * 1.0001 { f /= 2.0; }
+    while f * 2.0 <= frequency * 1.0001 { f *= 2.0; }
+    Ok(f)
+}
+
+/**
+Voices a chord symbol like `Cmaj7`, `F#m7b5`, `C4maj7` or `Cmaj7/E`, lowest note first.
+Without an octave the root goes in the octave at or below `register`. A slash
+bass goes below the root, and is left out of the chord above it.
+@param register In Hz, usually the note's pitch
+@return The frequencies of the notes
+*/
+pub fn voice (symbol: &str, register: f64) -> Result<Vec<f64>, ParseError> {
+    let (chord, bass): (&str, Option<&str>) = match symbol.split_once('/') {
+	Option::Some((chord, bass)) => { (chord, Option::Some(bass)) },
+	Option::None => { (symbol, Option::None) }
+    };
+    let (root_class, rest): (&str, &str) = split_pitch_class(chord)?;
+    // A lone 5, 6, 7, 9, 11 or 13 is a chord, not an octave
+    let (root, quality): (f64, &str) = if rest.starts_with(|c: char| c.is_ascii_digit()) && intervals(rest).is_err() {
+	(pitch_to_frequency(&format!("{}{}", root_class, &rest[..1]))?, &rest[1..])
+    } else {
+	(below(root_class, register)?, rest)
+    };
+    let mut frequencies: Vec<f64> = intervals(quality)?.iter().map(|i| root * 2.0_f64.powf(*i as f64 / 12.0)).collect();
+    if let Option::Some(bass) = bass {
+	let (bass_class, _): (&str, &str) = split_pitch_class(bass)?;
+	let mut bass: f64 = below(bass_class, root)?;
+	if bass >= root * 0.9999 { bass /= 2.0; }
+	// Drop any chord tone of the same pitch class, so Cmaj7/E is an inversion rather than a doubling
+	frequencies.retain(|f| ((f / bass).log2().fract() * 12.0).round() % 12.0 != 0.0);
+	frequencies.insert(0, bass);
+    }
+    Ok(frequencies)
+}
+
+#[derive(Copy, Clone)]
+pub enum Pattern {
+    Up,
+    Down,
+    UpDown, // Without playing the top and bottom notes twice
+    DownUp,
+    Random,
+}
+
+impl FromStr for Pattern {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s {
+	    "up" => { Ok(Pattern::Up) },
+	    "down" => { Ok(Pattern::Down) },
+	    "up-down" | "updown" | "up_down" => { Ok(Pattern::UpDown) },
+	    "down-up" | "downup" | "down_up" => { Ok(Pattern::DownUp) },
+	    "random" | "rand" => { Ok(Pattern::Random) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+/// Plays the notes of a chord one at a time, for as long as the chord is held.
+#[derive(Copy, Clone)]
+pub struct Arpeggio {
+    pattern: Pattern,
+    rate: f64, // Beats between steps
+    octaves: u32, // How many octaves the chord is spread over
+    gate: f64, // Length of each step, as a fraction of the rate
+    seed: u64, // For the random pattern, so renders are repeatable
+}
+
+impl Arpeggio {
+    pub fn new () -> Self {
+	Self{pattern: Pattern::Up, rate: 0.25, octaves: 1, gate: 1.0, seed: 1}
+    }
+    pub fn set (&mut self, key: &str, value: &str) -> () {
+	match key {
+	    "pattern" | "mode" => { self.pattern = value.parse().unwrap(); },
+	    "rate" | "step" => { self.rate = parse_note_value(value).unwrap(); },
+	    "octaves" | "octave" => { self.octaves = value.parse::<u32>().unwrap().max(1); },
+	    "gate" => { self.gate = value.parse().unwrap(); },
+	    "seed" => { self.seed = value.parse::<u64>().unwrap().max(1); },
+	    huh => { eprint!("Unrecognised option: {}\n", huh); }
+	}
+    }
+    /**
+    @param frequencies The notes of the chord
+    @param duration How long the chord is held, in beats
+    @return The time after the start of the chord, length and frequency of each step
+    */
+    pub fn steps (&self, frequencies: &[f64], duration: f64) -> Vec<(f64, f64, f64)> {
+	let mut sorted: Vec<f64> = frequencies.to_vec();
+	sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+	let mut run: Vec<f64> = (0..self.octaves).flat_map(|o| sorted.iter().map(move |f| f * 2.0_f64.powi(o as i32))).collect();
+	match self.pattern {
+	    Pattern::Up | Pattern::Random => {},
+	    Pattern::Down => { run.reverse(); },
+	    Pattern::UpDown => {
+		let back: Vec<f64> = run.iter().rev().skip(1).take(run.len().saturating_sub(2)).copied().collect();
+		run.extend(back);
+	    },
+	    Pattern::DownUp => {
+		run.reverse();
+		let back: Vec<f64> = run.iter().rev().skip(1).take(run.len().saturating_sub(2)).copied().collect();
+		run.extend(back);
+	    }
+	}
+	if run.is_empty() || self.rate <= 0.0 { return Vec::<(f64, f64, f64)>::new(); }
+	let mut state: u64 = self.seed.wrapping_mul(0x9E3779B97F4A7C15);
+	let mut steps: Vec<(f64, f64, f64)> = Vec::<(f64, f64, f64)>::new();
+	let mut time: f64 = 0.0;
+	let mut index: usize = 0;
+	while time < duration - 1e-9 {
+	    let frequency: f64 = match self.pattern {
+		Pattern::Random => {
+		    state ^= state << 13;
+		    state ^= state >> 7;
+		    state ^= state << 17;
+		    run[((state >> 32) % run.len() as u64) as usize]
+		},
+		_ => { run[index % run.len()] }
+	    };
+	    steps.push((time, (self.rate * self.gate).min(duration - time), frequency));
+	    time += self.rate;
+	    index += 1;
+	}
+	steps
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{MetaData, Note, ParseError, Song, WaveForm, envelope, fx, lfo, mixer};
+
+/*
+Songs as structured data, in JSON or TOML, with the same fields as a parsed song,
+so other programs can write songs without going through the text format. Notes
+are kept as they were written, and mono notes are only joined once a song is
+loaded to be played.
+
+Going back to the text format writes every note on a NOTE line of its own, with
+the options that differ from what it starts out as, so REPEAT sections, chords,
+arpeggios and the cursor come back expanded. LFOs and envelopes are defined again
+wherever a note uses a different version of them.
+*/
+
+pub enum Format {
+    Text,
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// By a file's extension, the text format unless it's `.json` or `.toml`.
+    pub fn of (path: &str) -> Self {
+	let lower: String = path.to_lowercase();
+	if lower.ends_with(".json") {
+	    Format::Json
+	} else if lower.ends_with(".toml") {
+	    Format::Toml
+	} else {
+	    Format::Text
+	}
+    }
+}
+
+/// Reads a song from JSON or TOML.
+pub fn read (text: &str, format: Format) -> Result<Song, ParseError> {
+    let read: Result<Song, String> = match format {
+	Format::Toml => { toml::from_str(text).map_err(|e| e.to_string()) },
+	_ => { serde_json::from_str(text).map_err(|e| e.to_string()) }
+    };
+    match read {
+	Result::Ok(mut song) => {
+	    if let Option::Some(note) = song.notes.iter().find(|e| e.track >= song.tracks.len() || e.stem >= song.stems.len()) {
+		eprint!("The note at beat {} is on a track or in a stem that the song doesn't have\n", note.time);
+		return Err(ParseError);
+	    }
+	    if let Option::Some(note) = song.notes.iter().chain(song.instruments.iter().map(|(_, e)| e)).find(|e| matches!(e.wave_form, WaveForm::Sample(index) if index >= song.samples.len())) {
+		eprint!("The note at beat {} plays a sample that the song doesn't have\n", note.time);
+		return Err(ParseError);
+	    }
+	    song.notes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+	    Ok(song)
+	},
+	Result::Err(err) => {
+	    eprint!("{}\n", err);
+	    Err(ParseError)
+	}
+    }
+}
+
+/// Writes a song out in one of the formats.
+pub fn write (song: &Song, format: Format) -> Result<String, ParseError> {
+    let written: Result<String, String> = match format {
+	Format::Json => { serde_json::to_string_pretty(song).map(|e| e + "\n").map_err(|e| e.to_string()) },
+	Format::Toml => { toml::to_string(song).map_err(|e| e.to_string()) },
+	Format::Text => { Ok(text(song)) }
+    };
+    written.map_err(|err| {
+	eprint!("{}\n", err);
+	ParseError
+    })
+}
+
+/// The options that differ from those of what something starts out as, as `key=value` pieces.
+fn changed (options: Vec<(String, String)>, base: &[(String, String)]) -> Vec<String> {
+    options.into_iter().filter(|e| !base.contains(e)).map(|(key, value)| format!("{}={}", key, value)).collect()
+}
+
+/// Adds a line of a command and its pieces.
+fn push_line (text: &mut String, command: &str, pieces: &[String]) -> () {
+    text.push_str(command);
+    for piece in pieces {
+	text.push(' ');
+	text.push_str(piece);
+    }
+    text.push('\n');
+}
+
+/// What the text written so far has defined, and where it's up to.
+struct Writer {
+    text: String,
+    lfos: Vec<(String, Vec<String>)>, // Name and options, as last defined
+    envelopes: Vec<(String, Vec<String>)>,
+    track: usize, // The TRACK or BUS section the text is in, or 0 outside of one
+    default_block: usize,
+    sample_warned: bool,
+}
+
+impl Writer {
+    /// Defines an LFO or envelope, unless it's already defined like this.
+    fn define (&mut self, command: &str, name: &str, options: Vec<String>) -> () {
+	let defined: &mut Vec<(String, Vec<String>)> = if command == "LFO" { &mut self.lfos } else { &mut self.envelopes };
+	if defined.iter().any(|(e, o)| e == name && *o == options) { return; }
+	defined.retain(|(e, _)| e != name);
+	defined.push((name.to_string(), options.clone()));
+	push_line(&mut self.text, &format!("{} {}", command, name), &options);
+    }
+    /// The pieces of a NOTE or INSTRUMENT line, defining the LFOs and envelopes it uses first.
+    fn note_pieces (&mut self, note: &Note, base: &Note) -> Vec<String> {
+	let lfo_base: Vec<(String, String)> = lfo::Lfo::new("").options();
+	for l in &note.lfos {
+	    self.define("LFO", &l.name, changed(l.options(), &lfo_base));
+	}
+	let envelope_base: Vec<(String, String)> = envelope::Envelope::new("").options();
+	for e in &note.envelopes {
+	    self.define("ENV", &e.name, changed(e.options(), &envelope_base));
+	}
+	let mut options: Vec<(String, String)> = note.options();
+	if matches!(note.wave_form, WaveForm::Sample(_)) {
+	    if !self.sample_warned {
+		eprint!("Samples can't be written in the text format, so their notes keep the wave they start out with\n");
+		self.sample_warned = true;
+	    }
+	    options.retain(|(key, _)| key != "wave");
+	}
+	let base_options: Vec<(String, String)> = base.options();
+	let mut pieces: Vec<String> = changed(options.clone(), &base_options);
+	// Whatever the names, the LFOs and envelopes might have been defined differently since the base was
+	for key in ["lfo", "env"] {
+	    let value: &String = &options.iter().find(|(k, _)| k == key).unwrap().1;
+	    if value != "none" || base_options.iter().any(|(k, v)| k == key && v != "none") {
+		let piece: String = format!("{}={}", key, value);
+		if !pieces.contains(&piece) { pieces.push(piece); }
+	    }
+	}
+	pieces
+    }
+    /// Moves into the TRACK or BUS section of a track, or out of any for track 0.
+    fn enter_track (&mut self, song: &Song, track: usize) -> () {
+	if self.track == track { return; }
+	if self.track != 0 {
+	    self.text.push_str(if song.tracks[self.track].bus { "END_BUS\n" } else { "END_TRACK\n" });
+	}
+	if track != 0 {
+	    let command: &str = if song.tracks[track].bus { "BUS" } else { "TRACK" };
+	    self.text.push_str(&format!("{} {}\n", command, song.tracks[track].name));
+	}
+	self.track = track;
+    }
+}
+
+/// Writes a song out in the text format.
+fn text (song: &Song) -> String {
+    let mut writer: Writer = Writer{text: String::new(), lfos: Vec::<(String, Vec<String>)>::new(), envelopes: Vec::<(String, Vec<String>)>::new(), track: 0, default_block: 0, sample_warned: false};
+
+    let meta_data: &MetaData = &song.meta_data;
+    let meta_options = |m: &MetaData| -> Vec<(String, String)> {
+	vec![
+	    ("limit".to_string(), m.limit.map_or("none".to_string(), |e| e.to_string())),
+	    ("limit_lookahead".to_string(), m.limit_lookahead.to_string()),
+	    ("limit_release".to_string(), m.limit_release.to_string()),
+	    ("clip".to_string(), m.clip.to_string()),
+	    ("bits".to_string(), m.bits.to_string()),
+	    ("dither".to_string(), m.dither.to_string())
+	]
+    };
+    let mut pieces: Vec<String> = vec![format!("tempo={}", meta_data.tempo), format!("length={}", meta_data.length)];
+    pieces.extend(changed(meta_options(meta_data), &meta_options(&MetaData::new())));
+    push_line(&mut writer.text, "META", &pieces);
+    for (bar, (numerator, denominator)) in song.meter.changes() {
+	if (bar, numerator, denominator) != (1, 4, 4) {
+	    writer.text.push_str(&format!("META time_sig={}/{} bar={}\n", numerator, denominator, bar));
+	}
+    }
+
+    // Tracks and buses, with their inserts
+    for (index, track) in song.tracks.iter().enumerate() {
+	let pieces: Vec<String> = changed(track.options(), &mixer::Track::new(&track.name, track.bus).options());
+	if index == 0 {
+	    // Notes outside of any section go to the first track, which the text format calls main
+	    if !pieces.is_empty() {
+		push_line(&mut writer.text, "TRACK main", &pieces);
+	    }
+	    if !track.fx.is_empty() {
+		eprint!("The effects on {} can't be written in the text format\n", track.name);
+	    }
+	    continue;
+	}
+	push_line(&mut writer.text, &format!("{} {}", if track.bus { "BUS" } else { "TRACK" }, track.name), &pieces);
+	for effect in &track.fx {
+	    push_line(&mut writer.text, &format!("FX {}", effect.name()), &changed(effect.options(), &effect.name().parse::<fx::EffectSpec>().unwrap().options()));
+	}
+	writer.text.push_str(if track.bus { "END_BUS\n" } else { "END_TRACK\n" });
+    }
+    for effect in &song.master.fx {
+	push_line(&mut writer.text, &format!("FX {}", effect.name()), &changed(effect.options(), &effect.name().parse::<fx::EffectSpec>().unwrap().options()));
+    }
+    for (index, track) in song.tracks.iter().chain(std::iter::once(&song.master)).enumerate() {
+	let name: &str = if index == 0 { "main" } else if index == song.tracks.len() { "master" } else { &track.name };
+	for (lane, curve) in &track.lanes {
+	    writer.text.push_str(&format!("AUTOMATE {} {} {}\n", name, lane, curve));
+	}
+    }
+
+    for (name, instrument) in &song.instruments {
+	let pieces: Vec<String> = writer.note_pieces(instrument, &Note::new());
+	push_line(&mut writer.text, &format!("INSTRUMENT {}", name), &pieces);
+    }
+
+    // A stem at a time, so the stems come back in the same order
+    let mut made: Vec<String> = Vec::<String>::new(); // Instruments made up to name stems
+    for (stem, stem_name) in song.stems.iter().enumerate() {
+	for note in song.notes.iter().filter(|e| e.stem == stem) {
+	    writer.enter_track(song, note.track);
+	    let mut command: String = "NOTE".to_string();
+	    let mut base: Note = Note::new();
+	    if note.track == 0 {
+		// The stem is named after the instrument or DEFAULT block the note comes from
+		let block: Option<usize> = stem_name.strip_prefix("default_").and_then(|e| e.parse().ok()).filter(|e| *e >= writer.default_block);
+		match block {
+		    Option::Some(block) => {
+			while writer.default_block < block {
+			    writer.text.push_str("DEFAULT\n");
+			    writer.default_block += 1;
+			}
+		    },
+		    Option::None => {
+			match song.instruments.iter().find(|(e, _)| e == stem_name) {
+			    Option::Some((_, instrument)) => { base = instrument.clone(); },
+			    Option::None => {
+				if !made.contains(stem_name) {
+				    writer.text.push_str(&format!("INSTRUMENT {}\n", stem_name));
+				    made.push(stem_name.clone());
+				}
+			    }
+			}
+			command = format!("NOTE inst={}", stem_name);
+		    }
+		}
+	    }
+	    let mut pieces: Vec<String> = vec![format!("time={}", note.time)];
+	    pieces.extend(writer.note_pieces(note, &base));
+	    push_line(&mut writer.text, &command, &pieces);
+	}
+    }
+    writer.enter_track(song, 0);
+    writer.text
+}
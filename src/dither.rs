@@ -0,0 +1,80 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::ParseError;
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum Dither {
+    Off, // Round to the nearest step
+    Tpdf, // Triangular noise of one step either way
+    Shaped, // TPDF, with the quantization error pushed up towards Nyquist
+}
+
+impl FromStr for Dither {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s {
+	    "off" | "none" | "no" => { Ok(Dither::Off) },
+	    "tpdf" | "on" | "yes" => { Ok(Dither::Tpdf) },
+	    "shaped" | "noise_shaping" | "ns" => { Ok(Dither::Shaped) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+impl std::fmt::Display for Dither {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let name: &str = match self {
+	    Dither::Off => { "off" },
+	    Dither::Tpdf => { "tpdf" },
+	    Dither::Shaped => { "shaped" }
+	};
+	write!(f, "{}", name)
+    }
+}
+
+/// Turns samples of the float mix (full scale is 1.0) into integer PCM, one channel at a time.
+pub struct Quantizer {
+    bits: u16,
+    dither: Dither,
+    state: u64, // Xorshift, seeded the same every time so renders are repeatable
+    errors: [[f64; 2]; 2], // Last two quantization errors of each channel
+}
+
+impl Quantizer {
+    pub fn new (bits: u16, dither: Dither) -> Self {
+	Self{bits: bits, dither: dither, state: 0x9E3779B97F4A7C15, errors: [[0.0; 2]; 2]}
+    }
+    fn random (&mut self) -> f64 {
+	self.state ^= self.state << 13;
+	self.state ^= self.state >> 7;
+	self.state ^= self.state << 17;
+	(self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+    pub fn quantize (&mut self, amplitude: f64, channel: usize) -> i32 {
+	let max: f64 = ((1i64 << (self.bits - 1)) - 1) as f64;
+	let mut wanted: f64 = amplitude * max;
+	if let Dither::Shaped = self.dither {
+	    // Second order error feedback, so the noise follows (1 - z^-1)^2
+	    let [e1, e2] = self.errors[channel];
+	    wanted -= 2.0 * e1 - e2;
+	}
+	let noise: f64 = match self.dither {
+	    Dither::Off => { 0.0 },
+	    Dither::Tpdf | Dither::Shaped => { self.random() - self.random() }
+	};
+	let a: f64 = (wanted + noise).round().clamp(-max - 1.0, max);
+	// Clipping errors aren't fed back, or a loud passage would set the filter ringing
+	self.errors[channel] = [(a - wanted).clamp(-1.0, 1.0), self.errors[channel][0]];
+	a as i32
+    }
+    /// Little endian bytes, 8 bit samples are unsigned as .wav wants them.
+    pub fn sample_data (&mut self, amplitude: f64, channel: usize) -> Vec<u8> {
+	let a: i32 = self.quantize(amplitude, channel);
+	match self.bits {
+	    8 => { vec![(a + 128) as u8] },
+	    16 => { (a as i16).to_le_bytes().to_vec() },
+	    _ => { a.to_le_bytes()[..(self.bits / 8) as usize].to_vec() }
+	}
+    }
+}
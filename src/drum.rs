@@ -0,0 +1,164 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{ParseError, SAMPLES_PER_SECOND};
+use crate::fx::Lowpass;
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum Kind {
+    Kick, // Sine with a falling pitch and a click
+    Snare, // Two tuned sines and high passed noise
+    ClosedHat, // Ring modulated squares at inharmonic ratios, high passed
+    OpenHat,
+    Clap, // Band passed noise in a few quick bursts, then a tail
+    Tom, // Like the kick, higher and with less sweep
+}
+
+impl FromStr for Kind {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s {
+	    "kick" | "bd" => { Ok(Kind::Kick) },
+	    "snare" | "sd" => { Ok(Kind::Snare) },
+	    "hat" | "hh" | "closed_hat" => { Ok(Kind::ClosedHat) },
+	    "open_hat" | "oh" => { Ok(Kind::OpenHat) },
+	    "clap" | "cp" => { Ok(Kind::Clap) },
+	    "tom" => { Ok(Kind::Tom) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let name: &str = match self {
+	    Kind::Kick => { "kick" },
+	    Kind::Snare => { "snare" },
+	    Kind::ClosedHat => { "hat" },
+	    Kind::OpenHat => { "open_hat" },
+	    Kind::Clap => { "clap" },
+	    Kind::Tom => { "tom" }
+	};
+	write!(f, "{}", name)
+    }
+}
+
+impl Kind {
+    /// Milliseconds for the hit to fade by 60dB, unless the note gives a decay.
+    pub fn decay (self) -> f64 {
+	match self {
+	    Kind::Kick => { 450.0 },
+	    Kind::Snare => { 220.0 },
+	    Kind::ClosedHat => { 70.0 },
+	    Kind::OpenHat => { 450.0 },
+	    Kind::Clap => { 300.0 },
+	    Kind::Tom => { 400.0 }
+	}
+    }
+}
+
+/// Settings for the drum waves, shared by every kind.
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct Drum {
+    tune: f64, // Semitones up from each drum's own tuning
+    tone: f64, // From 0 to 1, tone against noise for snares, brightness for hats and claps
+    click: f64, // Level of the kick's and tom's click
+    sweep: f64, // Semitones the kick and tom fall from at the start of the hit
+    bursts: u32, // Number of claps before the tail
+}
+
+impl Drum {
+    pub fn new () -> Self {
+	Self{tune: 0.0, tone: 0.5, click: 0.3, sweep: 24.0, bursts: 3}
+    }
+    /**
+    @return False if the option isn't a drum option
+    */
+    pub fn set (&mut self, key: &str, value: &str) -> bool {
+	match key {
+	    "tune" => { self.tune = value.parse().unwrap(); },
+	    "tone" => { self.tone = value.parse().unwrap(); },
+	    "click" => { self.click = value.parse().unwrap(); },
+	    "sweep" => { self.sweep = value.parse().unwrap(); },
+	    "bursts" => { self.bursts = value.parse::<u32>().unwrap().max(1); },
+	    _ => { return false; }
+	}
+	true
+    }
+    /// The options `set` takes to make these settings, as keys and values.
+    pub fn options (&self) -> Vec<(String, String)> {
+	let values: [(&str, f64); 4] = [("tune", self.tune), ("tone", self.tone), ("click", self.click), ("sweep", self.sweep)];
+	let mut options: Vec<(String, String)> = values.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+	options.push(("bursts".to_string(), self.bursts.to_string()));
+	options
+    }
+    /**
+    @param kind Which drum to play
+    @param time Seconds since the hit
+    @param decay Milliseconds for the hit to fade by 60dB
+    @param state Filters, carried from one sample to the next
+    @return From -1 to 1
+    */
+    pub fn audio_at (&self, kind: Kind, time: f64, decay: f64, state: &mut State) -> f64 {
+	let tune: f64 = 2.0_f64.powf(self.tune / 12.0);
+	let fade = |t: f64, ms: f64| if t < 0.0 { 0.0 } else { (-t * 6.9 / (ms.max(1.0) * 0.001)).exp() };
+	let white: f64 = noise((time * SAMPLES_PER_SECOND as f64).round() as u64);
+	match kind {
+	    Kind::Kick | Kind::Tom => {
+		let (base, sweep): (f64, f64) = match kind { Kind::Kick => { (50.0, self.sweep) }, _ => { (110.0, self.sweep * 0.3) } };
+		// The pitch falls exponentially to the base, so the phase has a closed form
+		let start: f64 = base * tune * 2.0_f64.powf(sweep / 12.0);
+		let settle: f64 = 0.03; // Seconds
+		let phase: f64 = base * tune * time + (start - base * tune) * settle * (1.0 - (-time / settle).exp());
+		let body: f64 = (phase * std::f64::consts::TAU).sin() * fade(time, decay);
+		body + self.click * white * fade(time, 8.0)
+	    },
+	    Kind::Snare => {
+		let body: f64 = ((180.0 * tune * time * std::f64::consts::TAU).sin() + 0.6 * (330.0 * tune * time * std::f64::consts::TAU).sin()) / 1.6 * fade(time, decay * 0.5);
+		let rattle: f64 = (white - state.filters[0].process(white, 1500.0 * tune, 0.707)) * fade(time, decay);
+		self.tone * body + (1.0 - self.tone) * rattle * 1.5
+	    },
+	    Kind::ClosedHat | Kind::OpenHat => {
+		// The six oscillator ratios of the TR-808's cymbal circuit, ring modulated in pairs
+		let square = |f: f64| if (f * tune * time) % 1.0 < 0.5 { 1.0 } else { -1.0 };
+		let metal: f64 = (square(205.3) * square(304.4) + square(369.6) * square(522.7) + square(540.0) * square(800.0)) / 3.0;
+		let bright: f64 = metal - state.filters[0].process(metal, 3000.0 + 3000.0 * self.tone, 0.707);
+		let sizzle: f64 = white - state.filters[1].process(white, 6000.0, 0.707);
+		(bright + 0.5 * sizzle) * fade(time, decay)
+	    },
+	    Kind::Clap => {
+		let high_passed: f64 = white - state.filters[0].process(white, 700.0 * tune, 0.707);
+		let band: f64 = state.filters[1].process(high_passed, (1200.0 + 1200.0 * self.tone) * tune, 1.5);
+		let gap: f64 = 0.011; // Seconds between bursts
+		let mut level: f64 = 0.0;
+		for burst in 0..self.bursts {
+		    level = level.max(fade(time - burst as f64 * gap, 25.0));
+		}
+		level = level.max(0.6 * fade(time - (self.bursts - 1) as f64 * gap, decay));
+		band * level * 2.0
+	    }
+	}
+    }
+}
+
+/// The filters a drum needs to keep between samples.
+#[derive(Copy, Clone)]
+pub struct State {
+    filters: [Lowpass; 2],
+}
+
+impl State {
+    pub fn new () -> Self {
+	Self{filters: [Lowpass::new(); 2]}
+    }
+}
+
+/// White noise from -1 to 1, the same for the same sample every time.
+fn noise (n: u64) -> f64 {
+    // SplitMix64
+    let mut z: u64 = n.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+}
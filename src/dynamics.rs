@@ -0,0 +1,117 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{MetaData, ParseError, SAMPLES_PER_SECOND};
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum Clip {
+    Hard,
+    Tanh,
+    Cubic,
+}
+
+impl FromStr for Clip {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s {
+	    "hard" | "off" | "none" => { Ok(Clip::Hard) },
+	    "tanh" | "soft" => { Ok(Clip::Tanh) },
+	    "cubic" => { Ok(Clip::Cubic) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+impl std::fmt::Display for Clip {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let name: &str = match self {
+	    Clip::Hard => { "hard" },
+	    Clip::Tanh => { "tanh" },
+	    Clip::Cubic => { "cubic" }
+	};
+	write!(f, "{}", name)
+    }
+}
+
+impl Clip {
+    /// Maps any level into full scale. Both soft curves have unity gain around zero.
+    pub fn apply (self, x: f64) -> f64 {
+	match self {
+	    Clip::Hard => { x.clamp(-1.0, 1.0) },
+	    Clip::Tanh => { x.tanh() },
+	    Clip::Cubic => {
+		// x - 4x^3/27 reaches full scale with a flat slope at 1.5
+		let c: f64 = x.clamp(-1.5, 1.5);
+		c - 4.0 * c * c * c / 27.0
+	    }
+	}
+    }
+}
+
+pub fn decibels_to_gain (db: f64) -> f64 {
+    10.0_f64.powf(db / 20.0)
+}
+
+pub fn gain_to_decibels (gain: f64) -> f64 {
+    20.0 * gain.log10()
+}
+
+fn peak (frames: &[[f64; 2]]) -> f64 {
+    frames.iter().fold(0.0, |a, f| a.max(f[0].abs()).max(f[1].abs()))
+}
+
+/**
+Brickwall limiter. The whole song is available, so the lookahead is just a
+matter of looking further along the buffer: the gain each frame needs is held
+for the lookahead, released exponentially, then smoothed with a moving average
+of the same length so it ramps down before the peak instead of jumping.
+@param ceiling The highest level to let through, as a gain
+@param lookahead In ms
+@param release In ms
+*/
+fn limit (frames: &mut [[f64; 2]], ceiling: f64, lookahead: f64, release: f64) -> () {
+    let window: usize = ((lookahead * 0.001 * (SAMPLES_PER_SECOND as f64)) as usize).max(1);
+    let recovery: f64 = 1.0 - (-1.0 / (release.max(0.001) * 0.001 * (SAMPLES_PER_SECOND as f64))).exp();
+    let needed: Vec<f64> = frames.iter().map(|f| {
+	let level: f64 = f[0].abs().max(f[1].abs());
+	if level > ceiling { ceiling / level } else { 1.0 }
+    }).collect();
+    let mut held: Vec<f64> = Vec::<f64>::with_capacity(needed.len());
+    let mut last: f64 = 1.0;
+    for i in 0..needed.len() {
+	let lowest: f64 = needed[i..(i + window + 1).min(needed.len())].iter().fold(1.0, |a, b| a.min(*b));
+	last = lowest.min(last + (1.0 - last) * recovery);
+	held.push(last);
+    }
+    let mut sum: f64 = 0.0;
+    for i in 0..frames.len() {
+	sum += held[i];
+	if i > window { sum -= held[i - window - 1]; }
+	let gain: f64 = sum / ((i + 1).min(window + 1) as f64);
+	frames[i][0] *= gain;
+	frames[i][1] *= gain;
+    }
+}
+
+/// Runs the master limiter and clipper over the mix, then reports how loud it got.
+pub fn master (frames: &mut [[f64; 2]], meta_data: &MetaData) -> () {
+    let input_peak: f64 = peak(frames);
+    if let Option::Some(ceiling) = meta_data.limit {
+	limit(frames, decibels_to_gain(ceiling), meta_data.limit_lookahead, meta_data.limit_release);
+    }
+    let mut clipped: u128 = 0;
+    for frame in frames.iter_mut() {
+	for channel in frame.iter_mut() {
+	    if channel.abs() > 1.0 { clipped += 1; }
+	    *channel = meta_data.clip.apply(*channel);
+	}
+    }
+    eprint!("Peak level: {:.2} dBFS", gain_to_decibels(input_peak));
+    if meta_data.limit.is_some() {
+	eprint!(" before the limiter");
+    }
+    eprint!("\n");
+    if clipped > 0 {
+	eprint!("{} samples went over full scale ({})\n", clipped, match meta_data.clip { Clip::Hard => { "hard clipped" }, _ => { "soft clipped" } });
+    }
+}
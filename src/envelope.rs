@@ -0,0 +1,229 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{ParseError, lerp, parse_note_value, pitch_to_frequency};
+use crate::lfo::{Modulation, Target};
+
+/**
+Bends a 0 to 1 ramp. A curvature of 0 is a straight line, negative values move
+quickly at first and then settle (like a capacitor charging or discharging),
+positive values start slowly.
+*/
+fn bend (x: f64, curvature: f64) -> f64 {
+    if curvature.abs() < 1e-6 {
+	x
+    } else {
+	(1.0 - (curvature * x).exp()) / (1.0 - curvature.exp())
+    }
+}
+
+fn parse_curvature (s: &str) -> Result<f64, ParseError> {
+    match s {
+	"lin" | "linear" => { Ok(0.0) },
+	"exp" | "exponential" => { Ok(-5.0) },
+	_ => { s.parse().map_err(|_| ParseError) }
+    }
+}
+
+/// Delay, attack, hold, decay, sustain and release, each ramp with its own curvature.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Dahdsr {
+    pub delay: f64, // Milliseconds of silence before the attack
+    pub attack: f64, // Milliseconds
+    pub hold: f64, // Milliseconds at the peak before the decay
+    pub decay: f64, // Milliseconds
+    pub sustain: f64, // Scalar
+    pub release: f64, // Milliseconds
+    curves: [f64; 3], // Curvature of the attack, decay and release
+}
+
+impl Dahdsr {
+    pub fn new () -> Self {
+	Self{delay: 0.0, attack: 0.0, hold: 0.0, decay: 0.0, sustain: 1.0, release: 0.0, curves: [0.0; 3]}
+    }
+    /**
+    @return False if the option isn't an envelope option
+    */
+    pub fn set (&mut self, key: &str, value: &str) -> bool {
+	match key {
+	    "dl" | "env_delay" => { self.delay = value.parse().unwrap(); },
+	    "a" | "attack" => { self.attack = value.parse().unwrap(); },
+	    "h" | "hold" => { self.hold = value.parse().unwrap(); },
+	    "d" | "decay" => { self.decay = value.parse().unwrap(); },
+	    "s" | "sustain" => { self.sustain = value.parse().unwrap(); },
+	    "r" | "release" => { self.release = value.parse().unwrap(); },
+	    "a_curve" | "attack_curve" => { self.curves[0] = parse_curvature(value).unwrap(); },
+	    "d_curve" | "decay_curve" => { self.curves[1] = parse_curvature(value).unwrap(); },
+	    "r_curve" | "release_curve" => { self.curves[2] = parse_curvature(value).unwrap(); },
+	    "curve" => {
+		let curvature: f64 = parse_curvature(value).unwrap();
+		self.curves = [curvature; 3];
+	    },
+	    _ => { return false; }
+	}
+	true
+    }
+    /// The options `set` takes to make this envelope, as keys and values.
+    pub fn options (&self) -> Vec<(String, String)> {
+	let values: [(&str, f64); 9] = [("dl", self.delay), ("a", self.attack), ("h", self.hold), ("d", self.decay), ("s", self.sustain), ("r", self.release), ("a_curve", self.curves[0]), ("d_curve", self.curves[1]), ("r_curve", self.curves[2])];
+	values.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+    /// Level while the note is held.
+    fn held_level (&self, since_start_ms: f64) -> f64 {
+	let mut t: f64 = since_start_ms - self.delay;
+	if t < 0.0 { return 0.0; }
+	if t < self.attack { return bend(t / self.attack, self.curves[0]); }
+	t -= self.attack;
+	if t < self.hold { return 1.0; }
+	t -= self.hold;
+	if t < self.decay { return lerp(bend(t / self.decay, self.curves[1]), 1.0, self.sustain); }
+	self.sustain
+    }
+    /**
+    Level of the envelope, from 0 to 1. The release starts from wherever the
+    envelope was when the note ended, even if that was part way up the attack.
+    @param since_start_ms Time since the note started
+    @param since_end_ms Time since the note ended, negative until then
+    */
+    pub fn level (&self, since_start_ms: f64, since_end_ms: f64) -> f64 {
+	if since_end_ms < 0.0 { return self.held_level(since_start_ms); }
+	if since_end_ms >= self.release { return 0.0; }
+	// Both times come from the same clock, so a note with no length can land a hair before its start
+	self.held_level((since_start_ms - since_end_ms).max(0.0)) * (1.0 - bend(since_end_ms / self.release, self.curves[2]))
+    }
+}
+
+/// An envelope that modulates a note parameter instead of its volume, restarting with every note.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub name: String,
+    shape: Dahdsr,
+    depth: f64, // In the target's units, at the peak
+    target: Target,
+}
+
+impl Envelope {
+    pub fn new (name: &str) -> Self {
+	Self{name: name.to_string(), shape: Dahdsr::new(), depth: 0.0, target: Target::PulseWidth}
+    }
+    pub fn set (&mut self, key: &str, value: &str) -> () {
+	if self.shape.set(key, value) { return; }
+	match key {
+	    "depth" | "amount" | "mag" | "magnitude" => { self.depth = value.parse().unwrap(); },
+	    "target" | "to" => { self.target = value.parse().unwrap(); },
+	    huh => { eprint!("Unrecognised option: {}\n", huh); }
+	}
+    }
+    /// The options `set` takes to make this envelope, as keys and values.
+    pub fn options (&self) -> Vec<(String, String)> {
+	let mut options: Vec<(String, String)> = self.shape.options();
+	options.push(("depth".to_string(), self.depth.to_string()));
+	options.push(("target".to_string(), self.target.to_string()));
+	options
+    }
+    /**
+    @param since_start_ms Time since the note started
+    @param since_end_ms Time since the note ended, negative until then
+    */
+    pub fn modulate (&self, modulation: &mut Modulation, since_start_ms: f64, since_end_ms: f64) -> () {
+	modulation.add(self.target, self.depth * self.shape.level(since_start_ms, since_end_ms));
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum Segment {
+    Linear,
+    Exponential, // Equal ratios in equal times, for pitches and cutoffs
+    Hold, // Stay at the previous point, then jump
+}
+
+impl FromStr for Segment {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s {
+	    "lin" | "linear" => { Ok(Segment::Linear) },
+	    "exp" | "exponential" => { Ok(Segment::Exponential) },
+	    "hold" | "step" => { Ok(Segment::Hold) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+impl std::fmt::Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let name: &str = match self {
+	    Segment::Linear => { "lin" },
+	    Segment::Exponential => { "exp" },
+	    Segment::Hold => { "hold" }
+	};
+	write!(f, "{}", name)
+    }
+}
+
+/// Breakpoints joined by segments, like `0:C4,0.1:C5:exp,0.5:G4:hold`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Curve {
+    points: Vec<(f64, f64, Segment)>, // Time in beats, value, and the shape of the segment leading up to it
+}
+
+impl Curve {
+    /**
+    @param s Comma separated `time:value[:segment]` points, with times in beats or note values
+    @param pitch Values are pitch names (or Hz), and segments are exponential unless given
+    */
+    pub fn parse (s: &str, pitch: bool) -> Result<Self, ParseError> {
+	let mut points: Vec<(f64, f64, Segment)> = Vec::<(f64, f64, Segment)>::new();
+	for point in s.split(',') {
+	    let parts: Vec<&str> = point.split(':').collect();
+	    if parts.len() < 2 { return Err(ParseError); }
+	    let time: f64 = parse_note_value(parts[0])?;
+	    let value: f64 = if pitch {
+		pitch_to_frequency(parts[1]).or_else(|_| parts[1].parse().map_err(|_| ParseError))?
+	    } else {
+		parts[1].parse().map_err(|_| ParseError)?
+	    };
+	    let segment: Segment = match parts.get(2) {
+		Option::Some(segment) => { segment.parse()? },
+		Option::None => { if pitch { Segment::Exponential } else { Segment::Linear } }
+	    };
+	    points.push((time, value, segment));
+	}
+	if points.is_empty() { return Err(ParseError); }
+	points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+	Ok(Self{points: points})
+    }
+    /// A curve that stays at one value, to be built on with `push`.
+    pub fn constant (value: f64) -> Self {
+	Self{points: vec![(0.0, value, Segment::Hold)]}
+    }
+    /// Adds a point at the end, dropping any points after it.
+    pub fn push (&mut self, time: f64, value: f64, segment: Segment) -> () {
+	self.points.retain(|p| p.0 <= time);
+	self.points.push((time, value, segment));
+    }
+    /// The value at some time in beats, holding the first and last points outside of the curve.
+    pub fn value_at (&self, time: f64) -> f64 {
+	let first: &(f64, f64, Segment) = &self.points[0];
+	if time <= first.0 { return first.1; }
+	for pair in self.points.windows(2) {
+	    let (start, end): (&(f64, f64, Segment), &(f64, f64, Segment)) = (&pair[0], &pair[1]);
+	    if time < end.0 {
+		let x: f64 = (time - start.0) / (end.0 - start.0);
+		return match end.2 {
+		    Segment::Hold => { start.1 },
+		    Segment::Exponential if start.1 * end.1 > 0.0 => { start.1 * (end.1 / start.1).powf(x) },
+		    _ => { lerp(x, start.1, end.1) }
+		};
+	    }
+	}
+	self.points[self.points.len() - 1].1
+    }
+}
+
+/// Written as `parse` reads it, with values in Hz for pitches.
+impl std::fmt::Display for Curve {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let points: Vec<String> = self.points.iter().map(|(time, value, segment)| format!("{}:{}:{}", time, value, segment)).collect();
+	write!(f, "{}", points.join(","))
+    }
+}
@@ -0,0 +1,151 @@
+use regex::{Captures, Regex};
+
+use crate::{ParseError, parse_note_value};
+
+/*
+Variables and arithmetic in the options of a line. `$name` is swapped for the
+text of a LET variable (or a macro's parameter), and then any option value with
++, -, *, %, or brackets in it is worked out as arithmetic, so `time=4*8+0.5` and
+`volume=$lead_vol*0.5` both end up as plain numbers. A lone / isn't enough to
+make a value arithmetic, as values like `dur=1/8` and `time_sig=6/8` already mean
+something else, but inside a sum it divides. Values that aren't arithmetic, like
+`wave=har(1,0.5)` or `lfo=vib,wob`, are left as they are.
+
+Options that take a note value, like `dur=`, read each `1/8` in a sum as a note
+value instead, so `dur=1/8+1/16` is a dotted eighth, and the sum is written back
+as quarter notes, `0.75/4`, which all of them read as 0.75 beats.
+*/
+
+/// Reads an arithmetic expression, one level of precedence at a time.
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    note_values: bool, // Numbers like 1/8 or 1/8d are note values, in beats
+}
+
+impl<'a> Parser<'a> {
+    fn peek (&self) -> Option<u8> {
+	self.bytes.get(self.position).copied()
+    }
+    fn digits (&mut self) -> () {
+	while self.peek().is_some_and(|e| e.is_ascii_digit() || e == b'.') {
+	    self.position += 1;
+	}
+    }
+    fn sum (&mut self) -> Result<f64, ParseError> {
+	let mut value: f64 = self.product()?;
+	loop {
+	    match self.peek() {
+		Option::Some(b'+') => { self.position += 1; value += self.product()?; },
+		Option::Some(b'-') => { self.position += 1; value -= self.product()?; },
+		_ => { return Ok(value); }
+	    }
+	}
+    }
+    fn product (&mut self) -> Result<f64, ParseError> {
+	let mut value: f64 = self.factor()?;
+	loop {
+	    match self.peek() {
+		Option::Some(b'*') => { self.position += 1; value *= self.factor()?; },
+		Option::Some(b'/') => { self.position += 1; value /= self.factor()?; },
+		Option::Some(b'%') => { self.position += 1; value %= self.factor()?; },
+		_ => { return Ok(value); }
+	    }
+	}
+    }
+    fn factor (&mut self) -> Result<f64, ParseError> {
+	match self.peek() {
+	    Option::Some(b'-') => {
+		self.position += 1;
+		Ok(-self.factor()?)
+	    },
+	    Option::Some(b'(') => {
+		self.position += 1;
+		let value: f64 = self.sum()?;
+		if self.peek() != Option::Some(b')') { return Err(ParseError); }
+		self.position += 1;
+		Ok(value)
+	    },
+	    _ => {
+		let start: usize = self.position;
+		self.digits();
+		if self.note_values && self.peek() == Option::Some(b'/') {
+		    self.position += 1;
+		    self.digits();
+		    if self.peek().is_some_and(|e| e == b'd' || e == b't') { self.position += 1; }
+		    return parse_note_value(std::str::from_utf8(&self.bytes[start..self.position]).unwrap());
+		}
+		std::str::from_utf8(&self.bytes[start..self.position]).unwrap().parse().map_err(|_| ParseError)
+	    }
+	}
+    }
+}
+
+/**
+Works out an expression like `4*8+0.5` or `-(1+2)*3`.
+@param note_values Whether `1/8` is a note value, in beats, rather than a division
+*/
+pub fn evaluate (s: &str, note_values: bool) -> Result<f64, ParseError> {
+    let mut parser: Parser = Parser{bytes: s.as_bytes(), position: 0, note_values: note_values};
+    let value: f64 = parser.sum()?;
+    if parser.position < s.len() { return Err(ParseError); }
+    Ok(value)
+}
+
+/// Whether a value asks to be worked out as arithmetic.
+fn is_arithmetic (s: &str) -> bool {
+    s.contains(['+', '*', '%', '(', ')']) || s.get(1..).is_some_and(|e| e.contains('-'))
+}
+
+/// Whether an option of a command takes a note value, like `dur=1/8`.
+fn takes_note_value (command: &str, key: &str) -> bool {
+    match key {
+	"dur" | "duration" | "step" | "grid" | "portamento" | "porta" => { true },
+	// The rate of an LFO or arpeggio, but an effect's is in Hz
+	"rate" => { command != "FX" },
+	"time" => { command == "FX" || command == "REPEAT" },
+	_ => { false }
+    }
+}
+
+/// Fills in variables and works out arithmetic, with its regexes built once for the whole song.
+pub struct Expander {
+    name: Regex,
+    option: Regex,
+}
+
+impl Expander {
+    pub fn new () -> Self {
+	Self{name: Regex::new(r"\$([A-Za-z_][A-Za-z0-9_]*)").expect("Invalid Regex"), option: Regex::new(r"(^|[ \t])([A-Za-z_][A-Za-z0-9_]*)=([^ \t]+)").expect("Invalid Regex")}
+    }
+    /**
+    Swaps `$name` for the value of a variable, leaving names it doesn't know.
+    @param variables Names and values
+    */
+    pub fn substitute (&self, text: &str, variables: &[(String, String)]) -> String {
+	self.name.replace_all(text, |captures: &Captures| {
+	    match variables.iter().find(|(e, _)| *e == captures[1]) {
+		Option::Some((_, value)) => { value.clone() },
+		Option::None => { captures[0].to_string() }
+	    }
+	}).into_owned()
+    }
+    /// Fills in the variables of a line, and works out the option values that are arithmetic.
+    pub fn expand (&self, line: &str, variables: &[(String, String)]) -> String {
+	let line: String = self.substitute(line, variables);
+	for found in self.name.find_iter(&line) {
+	    eprint!("No variable called {}\n", &found.as_str()[1..]);
+	}
+	let command: &str = line.split_whitespace().next().unwrap_or("");
+	self.option.replace_all(&line, |captures: &Captures| {
+	    let value: &str = &captures[3];
+	    if !is_arithmetic(value) { return captures[0].to_string(); }
+	    let note_value: bool = takes_note_value(command, &captures[2]);
+	    match evaluate(value, note_value) {
+		Ok(number) if note_value => { format!("{}{}={}/4", &captures[1], &captures[2], number) },
+		Ok(number) => { format!("{}{}={}", &captures[1], &captures[2], number) },
+		_ => { captures[0].to_string() }
+	    }
+	}).into_owned()
+    }
+}
@@ -0,0 +1,344 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{MetaData, ParseError, SAMPLES_PER_SECOND, parse_note_value};
+
+/*
+Effects are parsed into an `EffectSpec`, which only holds the settings from the
+song file. The spec is turned into an `Effect` (which owns the delay lines and
+filter state) right before rendering, once the tempo is known. Stereo signals get
+one `Effect` per channel, built with slightly different tunings so they spread.
+*/
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum EffectKind {
+    Reverb,
+    Delay,
+    Chorus,
+    Flanger,
+    Phaser,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum DelayTime {
+    Beats(f64),
+    Milliseconds(f64),
+}
+
+impl FromStr for DelayTime {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s.strip_suffix("ms") {
+	    Option::Some(ms) => { ms.parse().map(DelayTime::Milliseconds).map_err(|_| ParseError) },
+	    Option::None => { parse_note_value(s).map(DelayTime::Beats) }
+	}
+    }
+}
+
+impl std::fmt::Display for DelayTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    DelayTime::Beats(beats) => { write!(f, "{}", beats) },
+	    DelayTime::Milliseconds(ms) => { write!(f, "{}ms", ms) }
+	}
+    }
+}
+
+impl DelayTime {
+    pub fn beats (self, tempo: f64) -> f64 {
+	match self {
+	    DelayTime::Beats(beats) => { beats },
+	    DelayTime::Milliseconds(ms) => { ms * tempo / 60000.0 }
+	}
+    }
+    fn samples (self, meta_data: &MetaData) -> f64 {
+	match self {
+	    DelayTime::Beats(beats) => { beats * 60.0 / meta_data.tempo * (SAMPLES_PER_SECOND as f64) },
+	    DelayTime::Milliseconds(ms) => { ms * 0.001 * (SAMPLES_PER_SECOND as f64) }
+	}
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EffectSpec {
+    kind: EffectKind,
+    wet: f64, // Scalar
+    dry: f64, // Scalar
+    room: f64, // From 0 to 1 (reverb)
+    damp: f64, // From 0 to 1 (reverb)
+    time: DelayTime, // (delay)
+    feedback: f64, // Scalar (delay, flanger, phaser)
+    rate: f64, // In Hz (chorus, flanger, phaser)
+    depth: f64, // In ms, or from 0 to 1 for the phaser
+    delay: f64, // In ms (chorus, flanger)
+    stages: usize, // (phaser)
+}
+
+impl FromStr for EffectSpec {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	let base = Self{kind: EffectKind::Reverb, wet: 0.25, dry: 1.0, room: 0.5, damp: 0.5, time: DelayTime::Beats(0.5), feedback: 0.0, rate: 0.5, depth: 0.0, delay: 0.0, stages: 4};
+	match s {
+	    "reverb" | "verb" => { Ok(base) },
+	    "delay" | "echo" => { Ok(Self{kind: EffectKind::Delay, wet: 0.3, feedback: 0.4, ..base}) },
+	    "chorus" => { Ok(Self{kind: EffectKind::Chorus, wet: 0.5, rate: 0.8, depth: 3.0, delay: 20.0, ..base}) },
+	    "flanger" => { Ok(Self{kind: EffectKind::Flanger, wet: 0.5, rate: 0.25, depth: 1.5, delay: 2.0, feedback: 0.5, ..base}) },
+	    "phaser" => { Ok(Self{kind: EffectKind::Phaser, wet: 0.5, rate: 0.5, depth: 1.0, feedback: 0.3, ..base}) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+impl EffectSpec {
+    pub fn set (&mut self, key: &str, value: &str) -> () {
+	match key {
+	    "wet" => { self.wet = value.parse().unwrap(); },
+	    "dry" => { self.dry = value.parse().unwrap(); },
+	    "room" | "size" => { self.room = value.parse().unwrap(); },
+	    "damp" | "damping" => { self.damp = value.parse().unwrap(); },
+	    "time" => { self.time = value.parse().unwrap(); },
+	    "feedback" | "fb" => { self.feedback = value.parse().unwrap(); },
+	    "rate" => { self.rate = value.parse().unwrap(); },
+	    "depth" => { self.depth = value.parse().unwrap(); },
+	    "delay" => { self.delay = value.parse().unwrap(); },
+	    "stages" => { self.stages = value.parse().unwrap(); },
+	    huh => { eprint!("Unrecognised option: {}\n", huh); }
+	}
+    }
+    /// The name an `EffectSpec` is parsed from.
+    pub fn name (&self) -> &'static str {
+	match self.kind {
+	    EffectKind::Reverb => { "reverb" },
+	    EffectKind::Delay => { "delay" },
+	    EffectKind::Chorus => { "chorus" },
+	    EffectKind::Flanger => { "flanger" },
+	    EffectKind::Phaser => { "phaser" }
+	}
+    }
+    /// The options `set` takes to make this effect from its name, as keys and values.
+    pub fn options (&self) -> Vec<(String, String)> {
+	let values: [(&str, f64); 8] = [("wet", self.wet), ("dry", self.dry), ("room", self.room), ("damp", self.damp), ("feedback", self.feedback), ("rate", self.rate), ("depth", self.depth), ("delay", self.delay)];
+	let mut options: Vec<(String, String)> = values.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+	options.push(("time".to_string(), self.time.to_string()));
+	options.push(("stages".to_string(), self.stages.to_string()));
+	options
+    }
+    /**
+    @param meta_data The song's meta data, for tempo synced times
+    @param channel 0 for left (or mono), 1 for right
+    */
+    pub fn build (&self, meta_data: &MetaData, channel: usize) -> Effect {
+	// Right channel LFOs run a quarter cycle ahead
+	let phase: f64 = channel as f64 * 0.25;
+	let unit: Unit = match self.kind {
+	    EffectKind::Reverb => { Unit::Reverb(Reverb::new(self.room, self.damp, channel * 23)) },
+	    EffectKind::Delay => { Unit::Delay(DelayLine::new(self.time.samples(meta_data).round().max(1.0) as usize, self.feedback)) },
+	    EffectKind::Chorus | EffectKind::Flanger => { Unit::Modulated(ModulatedDelay::new(self.delay, self.depth, self.rate, phase, self.feedback)) },
+	    EffectKind::Phaser => { Unit::Phaser(Phaser::new(self.stages, self.depth, self.rate, phase, self.feedback)) }
+	};
+	Effect{wet: self.wet, dry: self.dry, unit: unit}
+    }
+}
+
+pub struct Effect {
+    wet: f64,
+    dry: f64,
+    unit: Unit,
+}
+
+impl Effect {
+    pub fn process (&mut self, input: f64) -> f64 {
+	let wet: f64 = match &mut self.unit {
+	    Unit::Reverb(reverb) => { reverb.process(input) },
+	    Unit::Delay(delay) => { delay.process(input) },
+	    Unit::Modulated(delay) => { delay.process(input) },
+	    Unit::Phaser(phaser) => { phaser.process(input) }
+	};
+	input * self.dry + wet * self.wet
+    }
+}
+
+enum Unit {
+    Reverb(Reverb),
+    Delay(DelayLine),
+    Modulated(ModulatedDelay),
+    Phaser(Phaser),
+}
+
+/// Trapezoidal state variable lowpass, stable while the cutoff moves.
+#[derive(Copy, Clone)]
+pub struct Lowpass {
+    state: [f64; 2],
+}
+
+impl Lowpass {
+    pub fn new () -> Self {
+	Self{state: [0.0; 2]}
+    }
+    /**
+    @param cutoff In Hz
+    @param resonance Q, 0.707 for no peak
+    */
+    pub fn process (&mut self, input: f64, cutoff: f64, resonance: f64) -> f64 {
+	let g: f64 = (std::f64::consts::PI * cutoff.clamp(10.0, SAMPLES_PER_SECOND as f64 * 0.49) / (SAMPLES_PER_SECOND as f64)).tan();
+	let k: f64 = 1.0 / resonance.max(0.1);
+	let a1: f64 = 1.0 / (1.0 + g * (g + k));
+	let a2: f64 = g * a1;
+	let a3: f64 = g * a2;
+	let v3: f64 = input - self.state[1];
+	let v1: f64 = a1 * self.state[0] + a2 * v3;
+	let v2: f64 = self.state[1] + a2 * self.state[0] + a3 * v3;
+	self.state = [2.0 * v1 - self.state[0], 2.0 * v2 - self.state[1]];
+	v2
+    }
+}
+
+/// Feedback delay with a fixed length. Used directly for echoes.
+struct DelayLine {
+    buffer: Vec<f64>,
+    index: usize,
+    feedback: f64,
+}
+
+impl DelayLine {
+    fn new (length: usize, feedback: f64) -> Self {
+	Self{buffer: vec![0.0; length], index: 0, feedback: feedback}
+    }
+    fn process (&mut self, input: f64) -> f64 {
+	let output: f64 = self.buffer[self.index];
+	self.buffer[self.index] = input + output * self.feedback;
+	self.index = (self.index + 1) % self.buffer.len();
+	output
+    }
+}
+
+/// Lowpass-feedback comb filter, as used by Freeverb.
+struct Comb {
+    buffer: Vec<f64>,
+    index: usize,
+    feedback: f64,
+    damp: f64,
+    filter_store: f64,
+}
+
+impl Comb {
+    fn process (&mut self, input: f64) -> f64 {
+	let output: f64 = self.buffer[self.index];
+	self.filter_store = output * (1.0 - self.damp) + self.filter_store * self.damp;
+	self.buffer[self.index] = input + self.filter_store * self.feedback;
+	self.index = (self.index + 1) % self.buffer.len();
+	output
+    }
+}
+
+/// Schroeder allpass, as used by Freeverb.
+struct Allpass {
+    buffer: Vec<f64>,
+    index: usize,
+}
+
+impl Allpass {
+    fn process (&mut self, input: f64) -> f64 {
+	let buffered: f64 = self.buffer[self.index];
+	self.buffer[self.index] = input + buffered * 0.5;
+	self.index = (self.index + 1) % self.buffer.len();
+	buffered - input
+    }
+}
+
+/// Freeverb: eight parallel combs into four allpasses in series.
+struct Reverb {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Reverb {
+    fn new (room: f64, damp: f64, spread: usize) -> Self {
+	// Tunings are in samples at 44.1kHz
+	let scale: f64 = (SAMPLES_PER_SECOND as f64) / 44100.0;
+	let size = |tuning: usize| (((tuning + spread) as f64 * scale) as usize).max(1);
+	Self{
+	    combs: [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617].into_iter().map(|t| Comb{buffer: vec![0.0; size(t)], index: 0, feedback: room * 0.28 + 0.7, damp: damp * 0.4, filter_store: 0.0}).collect(),
+	    allpasses: [556, 441, 341, 225].into_iter().map(|t| Allpass{buffer: vec![0.0; size(t)], index: 0}).collect(),
+	}
+    }
+    fn process (&mut self, input: f64) -> f64 {
+	let scaled: f64 = input * 0.015;
+	let mut a: f64 = 0.0;
+	for comb in &mut self.combs {
+	    a += comb.process(scaled);
+	}
+	for allpass in &mut self.allpasses {
+	    a = allpass.process(a);
+	}
+	a * 3.0
+    }
+}
+
+/// Short delay swept by a sine LFO. Chorus without feedback, flanger with.
+struct ModulatedDelay {
+    buffer: Vec<f64>,
+    index: usize,
+    delay: f64, // In samples
+    depth: f64, // In samples
+    rate: f64, // In Hz
+    phase: f64, // In cycles
+    feedback: f64,
+    sample: u128,
+}
+
+impl ModulatedDelay {
+    fn new (delay_ms: f64, depth_ms: f64, rate: f64, phase: f64, feedback: f64) -> Self {
+	let delay: f64 = delay_ms * 0.001 * (SAMPLES_PER_SECOND as f64);
+	let depth: f64 = depth_ms * 0.001 * (SAMPLES_PER_SECOND as f64);
+	Self{buffer: vec![0.0; (delay + depth) as usize + 2], index: 0, delay: delay, depth: depth, rate: rate, phase: phase, feedback: feedback, sample: 0}
+    }
+    fn process (&mut self, input: f64) -> f64 {
+	let time: f64 = (self.sample as f64) / (SAMPLES_PER_SECOND as f64);
+	self.sample += 1;
+	let offset: f64 = (self.delay + self.depth * (std::f64::consts::TAU * (self.rate * time + self.phase)).sin()).max(1.0);
+	let length: usize = self.buffer.len();
+	let position: f64 = (self.index + length) as f64 - offset;
+	let i: usize = position.floor() as usize;
+	let fraction: f64 = position - position.floor();
+	let output: f64 = crate::lerp(fraction, self.buffer[i % length], self.buffer[(i + 1) % length]);
+	self.buffer[self.index] = input + output * self.feedback;
+	self.index = (self.index + 1) % length;
+	output
+    }
+}
+
+/// Chain of first order allpasses with a swept corner frequency.
+struct Phaser {
+    stages: Vec<(f64, f64)>, // (last input, last output)
+    depth: f64,
+    rate: f64,
+    phase: f64,
+    feedback: f64,
+    last: f64,
+    sample: u128,
+}
+
+impl Phaser {
+    fn new (stages: usize, depth: f64, rate: f64, phase: f64, feedback: f64) -> Self {
+	Self{stages: vec![(0.0, 0.0); stages.max(1)], depth: depth, rate: rate, phase: phase, feedback: feedback, last: 0.0, sample: 0}
+    }
+    fn process (&mut self, input: f64) -> f64 {
+	let time: f64 = (self.sample as f64) / (SAMPLES_PER_SECOND as f64);
+	self.sample += 1;
+	// Sweep exponentially between 200Hz and (at full depth) 3200Hz
+	let sweep: f64 = (1.0 + (std::f64::consts::TAU * (self.rate * time + self.phase)).sin()) * 0.5 * self.depth;
+	let corner: f64 = 200.0 * 16.0_f64.powf(sweep);
+	let t: f64 = (std::f64::consts::PI * corner / (SAMPLES_PER_SECOND as f64)).tan();
+	let coefficient: f64 = (t - 1.0) / (t + 1.0);
+	let mut a: f64 = input + self.last * self.feedback;
+	for (x1, y1) in &mut self.stages {
+	    let y: f64 = coefficient * a + *x1 - coefficient * *y1;
+	    *x1 = a;
+	    *y1 = y;
+	    a = y;
+	}
+	self.last = a;
+	a
+    }
+}
@@ -0,0 +1,175 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{ParseError, WaveForm, parse_bool, parse_note_value};
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Target {
+    Pitch, // Hz
+    Volume, // Added to the note's volume
+    PulseWidth, // Added to the duty cycle of pulse waves
+    Cutoff, // Hz, added to the note's filter cutoff
+    Pan, // Added to the note's pan
+    HarmonicMix, // Added to the level of the upper harmonics of har(...) waves
+}
+
+impl FromStr for Target {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s {
+	    "pitch" | "frequency" | "freq" => { Ok(Target::Pitch) },
+	    "volume" | "vol" => { Ok(Target::Volume) },
+	    "pulse_width" | "pw" | "width" => { Ok(Target::PulseWidth) },
+	    "cutoff" | "filter" => { Ok(Target::Cutoff) },
+	    "pan" => { Ok(Target::Pan) },
+	    "harmonic_mix" | "harmonics" => { Ok(Target::HarmonicMix) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let name: &str = match self {
+	    Target::Pitch => { "pitch" },
+	    Target::Volume => { "volume" },
+	    Target::PulseWidth => { "pulse_width" },
+	    Target::Cutoff => { "cutoff" },
+	    Target::Pan => { "pan" },
+	    Target::HarmonicMix => { "harmonic_mix" }
+	};
+	write!(f, "{}", name)
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum Rate {
+    Hertz(f64),
+    Beats(f64), // Length of one cycle, follows the tempo
+}
+
+impl FromStr for Rate {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s.strip_suffix("Hz").or_else(|| s.strip_suffix("hz")) {
+	    Option::Some(hz) => { hz.parse().map(Rate::Hertz).map_err(|_| ParseError) },
+	    // Plain numbers are in Hz, note values like 1/16 are synced to the tempo
+	    Option::None => {
+		match s.parse::<f64>() {
+		    Ok(hz) => { Ok(Rate::Hertz(hz)) },
+		    Err(_) => { parse_note_value(s).map(Rate::Beats) }
+		}
+	    }
+	}
+    }
+}
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    Rate::Hertz(hz) => { write!(f, "{}Hz", hz) },
+	    // As a fraction of a quarter note, so it isn't read back as Hz
+	    Rate::Beats(beats) => { write!(f, "{}/4", beats) }
+	}
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Lfo {
+    pub name: String,
+    shape: WaveForm,
+    rate: Rate,
+    phase: f64, // In cycles
+    depth: f64, // In the target's units
+    target: Target,
+    delay: f64, // Milliseconds after the note starts before the LFO kicks in
+    fade: f64, // Milliseconds to reach full depth after the delay
+    free: bool, // Run from the start of the song instead of restarting with every note
+    unipolar: bool, // Swing from 0 to depth instead of from -depth to depth
+}
+
+impl Lfo {
+    pub fn new (name: &str) -> Self {
+	Self{name: name.to_string(), shape: WaveForm::Sine, rate: Rate::Hertz(5.0), phase: 0.0, depth: 0.0, target: Target::Pitch, delay: 0.0, fade: 0.0, free: false, unipolar: false}
+    }
+    pub fn set (&mut self, key: &str, value: &str) -> () {
+	match key {
+	    "shape" | "wave" => { self.shape = value.parse().unwrap(); },
+	    "rate" | "frequency" | "freq" => { self.rate = value.parse().unwrap(); },
+	    "phase" => { self.phase = value.parse().unwrap(); },
+	    "depth" | "mag" | "magnitude" => { self.depth = value.parse().unwrap(); },
+	    "target" | "to" => { self.target = value.parse().unwrap(); },
+	    "delay" => { self.delay = value.parse().unwrap(); },
+	    "fade" | "fade_in" => { self.fade = value.parse().unwrap(); },
+	    "sync" => {
+		match value {
+		    "note" => { self.free = false; },
+		    "free" | "song" => { self.free = true; },
+		    huh => { eprint!("Unrecognised sync: {}\n", huh); }
+		}
+	    },
+	    "unipolar" => { self.unipolar = parse_bool(value).unwrap(); },
+	    huh => { eprint!("Unrecognised option: {}\n", huh); }
+	}
+    }
+    /// The options `set` takes to make this LFO, as keys and values.
+    pub fn options (&self) -> Vec<(String, String)> {
+	vec![
+	    ("shape".to_string(), self.shape.to_string()),
+	    ("rate".to_string(), self.rate.to_string()),
+	    ("phase".to_string(), self.phase.to_string()),
+	    ("depth".to_string(), self.depth.to_string()),
+	    ("target".to_string(), self.target.to_string()),
+	    ("delay".to_string(), self.delay.to_string()),
+	    ("fade".to_string(), self.fade.to_string()),
+	    ("sync".to_string(), if self.free { "free" } else { "note" }.to_string()),
+	    ("unipolar".to_string(), self.unipolar.to_string())
+	]
+    }
+    /**
+    @param note_time Seconds since the note started
+    @param song_time Seconds since the song started
+    @param tempo In beats per minute
+    */
+    fn value (&self, note_time: f64, song_time: f64, tempo: f64) -> f64 {
+	let since_delay_ms: f64 = note_time * 1000.0 - self.delay;
+	if since_delay_ms < 0.0 { return 0.0; }
+	let amount: f64 = if since_delay_ms < self.fade { since_delay_ms / self.fade } else { 1.0 };
+	let hertz: f64 = match self.rate {
+	    Rate::Hertz(hz) => { hz },
+	    Rate::Beats(beats) => { tempo / 60.0 / beats }
+	};
+	let time: f64 = if self.free { song_time } else { note_time };
+	let a: f64 = self.shape.audio_at(time * hertz + self.phase);
+	self.depth * amount * if self.unipolar { a } else { a * 2.0 - 1.0 }
+    }
+}
+
+/// How far the LFOs on a note push each of its parameters at one point in time.
+pub struct Modulation {
+    pub pitch: f64,
+    pub volume: f64,
+    pub pulse_width: f64,
+    pub cutoff: f64,
+    pub pan: f64,
+    pub harmonic_mix: f64,
+}
+
+impl Modulation {
+    pub fn new () -> Self {
+	Self{pitch: 0.0, volume: 0.0, pulse_width: 0.0, cutoff: 0.0, pan: 0.0, harmonic_mix: 0.0}
+    }
+    pub fn add (&mut self, target: Target, amount: f64) -> () {
+	match target {
+	    Target::Pitch => { self.pitch += amount; },
+	    Target::Volume => { self.volume += amount; },
+	    Target::PulseWidth => { self.pulse_width += amount; },
+	    Target::Cutoff => { self.cutoff += amount; },
+	    Target::Pan => { self.pan += amount; },
+	    Target::HarmonicMix => { self.harmonic_mix += amount; }
+	}
+    }
+    pub fn add_lfo (&mut self, lfo: &Lfo, note_time: f64, song_time: f64, tempo: f64) -> () {
+	self.add(lfo.target, lfo.value(note_time, song_time, tempo));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::score::{Measure, NoteValue, Score, Written, bass_clef};
+
+/*
+LilyPond (https://lilypond.org) source for printing a song as sheet music, with
+a staff for every part. Pitches are absolute, like `c'` for middle C.
+*/
+
+/// Name and octave marks of a MIDI note number, spelt with sharps.
+fn pitch (midi: i32) -> String {
+    let name: &str = ["c", "cis", "d", "dis", "e", "f", "fis", "g", "gis", "a", "ais", "b"][midi.rem_euclid(12) as usize];
+    let octave: i32 = midi.div_euclid(12) - 4;
+    let marks: String = if octave > 0 { "'".repeat(octave as usize) } else { ",".repeat(-octave as usize) };
+    format!("{}{}", name, marks)
+}
+
+fn duration (value: &NoteValue) -> String {
+    format!("{}{}", value.denominator, if value.dotted { "." } else { "" })
+}
+
+/// Writes one voice of a bar, with its triplets grouped.
+fn write_voice (ly: &mut String, voice: &[Written], signature: (u32, u32)) -> () {
+    let mut in_tuplet: bool = false;
+    for written in voice {
+	if written.value.triplet != in_tuplet {
+	    ly.push_str(if written.value.triplet { "\\tuplet 3/2 { " } else { "} " });
+	    in_tuplet = written.value.triplet;
+	}
+	if written.whole_bar {
+	    ly.push_str(&format!("R1*{}/{} ", signature.0, signature.1));
+	    continue;
+	}
+	match written.pitches.as_slice() {
+	    [] => { ly.push_str("r"); },
+	    [single] => { ly.push_str(&pitch(*single)); },
+	    chord => { ly.push_str(&format!("<{}>", chord.iter().map(|e| pitch(*e)).collect::<Vec<String>>().join(" "))); }
+	}
+	ly.push_str(&duration(&written.value));
+	if written.tie { ly.push('~'); }
+	ly.push(' ');
+    }
+    if in_tuplet { ly.push_str("} "); }
+}
+
+/// Writes a score out as LilyPond source.
+pub fn write (score: &Score) -> String {
+    let mut ly: String = String::new();
+    ly.push_str("\\version \"2.24.0\"\n\n");
+    if !score.title.is_empty() {
+	ly.push_str(&format!("\\header {{\n  title = \"{}\"\n  tagline = ##f\n}}\n\n", score.title.replace('\\', "\\\\").replace('"', "\\\"")));
+    }
+    ly.push_str("\\score {\n  <<\n");
+    for (p, part) in score.parts.iter().enumerate() {
+	let measures: Vec<Measure> = score.measures(part);
+	let voice_count: usize = measures.first().map_or(1, |e| e.voices.len());
+	ly.push_str(&format!("    \\new Staff \\with {{ instrumentName = \"{}\" }} {{\n", part.name.replace('\\', "\\\\").replace('"', "\\\"")));
+	ly.push_str(&format!("      \\clef {}\n", if bass_clef(&measures) { "bass" } else { "treble" }));
+	if p == 0 {
+	    ly.push_str(&format!("      \\tempo 4 = {}\n", score.tempo.round()));
+	}
+	if voice_count > 1 { ly.push_str("      <<\n"); }
+	for v in 0..voice_count {
+	    if v > 0 { ly.push_str("      \\\\\n"); }
+	    ly.push_str("      {\n");
+	    for (m, measure) in measures.iter().enumerate() {
+		ly.push_str("        ");
+		if m == 0 || measures[m - 1].signature != measure.signature {
+		    ly.push_str(&format!("\\time {}/{} ", measure.signature.0, measure.signature.1));
+		}
+		write_voice(&mut ly, &measure.voices[v], measure.signature);
+		ly.push_str(if m + 1 == measures.len() { "\\bar \"|.\"" } else { "|" });
+		ly.push_str(&format!(" % {}\n", measure.number));
+	    }
+	    ly.push_str("      }\n");
+	}
+	if voice_count > 1 { ly.push_str("      >>\n"); }
+	ly.push_str("    }\n");
+    }
+    ly.push_str("  >>\n  \\layout { }\n}\n");
+    ly
+}
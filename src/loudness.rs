@@ -0,0 +1,129 @@
+use crate::SAMPLES_PER_SECOND;
+use crate::dynamics::gain_to_decibels;
+
+/*
+Loudness as measured by ITU-R BS.1770 / EBU R128: K-weighted mean square over
+400ms blocks, gated at -70 LUFS and then at 10 LU below the ungated average.
+*/
+
+pub struct Analysis {
+    pub integrated: f64, // LUFS
+    pub true_peak: f64, // dBTP
+    pub rms: f64, // dBFS
+}
+
+/// Direct form I biquad.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process (&mut self, input: f64) -> f64 {
+	let output: f64 = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+	self.x = [input, self.x[0]];
+	self.y = [output, self.y[0]];
+	output
+    }
+}
+
+/// The two K-weighting stages (a high shelf, then a high pass), worked out for our sample rate.
+fn k_weighting () -> [Biquad; 2] {
+    let rate: f64 = SAMPLES_PER_SECOND as f64;
+
+    let k: f64 = (std::f64::consts::PI * 1681.974450955533 / rate).tan();
+    let q: f64 = 0.7071752369554196;
+    let vh: f64 = 10.0_f64.powf(3.999843853973347 / 20.0);
+    let vb: f64 = vh.powf(0.4996667741545416);
+    let a0: f64 = 1.0 + k / q + k * k;
+    let shelf: Biquad = Biquad{
+	b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+	a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	x: [0.0; 2], y: [0.0; 2],
+    };
+
+    let k: f64 = (std::f64::consts::PI * 38.13547087602444 / rate).tan();
+    let q: f64 = 0.5003270373238773;
+    let a0: f64 = 1.0 + k / q + k * k;
+    let high_pass: Biquad = Biquad{
+	b: [1.0, -2.0, 1.0],
+	a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	x: [0.0; 2], y: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+fn integrated_loudness (frames: &[[f64; 2]]) -> f64 {
+    let mut filters: [[Biquad; 2]; 2] = [k_weighting(), k_weighting()];
+    let weighted: Vec<f64> = frames.iter().map(|frame| {
+	let mut power: f64 = 0.0;
+	for channel in 0..2 {
+	    let a: f64 = filters[channel].iter_mut().fold(frame[channel], |a, filter| filter.process(a));
+	    power += a * a;
+	}
+	power
+    }).collect();
+
+    // 400ms blocks, overlapping by 75%
+    let block: usize = (SAMPLES_PER_SECOND as usize * 4) / 10;
+    let step: usize = block / 4;
+    let mut blocks: Vec<f64> = Vec::<f64>::new();
+    let mut start: usize = 0;
+    while start + block <= weighted.len() {
+	blocks.push(weighted[start..start + block].iter().sum::<f64>() / (block as f64));
+	start += step;
+    }
+
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean = |powers: &Vec<f64>| powers.iter().sum::<f64>() / (powers.len() as f64);
+    let absolute: Vec<f64> = blocks.into_iter().filter(|p| loudness(*p) > -70.0).collect();
+    if absolute.is_empty() { return f64::NEG_INFINITY; }
+    let threshold: f64 = loudness(mean(&absolute)) - 10.0;
+    let relative: Vec<f64> = absolute.into_iter().filter(|p| loudness(*p) > threshold).collect();
+    if relative.is_empty() { return f64::NEG_INFINITY; }
+    loudness(mean(&relative))
+}
+
+/// Peak of the signal oversampled four times with a windowed sinc, so peaks between samples are caught.
+fn true_peak (frames: &[[f64; 2]]) -> f64 {
+    const TAPS: isize = 8; // On either side
+    let mut kernels: Vec<Vec<f64>> = Vec::<Vec<f64>>::new();
+    for phase in 1..4 {
+	let offset: f64 = phase as f64 / 4.0;
+	kernels.push((-TAPS + 1..=TAPS).map(|i| {
+	    let x: f64 = i as f64 - offset;
+	    let sinc: f64 = (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x);
+	    let window: f64 = 0.5 + 0.5 * (std::f64::consts::PI * x / (TAPS as f64)).cos();
+	    sinc * window
+	}).collect());
+    }
+    let mut peak: f64 = 0.0;
+    for channel in 0..2 {
+	for n in 0..frames.len() {
+	    peak = peak.max(frames[n][channel].abs());
+	    for kernel in &kernels {
+		let mut a: f64 = 0.0;
+		for (k, i) in (-TAPS + 1..=TAPS).enumerate() {
+		    let index: isize = n as isize + i;
+		    if index >= 0 && (index as usize) < frames.len() {
+			a += frames[index as usize][channel] * kernel[k];
+		    }
+		}
+		peak = peak.max(a.abs());
+	    }
+	}
+    }
+    peak
+}
+
+pub fn analyse (frames: &[[f64; 2]]) -> Analysis {
+    let square_sum: f64 = frames.iter().map(|f| f[0] * f[0] + f[1] * f[1]).sum();
+    Analysis{
+	integrated: integrated_loudness(frames),
+	true_peak: gain_to_decibels(true_peak(frames)),
+	rms: gain_to_decibels((square_sum / (frames.len().max(1) * 2) as f64).sqrt()),
+    }
+}
@@ -2,10 +2,35 @@ use std::io::Write;
 use std::fs::File;
 use std::io::BufRead;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
+mod abc;
+mod chord;
+mod convert;
+mod dither;
+mod drum;
+mod dynamics;
+mod envelope;
+mod expr;
+mod fx;
+mod lfo;
+mod lilypond;
+mod loudness;
+mod meter;
+mod mixer;
+mod mml;
+mod musicxml;
+mod sample;
+mod score;
+mod script;
+mod steps;
+mod tracker;
+mod xml;
+mod zip;
+
 const SAMPLES_PER_SECOND: u128 = 16000;
-const CHANNEL_COUNT: u8 = 1;
+const CHANNEL_COUNT: u8 = 2;
 
 /*
 // Will be removed soon.
@@ -28,45 +53,6 @@ fn scale_time_sin (time_in: u128, frequency_a: f64, frequency_b: f64, meta_frequ
 }
 */
 
-/**
-@param global_time The time since the start of the composition
-@param time_in The time since the start of the note
-@param frequency_start The frequency to start at
-@param frequency_end The frequency to end at
-@param lfo_frequency The frequency to wobble the frequency at
-@param duration The duration of the Entire note, encluding the release
-*/
-fn scale_time (global_time: f64, time_in: f64, frequency_start: f64, frequency_end: f64, lfo_frequency: Option<f64>, lfo_magnitude: Option<f64>, duration: f64) -> f64 {
-    match (lfo_frequency, lfo_magnitude) {
-	(Option::Some(lfo_freq), Option::Some(lfo_mag)) => {
-	    if frequency_start == frequency_end {
-		global_time * frequency_start + lfo_mag * ( std::f64::consts::TAU * lfo_freq * time_in ).sin() / ( std::f64::consts::TAU * lfo_freq )
-	    } else {
-		duration * frequency_start * ( frequency_end / frequency_start ).powf( time_in / duration ) / ( frequency_end / frequency_start ).ln() + lfo_mag * ( std::f64::consts::TAU * lfo_freq * time_in ).sin() / ( std::f64::consts::TAU * lfo_freq ) - ( duration * frequency_start / ( frequency_end / frequency_start ).ln() )
-	    }
-	},
-	(Option::None, Option::None) => {
-	    if frequency_start == frequency_end {
-		global_time * frequency_start
-	    } else {
-		duration * frequency_start * ( frequency_end / frequency_start ).powf( time_in / duration ) / ( frequency_end / frequency_start ).ln() - ( duration * frequency_start / ( frequency_end / frequency_start ).ln() )
-	    }
-	},
-	(Option::Some(_), Option::None) | (Option::None, Option::Some(_)) => {
-	    eprint!("Only one of the two lfo parameters has been set!");
-	    0.0
-	}
-    }
-}
-
-fn sample_data (amplitude: f64) -> [u8; 2] {
-    let mut a: f64 = amplitude * 65535.0 - 32768.0;
-    if a > 32767.0 { a = 32767.0; }
-    if a < -32768.0 { a = -32768.0; }
-    let double_byte: i16 = a as i16;
-    double_byte.to_le_bytes()
-}
-
 fn lerp (x: f64, a: f64, b: f64) -> f64 {
     x*(b-a)+a
 }
@@ -230,6 +216,31 @@ fn pitch_to_frequency (pitch_name: &str) -> Result<f64, ParseError> {
     }
 }
 
+/**
+Parses a length in beats. Accepts plain numbers of beats, or note values such
+as `1/8`, with an optional `d` (or `.`) for dotted and `t` for triplet. Only a
+value with a `/` is a note value, so `2.` is still two beats.
+*/
+fn parse_note_value (s: &str) -> Result<f64, ParseError> {
+    if !s.contains('/') { return s.parse::<f64>().map_err(|_| ParseError); }
+    let (value, scale): (&str, f64) = if let Option::Some(v) = s.strip_suffix('d').or_else(|| s.strip_suffix('.')) {
+	(v, 1.5)
+    } else if let Option::Some(v) = s.strip_suffix('t') {
+	(v, 2.0 / 3.0)
+    } else {
+	(s, 1.0)
+    };
+    match value.split_once('/') {
+	Option::Some((numerator, denominator)) => {
+	    match (numerator.parse::<f64>(), denominator.parse::<f64>()) {
+		(Ok(n), Ok(d)) => { Ok(4.0 * n / d * scale) }, // A whole note is four beats
+		_ => { Err(ParseError) }
+	    }
+	},
+	Option::None => { Err(ParseError) }
+    }
+}
+
 fn pitch_to_frequency_or_disable (s: String) -> Result<Option<f64>, ParseError> {
     match s.as_str() {
 	"disable" | "none" | "no" | "off" => { Result::Ok(Option::None) },
@@ -240,7 +251,7 @@ fn pitch_to_frequency_or_disable (s: String) -> Result<Option<f64>, ParseError>
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 enum WaveForm {
     Square,
     Triangle,
@@ -249,10 +260,12 @@ enum WaveForm {
     SawTooth,
     Noise,
     Harmonics(Vec::<f64>),
+    Drum(drum::Kind), // Played by the note itself, see drum::Drum
+    Sample(usize), // Index into the song's samples, so they're stored once
 }
 
 #[derive(Debug)]
-struct ParseError;
+pub struct ParseError;
 impl FromStr for WaveForm {
     type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -277,15 +290,40 @@ impl FromStr for WaveForm {
 			if parts.len() < 2 { return Err(ParseError); }
 			Ok(WaveForm::Harmonics(parts[1..].into_iter().map(|e| match e.parse() { Ok(v) => {v}, Err(_) => {0.0} } ).collect()))
 		    },
-		    _ => { Err(ParseError) }
+		    drum => { drum.parse().map(WaveForm::Drum) }
 		}
 	    },
 	}
     }
 }
 
+impl std::fmt::Display for WaveForm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    WaveForm::Square => { write!(f, "squ") },
+	    WaveForm::Triangle => { write!(f, "tri") },
+	    WaveForm::Sine => { write!(f, "sin") },
+	    WaveForm::Pulse(ratio) => { write!(f, "pul({})", ratio) },
+	    WaveForm::SawTooth => { write!(f, "saw") },
+	    WaveForm::Noise => { write!(f, "noi") },
+	    WaveForm::Harmonics(volumes) => { write!(f, "har({})", volumes.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(",")) },
+	    WaveForm::Drum(kind) => { write!(f, "{}", kind) },
+	    // Only the structured formats can hold the recording
+	    WaveForm::Sample(_) => { write!(f, "sample") }
+	}
+    }
+}
+
 impl WaveForm {
-    fn audio_at (self, virt_time: f64) -> f64 {
+    fn audio_at (&self, virt_time: f64) -> f64 {
+	self.modulated_audio_at(virt_time, 0.0, 1.0)
+    }
+    /**
+    @param virt_time The phase, in cycles
+    @param pulse_width Added to the duty cycle of pulse waves
+    @param harmonic_mix Level of every harmonic but the first of har(...) waves
+    */
+    fn modulated_audio_at (&self, virt_time: f64, pulse_width: f64, harmonic_mix: f64) -> f64 {
 	match self {
 	    WaveForm::Square => {
 		if virt_time % 1.0 < 0.5 {
@@ -305,7 +343,7 @@ impl WaveForm {
 		(1.0+( virt_time * std::f64::consts::TAU ).sin())*0.5
 	    },
 	    WaveForm::Pulse(ratio) => {
-		if virt_time % 1.0 < 1.0 - ratio {
+		if virt_time % 1.0 < 1.0 - (ratio + pulse_width).clamp(0.0, 1.0) {
 		    0.0
 		} else {
 		    1.0
@@ -322,20 +360,23 @@ impl WaveForm {
 		if a / 100.0 < 0.5 { 0.0 } else { 1.0 }
 	    },
 	    WaveForm::Harmonics(volumes) => {
-		let sum: f64 = volumes.clone().into_iter().reduce(|a, b| a + b).unwrap();
+		let mix: f64 = harmonic_mix.max(0.0);
+		let sum: f64 = volumes.iter().enumerate().map(|(i, v)| if i == 0 { *v } else { v * mix }).reduce(|a, b| a + b).unwrap();
 		let mut frequency: f64 = 1.0;
 		let mut a: f64 = 0.0;
 		for volume in volumes {
-		    a += WaveForm::Sine.audio_at(virt_time*frequency) * volume;
+		    a += WaveForm::Sine.audio_at(virt_time*frequency) * volume * if frequency == 1.0 { 1.0 } else { mix };
 		    frequency += 1.0;
 		}
-		a / sum
-	    }
+		if sum == 0.0 { 0.5 } else { a / sum }
+	    },
+	    WaveForm::Drum(_) => { 0.5 },
+	    WaveForm::Sample(_) => { 0.5 }
 	}
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Note {
     wave_form: WaveForm,
     volume: f64, // From 0 to 1
@@ -345,36 +386,254 @@ struct Note {
     lfo_pitch_mag: Option<f64>, // In Hz or none
     lfo_volume_freq: Option<f64>, // In Hz or none
     lfo_volume_mag: Option<f64>, // In unit or none
+    lfos: Vec<lfo::Lfo>,
+    envelopes: Vec<envelope::Envelope>, // Modulation envelopes
+    curves: Vec<(lfo::Target, envelope::Curve)>, // Automation over the note, in beats since its start
     duration: f64, // In beats
     time: f64, // In beats since last note
-    attack: f64, // Milliseconds
-    decay: f64, // Milliseconds
-    sustain: f64, // Scalar
-    release: f64 //  Milliseconds
+    volume_envelope: envelope::Dahdsr, // Drum waves are one shots, and only use its decay
+    drum: drum::Drum,
+    pan: f64, // From -1 (left) to 1 (right)
+    cutoff: Option<f64>, // Lowpass filter cutoff in Hz, or no filter
+    resonance: f64, // Filter Q
+    harmonic_mix: f64, // Scalar for the upper harmonics of har(...) waves
+    retrigger: bool, // Start the wave from the beginning of its cycle instead of running freely
+    mono: bool, // Play as one voice with the other mono notes of its instrument on its track, see join_mono_notes
+    portamento: fx::DelayTime, // Time to glide from the voice's last pitch (mono)
+    portamento_rate: bool, // The portamento is the time per octave instead of for every glide
+    track: usize, // Index into the song's tracks
+    stem: usize, // Index into the song's stems
+    #[serde(default)]
+    instrument: Option<String>, // Name of the instrument it was made from, or of the part it was imported from
+}
+
+/// The state a note needs to carry from one sample to the next while it plays.
+struct Voice {
+    phase: f64, // In cycles
+    filter: fx::Lowpass,
+    drum: drum::State,
+}
+
+impl Voice {
+    fn new (note: &Note, meta_data: &MetaData) -> Self {
+	// Unless retriggered, start where an oscillator running since the start of the song would be
+	let phase: f64 = if note.retrigger { 0.0 } else { note.time * 60.0 / meta_data.tempo * note.frequency };
+	Self{phase: phase, filter: fx::Lowpass::new(), drum: drum::State::new()}
+    }
 }
 
 impl Note {
     fn new () -> Self {
-	Self{wave_form: WaveForm::Square, volume: 0.25, frequency: 440.0, glide_to: Option::None, lfo_pitch_freq: Option::None, lfo_volume_freq: Option::None, lfo_pitch_mag: Option::None, lfo_volume_mag: Option::None, duration: 0.25, time: 0.0, attack: 0.0, decay: 0.0, sustain: 1.0, release: 0.0}
-    }
-    fn audio_at (self, time: f64, meta_data: MetaData) -> f64 {
-	let capped_time_ms: f64 = if time > (self.time + self.duration) * 60.0 / meta_data.tempo { (self.time + self.duration) * 60000.0 / meta_data.tempo } else { time * 1000.0 };
-	let time_since_start_s: f64 = time - self.time * 60.0 / meta_data.tempo; // in seconds (uncapped)
-	let time_since_start_ms: f64 = capped_time_ms - self.time * 60000.0 / meta_data.tempo; // in ms (capped)
-	let time_until_end_ms: f64 = (self.time + self.duration) * 60000.0 / meta_data.tempo + self.release - time * 1000.0; // in ms
-	let mut volume_multiplier: f64 = 1.0;
-	if time_since_start_ms < self.attack {
-	    volume_multiplier *= time_since_start_ms / self.attack;
-	} else if time_since_start_ms < self.attack + self.decay {
-	    volume_multiplier *= lerp((time_since_start_ms - self.attack) / self.decay, 1.0, self.sustain);
-	} else {
-	    volume_multiplier *= self.sustain;
+	Self{wave_form: WaveForm::Square, volume: 0.25, frequency: 440.0, glide_to: Option::None, lfo_pitch_freq: Option::None, lfo_volume_freq: Option::None, lfo_pitch_mag: Option::None, lfo_volume_mag: Option::None, lfos: Vec::<lfo::Lfo>::new(), envelopes: Vec::<envelope::Envelope>::new(), curves: Vec::<(lfo::Target, envelope::Curve)>::new(), duration: 0.25, time: 0.0, volume_envelope: envelope::Dahdsr::new(), drum: drum::Drum::new(), pan: 0.0, cutoff: Option::None, resonance: 0.707, harmonic_mix: 1.0, retrigger: false, mono: false, portamento: fx::DelayTime::Milliseconds(0.0), portamento_rate: false, track: 0, stem: 0, instrument: Option::None}
+    }
+    /**
+    Sets the options that DEFAULT and NOTE lines share.
+    @return False if the option isn't one of them
+    */
+    fn set (&mut self, key: &str, value: &str, lfos: &[lfo::Lfo], envelopes: &[envelope::Envelope]) -> bool {
+	if self.volume_envelope.set(key, value) || self.drum.set(key, value) { return true; }
+	match key {
+	    "wave" => { self.wave_form = value.parse().unwrap(); },
+	    "volume" => { self.volume = value.parse().unwrap(); },
+	    "frequency" => { self.frequency = value.parse().unwrap(); },
+	    "pitch" => { self.frequency = pitch_to_frequency(value).unwrap(); }, // Placeholder
+	    "duration" | "dur" => { self.duration = parse_note_value(value).unwrap(); },
+	    "lfo_pitch_freq" | "lfo_frequency_freq" | "lfo_frequency_frequency" | "lfo_freq_freq" | "lfo_meta_freq" => { self.lfo_pitch_freq = parse_f64_or_disable(value.to_string()).unwrap() },
+	    "lfo_volume_freq" | "lfo_vol_freq" | "lfo_vol_frequency" | "lfo_volume_frequency" => { self.lfo_volume_freq = parse_f64_or_disable(value.to_string()).unwrap() },
+	    "lfo_pitch_mag" | "lfo_frequency_mag" | "lfo_frequency_magnitude" | "lfo_freq_mag" => { self.lfo_pitch_mag = parse_f64_or_disable(value.to_string()).unwrap() },
+	    "lfo_volume_mag" | "lfo_vol_mag" | "lfo_volume_magnitude" | "lfo_vol_magnitude" => { self.lfo_volume_mag = parse_f64_or_disable(value.to_string()).unwrap() },
+	    "lfo" | "lfos" => {
+		self.lfos.clear();
+		if !matches!(value, "disable" | "none" | "no" | "off") {
+		    for name in value.split(',') {
+			match lfos.iter().find(|e| e.name == name) {
+			    Option::Some(found) => { self.lfos.push(found.clone()); },
+			    Option::None => { eprint!("No LFO called {}\n", name); }
+			}
+		    }
+		}
+	    },
+	    "env" | "envs" | "envelope" | "envelopes" => {
+		self.envelopes.clear();
+		if !matches!(value, "disable" | "none" | "no" | "off") {
+		    for name in value.split(',') {
+			match envelopes.iter().find(|e| e.name == name) {
+			    Option::Some(found) => { self.envelopes.push(found.clone()); },
+			    Option::None => { eprint!("No envelope called {}\n", name); }
+			}
+		    }
+		}
+	    },
+	    curve if curve.ends_with("_env") && curve[..curve.len() - 4].parse::<lfo::Target>().is_ok() => {
+		let target: lfo::Target = curve[..curve.len() - 4].parse().unwrap();
+		self.curves.retain(|(t, _)| *t != target);
+		if !matches!(value, "disable" | "none" | "no" | "off") {
+		    self.curves.push((target, envelope::Curve::parse(value, matches!(target, lfo::Target::Pitch)).unwrap()));
+		}
+	    },
+	    "retrigger" | "sync" => { self.retrigger = parse_bool(value).unwrap(); },
+	    "mono" | "monophonic" | "legato" => { self.mono = parse_bool(value).unwrap(); },
+	    "portamento" | "porta" => { self.portamento = value.parse().unwrap(); },
+	    "portamento_mode" | "porta_mode" => {
+		match value {
+		    "time" | "constant_time" => { self.portamento_rate = false; },
+		    "rate" | "constant_rate" => { self.portamento_rate = true; },
+		    huh => { eprint!("Unrecognised portamento mode: {}\n", huh); }
+		}
+	    },
+	    "pan" => { self.pan = value.parse().unwrap(); },
+	    "cutoff" => { self.cutoff = parse_f64_or_disable(value.to_string()).unwrap(); },
+	    "resonance" | "q" => { self.resonance = value.parse().unwrap(); },
+	    "harmonic_mix" => { self.harmonic_mix = value.parse().unwrap(); },
+	    _ => { return false; }
+	}
+	true
+    }
+    /// The options of a NOTE line that make this note, but for its time, as keys and values.
+    fn options (&self) -> Vec<(String, String)> {
+	let optional = |value: Option<f64>| -> String { value.map_or("none".to_string(), |e| e.to_string()) };
+	let names = |names: Vec<&String>| -> String { if names.is_empty() { "none".to_string() } else { names.into_iter().cloned().collect::<Vec<String>>().join(",") } };
+	let mut options: Vec<(String, String)> = vec![
+	    ("wave".to_string(), self.wave_form.to_string()),
+	    ("volume".to_string(), self.volume.to_string()),
+	    ("frequency".to_string(), self.frequency.to_string()),
+	    ("duration".to_string(), self.duration.to_string()),
+	    ("glide_to_freq".to_string(), optional(self.glide_to)),
+	    ("lfo_pitch_freq".to_string(), optional(self.lfo_pitch_freq)),
+	    ("lfo_pitch_mag".to_string(), optional(self.lfo_pitch_mag)),
+	    ("lfo_volume_freq".to_string(), optional(self.lfo_volume_freq)),
+	    ("lfo_volume_mag".to_string(), optional(self.lfo_volume_mag)),
+	    ("lfo".to_string(), names(self.lfos.iter().map(|e| &e.name).collect())),
+	    ("env".to_string(), names(self.envelopes.iter().map(|e| &e.name).collect()))
+	];
+	for target in [lfo::Target::Pitch, lfo::Target::Volume, lfo::Target::PulseWidth, lfo::Target::Cutoff, lfo::Target::Pan, lfo::Target::HarmonicMix] {
+	    let curve: Option<&envelope::Curve> = self.curves.iter().find(|(t, _)| *t == target).map(|(_, curve)| curve);
+	    options.push((format!("{}_env", target), curve.map_or("none".to_string(), |e| e.to_string())));
+	}
+	options.extend(self.volume_envelope.options());
+	options.extend(self.drum.options());
+	options.extend([
+	    ("pan".to_string(), self.pan.to_string()),
+	    ("cutoff".to_string(), optional(self.cutoff)),
+	    ("resonance".to_string(), self.resonance.to_string()),
+	    ("harmonic_mix".to_string(), self.harmonic_mix.to_string()),
+	    ("retrigger".to_string(), self.retrigger.to_string()),
+	    ("mono".to_string(), self.mono.to_string()),
+	    ("portamento".to_string(), self.portamento.to_string()),
+	    ("portamento_mode".to_string(), if self.portamento_rate { "rate" } else { "time" }.to_string())
+	]);
+	options
+    }
+    /// Warns about settings that can't be used as they are.
+    fn check (&self) -> () {
+	if self.lfo_pitch_freq.is_some() != self.lfo_pitch_mag.is_some() {
+	    eprint!("Only one of lfo_pitch_freq and lfo_pitch_mag has been set, so the pitch LFO is ignored!\n");
+	}
+	if self.lfo_volume_freq.is_some() != self.lfo_volume_mag.is_some() {
+	    eprint!("Only one of lfo_volume_freq and lfo_volume_mag has been set, so the volume LFO is ignored!\n");
+	}
+    }
+    /**
+    Returns the note's left and right output at some time in seconds.
+    @param samples The song's samples, for notes that play one
+    */
+    fn audio_at (&self, time: f64, meta_data: &MetaData, samples: &[sample::Sample], voice: &mut Voice) -> [f64; 2] {
+	let time_since_start_s: f64 = time - self.time * 60.0 / meta_data.tempo; // in seconds
+	let time_since_start_ms: f64 = time_since_start_s * 1000.0;
+	let time_since_end_ms: f64 = time * 1000.0 - (self.time + self.duration) * 60000.0 / meta_data.tempo; // negative until the note ends
+	let volume_multiplier: f64 = match self.wave_form {
+	    WaveForm::Drum(_) => { 1.0 },
+	    _ => { self.volume_envelope.level(time_since_start_ms, time_since_end_ms) }
+	};
+
+	let mut modulation: lfo::Modulation = lfo::Modulation::new();
+	for l in &self.lfos {
+	    modulation.add_lfo(l, time_since_start_s, time, meta_data.tempo);
+	}
+	for e in &self.envelopes {
+	    e.modulate(&mut modulation, time_since_start_ms, time_since_end_ms);
+	}
+	if let (Option::Some(lfo_freq), Option::Some(lfo_mag)) = (self.lfo_pitch_freq, self.lfo_pitch_mag) {
+	    modulation.pitch += lfo_mag * ( std::f64::consts::TAU * lfo_freq * time_since_start_s ).cos();
+	}
+	if let (Option::Some(lfo_volume_freq), Option::Some(lfo_volume_mag)) = (self.lfo_volume_freq, self.lfo_volume_mag) {
+	    modulation.volume += WaveForm::Sine.audio_at(time*lfo_volume_freq)*lfo_volume_mag;
+	}
+
+	// Glides are exponential over the entire note, including the release
+	let mut frequency: f64 = match self.glide_to {
+	    Option::Some(glide_to) => { self.frequency * ( glide_to / self.frequency ).powf( time_since_start_s / ( self.duration * 60.0 / meta_data.tempo + self.volume_envelope.release * 0.001 ) ) },
+	    Option::None => { self.frequency }
+	};
+	let mut volume: f64 = self.volume;
+	let mut pan: f64 = self.pan;
+	let mut cutoff: Option<f64> = self.cutoff;
+	let mut harmonic_mix: f64 = self.harmonic_mix;
+	let mut pulse_width: f64 = 0.0; // Offset from the width in the wave form
+	// Automation curves replace the note's own value for their parameter
+	let time_since_start_beats: f64 = time_since_start_s * meta_data.tempo / 60.0;
+	for (target, curve) in &self.curves {
+	    let value: f64 = curve.value_at(time_since_start_beats);
+	    match target {
+		lfo::Target::Pitch => { frequency = value; },
+		lfo::Target::Volume => { volume = value; },
+		lfo::Target::PulseWidth => { pulse_width = value - if let WaveForm::Pulse(ratio) = self.wave_form { ratio } else { 0.0 }; },
+		lfo::Target::Cutoff => { cutoff = Option::Some(value); },
+		lfo::Target::Pan => { pan = value; },
+		lfo::Target::HarmonicMix => { harmonic_mix = value; }
+	    }
+	}
+
+	frequency += modulation.pitch;
+	let mut a: f64 = match self.wave_form {
+	    WaveForm::Drum(kind) => { self.drum.audio_at(kind, time_since_start_s, self.drum_decay(kind), &mut voice.drum) },
+	    WaveForm::Sample(index) => { samples[index].audio_at(voice.phase) * 2.0 - 1.0 },
+	    _ => { self.wave_form.modulated_audio_at(voice.phase, pulse_width + modulation.pulse_width, harmonic_mix + modulation.harmonic_mix) * 2.0 - 1.0 }
+	};
+	voice.phase += frequency / (SAMPLES_PER_SECOND as f64);
+	if let Option::Some(cutoff) = cutoff {
+	    a = voice.filter.process(a, cutoff + modulation.cutoff, self.resonance);
+	}
+	a *= (volume + modulation.volume) * volume_multiplier;
+	mixer::pan_gains(pan + modulation.pan).map(|g| a * g)
+    }
+    /// Milliseconds for a drum hit to die away, from the note's decay if it has one.
+    fn drum_decay (&self, kind: drum::Kind) -> f64 {
+	if self.volume_envelope.decay > 0.0 { self.volume_envelope.decay } else { kind.decay() }
+    }
+    /// How long the note sounds for in beats, including the release, or the whole hit for drums.
+    fn sounding_beats (&self, tempo: f64) -> f64 {
+	match self.wave_form {
+	    WaveForm::Drum(kind) => { self.drum_decay(kind) * tempo / 60000.0 },
+	    _ => { self.duration + self.volume_envelope.release * tempo / 60000.0 }
+	}
+    }
+    /// The note's pitch (ignoring LFOs and glide_to) some time in beats after it starts.
+    fn pitch_at (&self, time: f64) -> f64 {
+	match self.curves.iter().find(|(t, _)| *t == lfo::Target::Pitch) {
+	    Option::Some((_, curve)) => { curve.value_at(time) },
+	    Option::None => { self.frequency }
 	}
-	if time_until_end_ms < self.release {
-	    volume_multiplier *= lerp(time_until_end_ms / self.release, 0.0, 1.0);
+    }
+    /// Glides from one pitch to another, starting some time in beats after the note starts.
+    fn bend_pitch (&mut self, time: f64, from: f64, to: f64, glide: f64) -> () {
+	let mut curve: envelope::Curve = match self.curves.iter().position(|(t, _)| *t == lfo::Target::Pitch) {
+	    Option::Some(index) => { self.curves.remove(index).1 },
+	    Option::None => { envelope::Curve::constant(self.frequency) }
+	};
+	if time > 0.0 {
+	    curve.push(time, from, envelope::Segment::Exponential);
+	} else {
+	    // Bending from the start, so the first point is where it bends from
+	    curve = envelope::Curve::constant(from);
 	}
-	let vol: f64 = match (self.lfo_volume_freq, self.lfo_volume_mag) { (Option::Some(lfo_volume_freq), Option::Some(lfo_volume_mag)) => {self.volume+WaveForm::Sine.audio_at(time*lfo_volume_freq)*lfo_volume_mag}, _ => {self.volume} };
-	self.wave_form.audio_at( scale_time(time, time_since_start_s, self.frequency, self.glide_to.unwrap_or_else(|| self.frequency), self.lfo_pitch_freq, self.lfo_pitch_mag, (self.duration + self.release * 0.001) * 60.0 / meta_data.tempo) ) * vol * volume_multiplier
+	curve.push(time + glide, to, if glide > 0.0 { envelope::Segment::Exponential } else { envelope::Segment::Hold });
+	self.curves.push((lfo::Target::Pitch, curve));
+    }
+    /// How long in beats the portamento takes to get to this note from a pitch.
+    fn portamento_beats (&self, from: f64, tempo: f64) -> f64 {
+	let beats: f64 = self.portamento.beats(tempo);
+	if self.portamento_rate { beats * (self.frequency / from).log2().abs() } else { beats }
     }
     fn delayed_by (self, time: f64) -> Self {
 	let mut other = self.clone();
@@ -383,15 +642,21 @@ impl Note {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 struct MetaData {
     tempo: f64,
     length: f64,
+    limit: Option<f64>, // Limiter ceiling in dBFS, or no limiter
+    limit_lookahead: f64, // Milliseconds
+    limit_release: f64, // Milliseconds
+    clip: dynamics::Clip,
+    bits: u16, // Bits per sample in the output, 8, 16 or 24
+    dither: dither::Dither,
 }
 
 impl MetaData {
     fn new () -> Self {
-	Self{tempo: 100.0, length: 16.0}
+	Self{tempo: 100.0, length: 16.0, limit: Option::None, limit_lookahead: 5.0, limit_release: 100.0, clip: dynamics::Clip::Hard, bits: 16, dither: dither::Dither::Tpdf}
     }
 }
 
@@ -413,6 +678,74 @@ impl RepeatPM {
     }
 }
 
+/// Adds a note to the song, as many times as the REPEAT section it's in asks for.
+fn add_note (notes: &mut Vec<Note>, note: Note, mode: ParseMode) -> () {
+    match mode {
+	ParseMode::Standard => { notes.push(note); },
+	ParseMode::Repeat(options) => {
+	    for i in 0..options.number {
+		notes.push(note.clone().delayed_by(options.time * i as f64));
+	    }
+	}
+    }
+}
+
+/// The time of a note, either `bar:beat` or in beats after the DEFAULT's time.
+fn note_time (meter: &meter::Meter, s: &str, offset: f64) -> f64 {
+    let (time, absolute): (f64, bool) = meter.parse_position(s).unwrap();
+    if absolute { time } else { time + offset }
+}
+
+/**
+Places an untimed NOTE or STEPS line in cursor mode, and moves the cursor past
+it, or inside a CHORD section, notes where the section will end.
+@param length In beats
+@return Where the line starts, or none outside of cursor mode
+*/
+fn advance_cursor (cursor: &mut Option<f64>, chord_length: &mut Option<f64>, length: f64) -> Option<f64> {
+    let position: f64 = (*cursor)?;
+    match chord_length {
+	Option::Some(longest) => { *longest = longest.max(length); },
+	Option::None => { *cursor = Option::Some(position + length); }
+    }
+    Option::Some(position)
+}
+
+/// Starts a note from an instrument, or from the defaults if it doesn't use one.
+fn note_from (default: &Note, instruments: &[(String, Note)], instrument: Option<&str>) -> Note {
+    match instrument {
+	Option::Some(name) => {
+	    match instruments.iter().find(|(e, _)| e == name) {
+		Option::Some((_, found)) => {
+		    let mut note: Note = found.clone();
+		    note.time = default.time;
+		    note.instrument = Option::Some(name.to_string());
+		    note
+		},
+		Option::None => {
+		    eprint!("No instrument called {}\n", name);
+		    default.clone()
+		}
+	    }
+	},
+	Option::None => { default.clone() }
+    }
+}
+
+/// Notes in a track go in its stem, others in one for their instrument or DEFAULT block.
+fn stem_name (tracks: &[mixer::Track], current_track: usize, instrument: Option<&str>, default_block: usize) -> String {
+    match (current_track, instrument) {
+	(0, Option::Some(name)) => { name.to_string() },
+	(0, Option::None) => { format!("default_{}", default_block) },
+	_ => { tracks[current_track].name.clone() }
+    }
+}
+
+/// Finds the `inst=` option of a line.
+fn find_instrument (pieces: &[String]) -> Option<String> {
+    pieces.iter().find_map(|e| e.strip_prefix("inst=").or_else(|| e.strip_prefix("instrument=")).map(|e| e.to_string()))
+}
+
 fn parse_f64_or_disable (s: String) -> Result<Option<f64>, std::num::ParseFloatError> {
     match s.as_str() {
 	"disable" | "none" | "no" | "off" => { Result::Ok(Option::None) },
@@ -423,22 +756,221 @@ fn parse_f64_or_disable (s: String) -> Result<Option<f64>, std::num::ParseFloatE
     }
 }
 
-fn print_wave( file: File ) -> () {
-    let mut data_buffer: Vec<u8> = Vec::<u8>::new();
-    
-    let lines = std::io::BufReader::new(file).lines();
+fn parse_bool (s: &str) -> Result<bool, ParseError> {
+    match s {
+	"yes" | "on" | "true" | "1" => { Ok(true) },
+	"no" | "off" | "false" | "0" => { Ok(false) },
+	_ => { Err(ParseError) }
+    }
+}
+
+struct Options {
+    solo: Vec<String>, // Track names
+    stems: Option<String>, // Directory to write one file per stem to
+    normalize: Option<f64>, // Integrated loudness target in LUFS
+    peak: Option<f64>, // True peak target in dBTP
+    default: Vec<String>, // Note options like `wave=tri`, to play imported scores with
+    tune: Option<u32>, // Which tune of an ABC file to play, by its X: number
+    export: Option<String>, // Notation to write the song out as instead of rendering it, `musicxml`, `mxl` or `lilypond`
+}
+
+impl Options {
+    fn new () -> Self {
+	Self{solo: Vec::<String>::new(), stems: Option::None, normalize: Option::None, peak: Option::None, default: Vec::<String>::new(), tune: Option::None, export: Option::None}
+    }
+}
+
+/// Parses levels like `-14LUFS`, `-1dBTP` or `-1`.
+fn parse_level (s: &str, unit: &str) -> Result<f64, ParseError> {
+    let split: Option<usize> = s.len().checked_sub(unit.len()).filter(|e| s.is_char_boundary(*e));
+    let number: &str = match split {
+	Option::Some(at) if s[at..].eq_ignore_ascii_case(unit) => { &s[..at] },
+	_ => { s }
+    };
+    number.parse().map_err(|_| ParseError)
+}
+
+/// Normalizes the mix to the targets from the command line, then runs the master dynamics and reports the loudness.
+fn finish_mix (frames: &mut [[f64; 2]], meta_data: &MetaData, options: &Options) -> () {
+    if options.normalize.is_some() || options.peak.is_some() {
+	let analysis: loudness::Analysis = loudness::analyse(frames);
+	let mut gain: f64 = f64::INFINITY; // In dB
+	if let Option::Some(target) = options.normalize {
+	    gain = gain.min(target - analysis.integrated);
+	}
+	if let Option::Some(target) = options.peak {
+	    gain = gain.min(target - analysis.true_peak);
+	}
+	if gain.is_finite() {
+	    eprint!("Normalizing by {:+.2} dB\n", gain);
+	    let scale: f64 = dynamics::decibels_to_gain(gain);
+	    for frame in frames.iter_mut() {
+		frame[0] *= scale;
+		frame[1] *= scale;
+	    }
+	} else {
+	    eprint!("Nothing to normalize, the song is silent\n");
+	}
+    }
+    dynamics::master(frames, meta_data);
+    let analysis: loudness::Analysis = loudness::analyse(frames);
+    eprint!("Integrated loudness: {:.1} LUFS, true peak: {:.2} dBTP, RMS: {:.2} dBFS\n", analysis.integrated, analysis.true_peak, analysis.rms);
+}
+
+#[derive(Serialize, Deserialize)]
+struct Song {
+    meta_data: MetaData,
+    notes: Vec<Note>, // Sorted by time, and with the mono notes joined once loaded
+    tracks: Vec<mixer::Track>,
+    master: mixer::Track, // Effects and automation on the whole mix
+    stems: Vec<String>, // Names of the tracks and DEFAULT blocks notes are grouped into
+    meter: meter::Meter,
+    instruments: Vec<(String, Note)>, // As they were at the end of the song
+    #[serde(default)]
+    samples: Vec<sample::Sample>, // Played by notes with a Sample wave form
+}
+
+/// Finds the stem with this name, adding it if it's new.
+fn stem_index (stems: &mut Vec<String>, name: String) -> usize {
+    match stems.iter().position(|e| *e == name) {
+	Option::Some(index) => { index },
+	Option::None => {
+	    stems.push(name);
+	    stems.len() - 1
+	}
+    }
+}
+
+/// Lines being read, from the song itself, a file it includes or a macro it calls.
+struct Source {
+    lines: Box<dyn Iterator<Item = std::io::Result<String>>>,
+    path: std::path::PathBuf, // The file, or for macros the file they're called from
+    offset: f64, // In beats, added to the times of the notes from these lines
+    first_note: usize, // How many notes there were before these lines
+    library: bool, // IMPORTed, so only its definitions are read
+    called: Option<String>, // The macro's name
+    saved: Option<Saved>, // For included files, what to go back to once they're done
+}
+
+/// What a file that includes another one had going, so the included file can't change it.
+struct Saved {
+    default: Note,
+    current_track: usize,
+    cursor: Option<f64>,
+    chord_length: Option<f64>,
+    default_block: usize,
+    current_mode: ParseMode,
+    arpeggio: Option<chord::Arpeggio>,
+}
+
+/// Lines saved by a MACRO section, to be played back by CALL lines.
+struct Macro {
+    name: String,
+    parameters: Vec<(String, Option<String>)>, // Names and default values
+    lines: Vec<String>,
+}
+
+/**
+@param file The song
+@param path Where the song is, for the files it includes
+*/
+fn parse_song( file: File, path: &std::path::Path ) -> Song {
+    let path: std::path::PathBuf = std::fs::canonicalize(path).unwrap_or(path.to_path_buf());
+    let mut sources: Vec<Source> = vec![Source{lines: Box::new(std::io::BufReader::new(file).lines()), path: path, offset: 0.0, first_note: 0, library: false, called: Option::None, saved: Option::None}];
+    let mut imported: Vec<std::path::PathBuf> = Vec::<std::path::PathBuf>::new(); // Libraries are only read once
+    let mut variables: Vec<(String, String)> = Vec::<(String, String)>::new();
+    let mut macros: Vec<Macro> = Vec::<Macro>::new();
+    let mut recording: Option<Macro> = Option::None; // Inside a MACRO section if some
+    let mut scripting: Option<script::Script> = Option::None; // Inside a SCRIPT section if some
 
     let mut default: Note = Note::new();
     let mut meta_data: MetaData = MetaData::new();
     let mut notes: Vec<Note> = Vec::<Note>::new();
     let mut current_mode: ParseMode = ParseMode::Standard;
+    let mut master: mixer::Track = mixer::Track::new("master", false);
+    let mut tracks: Vec<mixer::Track> = vec![mixer::Track::new("main", false)];
+    let mut current_track: usize = 0; // Inside a TRACK or BUS section if not 0
+    let mut stems: Vec<String> = Vec::<String>::new();
+    let mut lfos: Vec<lfo::Lfo> = Vec::<lfo::Lfo>::new();
+    let mut envelopes: Vec<envelope::Envelope> = Vec::<envelope::Envelope>::new();
+    let mut default_block: usize = 0; // The DEFAULT block notes are in
+    let mut default_blocks: usize = 0; // How many there have been, so an included file's don't share a stem with the next
+    let mut arpeggio: Option<chord::Arpeggio> = Option::None; // Inside an ARP section if some
+    let mut instruments: Vec<(String, Note)> = Vec::<(String, Note)>::new();
+    let mut cursor: Option<f64> = Option::None; // In beats, in cursor mode
+    let mut chord_length: Option<f64> = Option::None; // Of the longest note so far, inside a CHORD section
+    let mut meter: meter::Meter = meter::Meter::new();
+    let mut length: Option<String> = Option::None; // Worked out at the end, when the time signatures are all known
+    let mut grace_notes: Vec<(std::ops::Range<usize>, fx::DelayTime, f64)> = Vec::<(std::ops::Range<usize>, fx::DelayTime, f64)>::new(); // Moved earlier by their flam at the end, when the tempo is known, but not before the start of their lane (in beats from there)
     
     let sep  = Regex::new(r"[ \t]+").expect("Invalid Regex");
     let sep1 = Regex::new(r"[=]").expect("Invalid Regex");
-    for l in lines {
+    let quoted = Regex::new(r#""([^"]*)""#).expect("Invalid Regex");
+    let expander: expr::Expander = expr::Expander::new();
+    while let Option::Some(source) = sources.last_mut() {
+	let library: bool = source.library;
+	let l: std::io::Result<String> = match source.lines.next() {
+	    Option::Some(l) => { l },
+	    Option::None => {
+		// Done with an included file, so its notes move to where it was included
+		let done: Source = sources.pop().unwrap();
+		// A section can't go on past the end of the file or macro it started in
+		if let Option::Some(unfinished) = recording.take() {
+		    eprint!("Macro {} has no END_MACRO\n", unfinished.name);
+		}
+		if scripting.take().is_some() {
+		    eprint!("Script has no END_SCRIPT\n");
+		}
+		for note in &mut notes[done.first_note..] {
+		    note.time += done.offset;
+		}
+		if let Option::Some(saved) = done.saved {
+		    default = saved.default;
+		    current_track = saved.current_track;
+		    cursor = saved.cursor;
+		    chord_length = saved.chord_length;
+		    default_block = saved.default_block;
+		    current_mode = saved.current_mode;
+		    arpeggio = saved.arpeggio;
+		}
+		continue;
+	    }
+	};
 	match l {
 	    Result::Ok(line) => {
+		if let Option::Some(saving) = &mut recording {
+		    // Macros are saved as they are, and only filled in when they're called
+		    if line.trim() == "END_MACRO" {
+			eprint!("End of macro {}\n", saving.name);
+			let saved: Macro = recording.take().unwrap();
+			macros.retain(|e| e.name != saved.name);
+			macros.push(saved);
+		    } else {
+			saving.lines.push(line);
+		    }
+		    continue;
+		}
+		if let Option::Some(script) = &mut scripting {
+		    if line.trim() == "END_SCRIPT" {
+			eprint!("End of script\n");
+			let script: script::Script = scripting.take().unwrap();
+			// What the script writes is read next, like a macro being called
+			if let Result::Ok(written) = script.run(&variables, &instruments) {
+			    let lines: Vec<std::io::Result<String>> = written.into_iter().map(Result::Ok).collect();
+			    let path: std::path::PathBuf = sources.last().unwrap().path.clone();
+			    sources.push(Source{lines: Box::new(lines.into_iter()), path: path, offset: script.offset, first_note: notes.len(), library: library, called: Option::None, saved: Option::None});
+			}
+		    } else {
+			script.code.push_str(&line);
+			script.code.push('\n');
+		    }
+		    continue;
+		}
+		let line: String = expander.expand(&line, &variables);
 		let pieces: Vec<String> = sep.split(line.as_str()).into_iter().map(|e| e.to_string()).collect();
+		if library && !matches!(pieces[0].as_str(), "INSTRUMENT" | "INST" | "LFO" | "ENV" | "LET" | "MACRO" | "IMPORT" | "") {
+		    continue;
+		}
 		match pieces[0].as_str() {
 		    "META" => {
 			eprint!("Meta Data\n");
@@ -447,70 +979,214 @@ fn print_wave( file: File ) -> () {
 			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
 			    match halves[0].as_str() {
 				"tempo" => { meta_data.tempo = halves[1].parse().unwrap(); },
-				"length" => { meta_data.length = halves[1].parse().unwrap(); },
+				"length" => { length = Option::Some(halves[1].clone()); },
+				"time_sig" | "time_signature" => {
+				    let (numerator, denominator): (u32, u32) = meter::Meter::parse_signature(halves[1].as_str()).unwrap();
+				    let bar: u32 = pieces.iter().find_map(|e| e.strip_prefix("bar=")).map(|e| e.parse().unwrap()).unwrap_or(1);
+				    meter.set(bar, numerator, denominator);
+				},
+				"bar" => {}, // Where the time signature changes
+				"limit" | "limiter" | "ceiling" => { meta_data.limit = parse_f64_or_disable(halves[1].clone()).unwrap(); },
+				"limit_lookahead" | "lookahead" => { meta_data.limit_lookahead = halves[1].parse().unwrap(); },
+				"limit_release" => { meta_data.limit_release = halves[1].parse().unwrap(); },
+				"clip" => { meta_data.clip = halves[1].parse().unwrap(); },
+				"bits" | "bit_depth" => {
+				    match halves[1].parse() {
+					Ok(bits @ (8 | 16 | 24)) => { meta_data.bits = bits; },
+					_ => { eprint!("Only 8, 16 and 24 bit output is supported\n"); }
+				    }
+				},
+				"dither" => { meta_data.dither = halves[1].parse().unwrap(); },
 				huh => { eprint!("Unrecognised option: {}\n", huh); }
 			    }
 			}
 		    },
 		    "DEFAULT" => {
 			eprint!("Note Default\n");
+			default_blocks += 1;
+			default_block = default_blocks;
 			for piece in &pieces[1..] {
 			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
 			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
 			    match halves[0].as_str() {
-				"wave" => { default.wave_form = halves[1].parse().unwrap(); },
-				"volume" => { default.volume = halves[1].parse().unwrap(); },
-				"frequency" => { default.frequency = halves[1].parse().unwrap(); },
-				"pitch" => { default.frequency = pitch_to_frequency(halves[1].as_str()).unwrap(); }, // Placeholder
-				"duration" => { default.duration = halves[1].parse().unwrap(); },
-				"time" => { default.time = halves[1].parse().unwrap(); },
-				"a" | "attack" => { default.attack = halves[1].parse().unwrap(); },
-				"d" | "decay" => { default.decay = halves[1].parse().unwrap(); },
-				"s" | "sustain" => { default.sustain = halves[1].parse().unwrap(); },
-				"r" | "release" => { default.release = halves[1].parse().unwrap(); },
-				"lfo_pitch_freq" | "lfo_frequency_freq" | "lfo_frequency_frequency" | "lfo_freq_freq" | "lfo_meta_freq" => { default.lfo_pitch_freq = parse_f64_or_disable(halves[1].clone()).unwrap() },
-				"lfo_volume_freq" | "lfo_vol_freq" | "lfo_vol_frequency" | "lfo_volume_frequency" => { default.lfo_volume_freq = parse_f64_or_disable(halves[1].clone()).unwrap() },
-				"lfo_pitch_mag" | "lfo_frequency_mag" | "lfo_frequency_magnitude" | "lfo_freq_mag" => { default.lfo_pitch_mag = parse_f64_or_disable(halves[1].clone()).unwrap() },
-				"lfo_volume_mag" | "lfo_vol_mag" | "lfo_volume_magnitude" | "lfo_vol_magnitude" => { default.lfo_volume_mag = parse_f64_or_disable(halves[1].clone()).unwrap() },
+				"time" => { default.time = meter.parse_position(halves[1].as_str()).unwrap().0; },
 				"glide_to" => { default.glide_to = parse_f64_or_disable(halves[1].clone()).unwrap() },
-				huh => { eprint!("Unrecognised option: {}\n", huh); }
+				huh => {
+				    if !default.set(huh, halves[1].as_str(), &lfos, &envelopes) {
+					eprint!("Unrecognised option: {}\n", huh);
+				    }
+				}
 			    }
 			}
 		    },
 		    "NOTE" => {
 			eprint!("Note\n");
-			let mut note: Note = default.clone();
+			let instrument: Option<String> = find_instrument(&pieces);
+			let mut note: Note = note_from(&default, &instruments, instrument.as_deref());
+			note.track = current_track;
+			note.stem = stem_index(&mut stems, stem_name(&tracks, current_track, instrument.as_deref(), default_block));
+			let mut chord_symbol: Option<String> = Option::None;
+			let mut timed: bool = false;
 			for piece in &pieces[1..] {
 			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
 			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
 			    match halves[0].as_str() {
-				"wave" => { note.wave_form = halves[1].parse().unwrap(); },
-				"volume" => { note.volume = halves[1].parse().unwrap(); },
-				"frequency" => { note.frequency = halves[1].parse().unwrap(); },
-				"pitch" => { note.frequency = pitch_to_frequency(halves[1].as_str()).unwrap(); }, // Placeholder
-				"duration" => { note.duration = halves[1].parse().unwrap(); },
-				"time" => { note.time = halves[1].parse::<f64>().unwrap() + default.time; },
-				"a" | "attack" => { note.attack = halves[1].parse().unwrap(); },
-				"d" | "decay" => { note.decay = halves[1].parse().unwrap(); },
-				"s" | "sustain" => { note.sustain = halves[1].parse().unwrap(); },
-				"r" | "release" => { note.release = halves[1].parse().unwrap(); },
-				"lfo_pitch_freq" | "lfo_frequency_freq" | "lfo_frequency_frequency" | "lfo_freq_freq" | "lfo_meta_freq" => { note.lfo_pitch_freq = parse_f64_or_disable(halves[1].clone()).unwrap() },
-				"lfo_volume_freq" | "lfo_vol_freq" | "lfo_vol_frequency" | "lfo_volume_frequency" => { note.lfo_volume_freq = parse_f64_or_disable(halves[1].clone()).unwrap() },
-				"lfo_pitch_mag" | "lfo_frequency_mag" | "lfo_frequency_magnitude" | "lfo_freq_mag" => { note.lfo_pitch_mag = parse_f64_or_disable(halves[1].clone()).unwrap() },
-				"lfo_volume_mag" | "lfo_vol_mag" | "lfo_volume_magnitude" | "lfo_vol_magnitude" => { note.lfo_volume_mag = parse_f64_or_disable(halves[1].clone()).unwrap() },
+				"time" => {
+				    note.time = note_time(&meter, halves[1].as_str(), default.time);
+				    timed = true;
+				},
 				"glide_to_freq" | "glide_to_frequency" => { note.glide_to = parse_f64_or_disable(halves[1].clone()).unwrap() },
 				"glide_to_pitch" | "glide_to" => { note.glide_to = pitch_to_frequency_or_disable(halves[1].clone()).unwrap() },
-				huh => { eprint!("Unrecognised option: {}\n", huh); }
+				"chord" => { chord_symbol = Option::Some(halves[1].clone()); },
+				"inst" | "instrument" => {},
+				huh => {
+				    if !note.set(huh, halves[1].as_str(), &lfos, &envelopes) {
+					eprint!("Unrecognised option: {}\n", huh);
+				    }
+				}
+			    }
+			}
+			note.check();
+			if !timed {
+			    if let Option::Some(position) = advance_cursor(&mut cursor, &mut chord_length, note.duration) {
+				note.time = position;
+			    }
+			}
+			// Chords and arpeggios become plain notes here, the pitch sets the register of a chord
+			let frequencies: Vec<f64> = match &chord_symbol {
+			    Option::Some(symbol) => { chord::voice(symbol, note.frequency).unwrap() },
+			    Option::None => { vec![note.frequency] }
+			};
+			let played: Vec<Note> = match arpeggio {
+			    Option::Some(arpeggio) => {
+				arpeggio.steps(&frequencies, note.duration).into_iter().map(|(time, duration, frequency)| {
+				    let mut step: Note = note.clone().delayed_by(time);
+				    step.duration = duration;
+				    step.frequency = frequency;
+				    step
+				}).collect()
+			    },
+			    Option::None => {
+				frequencies.into_iter().map(|frequency| {
+				    let mut tone: Note = note.clone();
+				    tone.frequency = frequency;
+				    tone
+				}).collect()
+			    }
+			};
+			for note in played {
+			    add_note(&mut notes, note, current_mode);
+			}
+		    },
+		    "STEPS" => {
+			eprint!("Steps\n");
+			// The pattern is quoted, so it can be split up with spaces
+			let pattern: String = quoted.captures(&line).map(|e| e[1].to_string()).unwrap_or(String::new());
+			let pieces: Vec<String> = sep.split(quoted.replace(&line, "").trim()).into_iter().map(|e| e.to_string()).collect();
+			let instrument: Option<String> = find_instrument(&pieces);
+			let mut note: Note = note_from(&default, &instruments, instrument.as_deref());
+			note.track = current_track;
+			note.stem = stem_index(&mut stems, stem_name(&tracks, current_track, instrument.as_deref(), default_block));
+			let mut lane: steps::Lane = steps::Lane::new();
+			let mut timed: bool = false;
+			for piece in &pieces[1..] {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    match halves[0].as_str() {
+				"time" => {
+				    note.time = note_time(&meter, halves[1].as_str(), default.time);
+				    timed = true;
+				},
+				"inst" | "instrument" => {},
+				huh => {
+				    if !lane.set(huh, halves[1].as_str()) && !note.set(huh, halves[1].as_str(), &lfos, &envelopes) {
+					eprint!("Unrecognised option: {}\n", huh);
+				    }
+				}
+			    }
+			}
+			eprint!("\t{}\n", pattern);
+			note.check();
+			if !timed {
+			    if let Option::Some(position) = advance_cursor(&mut cursor, &mut chord_length, lane.length(&pattern)) {
+				note.time = position;
+			    }
+			}
+			for (time, volume, flam) in lane.hits(&pattern) {
+			    let mut hit: Note = note.clone().delayed_by(time);
+			    hit.volume *= volume;
+			    let first: usize = notes.len();
+			    add_note(&mut notes, hit, current_mode);
+			    if let Option::Some(flam) = flam {
+				grace_notes.push((first..notes.len(), flam, time));
+			    }
+			}
+		    },
+		    "MELODY" => {
+			eprint!("Melody\n");
+			let melody: String = quoted.captures(&line).map(|e| e[1].to_string()).unwrap_or(String::new());
+			let pieces: Vec<String> = sep.split(quoted.replace(&line, "").trim()).into_iter().map(|e| e.to_string()).collect();
+			let instrument: Option<String> = find_instrument(&pieces);
+			let mut note: Note = note_from(&default, &instruments, instrument.as_deref());
+			note.track = current_track;
+			note.stem = stem_index(&mut stems, stem_name(&tracks, current_track, instrument.as_deref(), default_block));
+			let mut timed: bool = false;
+			for piece in &pieces[1..] {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    match halves[0].as_str() {
+				"time" => {
+				    note.time = note_time(&meter, halves[1].as_str(), default.time);
+				    timed = true;
+				},
+				"inst" | "instrument" => {},
+				huh => {
+				    if !note.set(huh, halves[1].as_str(), &lfos, &envelopes) {
+					eprint!("Unrecognised option: {}\n", huh);
+				    }
+				}
 			    }
 			}
-			match current_mode {
-			    ParseMode::Standard => { notes.push(note); },
-			    ParseMode::Repeat(options) => {
-				for i in 0..options.number {
-				    notes.push(note.clone().delayed_by(options.time * i as f64));
+			eprint!("\t{}\n", melody);
+			note.check();
+			match mml::parse(melody.as_str()) {
+			    Ok((played, length)) => {
+				if !timed {
+				    if let Option::Some(position) = advance_cursor(&mut cursor, &mut chord_length, length) {
+					note.time = position;
+				    }
+				}
+				for (time, duration, frequency) in played {
+				    let mut tone: Note = note.clone().delayed_by(time);
+				    tone.duration = duration;
+				    tone.frequency = frequency;
+				    add_note(&mut notes, tone, current_mode);
+				}
+			    },
+			    Err(_) => { eprint!("Unrecognised melody: {}\n", melody); }
+			}
+		    },
+		    "INSTRUMENT" | "INST" => {
+			let name: &str = pieces.get(1).map(|e| e.as_str()).unwrap_or("");
+			eprint!("Instrument {}\n", name);
+			let mut new_instrument: Note = Note::new();
+			for piece in pieces.iter().skip(2) {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    match halves[0].as_str() {
+				"glide_to_freq" | "glide_to_frequency" => { new_instrument.glide_to = parse_f64_or_disable(halves[1].clone()).unwrap() },
+				"glide_to_pitch" | "glide_to" => { new_instrument.glide_to = pitch_to_frequency_or_disable(halves[1].clone()).unwrap() },
+				huh => {
+				    if !new_instrument.set(huh, halves[1].as_str(), &lfos, &envelopes) {
+					eprint!("Unrecognised option: {}\n", huh);
+				    }
 				}
 			    }
 			}
+			new_instrument.check();
+			// Redefining an instrument only changes the notes that come after
+			instruments.retain(|(e, _)| e != name);
+			instruments.push((name.to_string(), new_instrument));
 		    },
 		    "REPEAT" => {
 			eprint!("Repeat mode enabled\n");
@@ -519,7 +1195,11 @@ fn print_wave( file: File ) -> () {
 			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
 			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
 			    match halves[0].as_str() {
-				"time" => { new_mode.time = halves[1].parse().unwrap(); },
+				"time" => {
+				    // How far apart the repeats are, as a note value, beats or bars
+				    let time: &str = halves[1].as_str();
+				    new_mode.time = if time.contains('/') { parse_note_value(time) } else { meter.parse_length(time) }.unwrap();
+				},
 				"n" | "num" | "number" | "times" => { new_mode.number = halves[1].parse().unwrap(); },
 				huh => { eprint!("Unrecognised option: {}\n", huh); }
 			    }
@@ -530,6 +1210,243 @@ fn print_wave( file: File ) -> () {
 			eprint!("Repeat mode disabled\n");
 			current_mode = ParseMode::Standard;
 		    },
+		    "ARP" => {
+			eprint!("Arpeggiator enabled\n");
+			let mut new_arpeggio: chord::Arpeggio = chord::Arpeggio::new();
+			for piece in &pieces[1..] {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    new_arpeggio.set(halves[0].as_str(), halves[1].as_str());
+			}
+			arpeggio = Option::Some(new_arpeggio);
+		    },
+		    "END_ARP" => {
+			eprint!("Arpeggiator disabled\n");
+			arpeggio = Option::None;
+		    },
+		    "CURSOR" => {
+			// Untimed notes follow on from each other, starting here
+			eprint!("Cursor\n");
+			cursor = Option::Some(default.time);
+			for piece in &pieces[1..] {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    match halves[0].as_str() {
+				"off" | "disable" | "none" | "no" => { cursor = Option::None; },
+				"time" if halves.len() > 1 => { cursor = Option::Some(note_time(&meter, halves[1].as_str(), default.time)); },
+				huh => { eprint!("Unrecognised option: {}\n", huh); }
+			    }
+			}
+			eprint!("\t{:?}\n", cursor);
+		    },
+		    "REST" => {
+			eprint!("Rest\n");
+			let mut length: f64 = default.duration;
+			for piece in &pieces[1..] {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    match halves[0].as_str() {
+				"dur" | "duration" => { length = parse_note_value(halves[1].as_str()).unwrap(); },
+				huh => { eprint!("Unrecognised option: {}\n", huh); }
+			    }
+			}
+			if advance_cursor(&mut cursor, &mut chord_length, length).is_none() {
+			    eprint!("REST only works in cursor mode\n");
+			}
+		    },
+		    "CHORD" => {
+			// The untimed notes of the section all start at the cursor
+			eprint!("Chord\n");
+			chord_length = Option::Some(0.0);
+		    },
+		    "END_CHORD" => {
+			eprint!("End of chord\n");
+			if let (Option::Some(position), Option::Some(length)) = (cursor, chord_length) {
+			    cursor = Option::Some(position + length);
+			}
+			chord_length = Option::None;
+		    },
+		    "FX" => {
+			eprint!("Effect\n");
+			match pieces.get(1).map(|e| e.parse::<fx::EffectSpec>()) {
+			    Option::Some(Ok(mut effect)) => {
+				eprint!("\t{}\n", pieces[1]);
+				for piece in &pieces[2..] {
+				    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+				    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+				    effect.set(halves[0].as_str(), halves[1].as_str());
+				}
+				if current_track == 0 {
+				    master.fx.push(effect);
+				} else {
+				    tracks[current_track].fx.push(effect);
+				}
+			    },
+			    _ => { eprint!("Unrecognised effect: {}\n", pieces.get(1).map(|e| e.as_str()).unwrap_or("")); }
+			}
+		    },
+		    "LFO" => {
+			let name: &str = pieces.get(1).map(|e| e.as_str()).unwrap_or("");
+			eprint!("LFO {}\n", name);
+			let mut new_lfo: lfo::Lfo = lfo::Lfo::new(name);
+			for piece in pieces.iter().skip(2) {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    new_lfo.set(halves[0].as_str(), halves[1].as_str());
+			}
+			// Redefining an LFO only changes the notes that come after
+			lfos.retain(|e| e.name != name);
+			lfos.push(new_lfo);
+		    },
+		    "ENV" => {
+			let name: &str = pieces.get(1).map(|e| e.as_str()).unwrap_or("");
+			eprint!("Envelope {}\n", name);
+			let mut new_envelope: envelope::Envelope = envelope::Envelope::new(name);
+			for piece in pieces.iter().skip(2) {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    new_envelope.set(halves[0].as_str(), halves[1].as_str());
+			}
+			envelopes.retain(|e| e.name != name);
+			envelopes.push(new_envelope);
+		    },
+		    "AUTOMATE" | "AUTOMATION" => {
+			// AUTOMATE <track, bus or master> <volume, pan or cutoff> <points>
+			eprint!("Automation\n");
+			if pieces.len() < 4 {
+			    eprint!("Automation needs a track, a parameter and points\n");
+			} else {
+			    eprint!("\t{}\t{}\t{}\n", pieces[1], pieces[2], pieces[3]);
+			    let track: Option<&mut mixer::Track> = if pieces[1] == "master" { Option::Some(&mut master) } else { tracks.iter_mut().find(|t| t.name == pieces[1]) };
+			    match (track, pieces[2].parse::<mixer::Lane>()) {
+				(Option::Some(track), Ok(lane)) => {
+				    track.lanes.retain(|(l, _)| *l != lane);
+				    track.lanes.push((lane, envelope::Curve::parse(pieces[3].as_str(), false).unwrap()));
+				},
+				(Option::None, _) => { eprint!("No track called {}\n", pieces[1]); },
+				(_, Err(_)) => { eprint!("Unrecognised option: {}\n", pieces[2]); }
+			    }
+			}
+		    },
+		    "TRACK" | "BUS" => {
+			let bus: bool = pieces[0] == "BUS";
+			let name: &str = pieces.get(1).map(|e| e.as_str()).unwrap_or("");
+			eprint!("{} {}\n", if bus { "Bus" } else { "Track" }, name);
+			current_track = match tracks.iter().position(|t| t.name == name) {
+			    Option::Some(index) => { index },
+			    Option::None => {
+				tracks.push(mixer::Track::new(name, bus));
+				tracks.len() - 1
+			    }
+			};
+			if tracks[current_track].bus != bus {
+			    eprint!("{} is already used as a {}\n", name, if bus { "track" } else { "bus" });
+			}
+			for piece in pieces.iter().skip(2) {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    tracks[current_track].set(halves[0].as_str(), halves[1].as_str());
+			}
+		    },
+		    "END_TRACK" | "END_BUS" => {
+			eprint!("Back to the main track\n");
+			current_track = 0;
+		    },
+		    "INCLUDE" | "IMPORT" => {
+			// INCLUDE reads a file as if it were written here, IMPORT only takes its definitions
+			let import: bool = pieces[0] == "IMPORT";
+			let name: &str = pieces.get(1).map(|e| e.as_str()).unwrap_or("");
+			let path: std::path::PathBuf = sources.last().unwrap().path.parent().map_or(std::path::PathBuf::from(name), |e| e.join(name));
+			let path: std::path::PathBuf = std::fs::canonicalize(&path).unwrap_or(path);
+			eprint!("{} {}\n", if import { "Import" } else { "Include" }, path.display());
+			let mut offset: f64 = 0.0;
+			for piece in pieces.iter().skip(2) {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    match halves[0].as_str() {
+				"at" | "time" => { offset = meter.parse_position(halves[1].as_str()).unwrap().0; },
+				huh => { eprint!("Unrecognised option: {}\n", huh); }
+			    }
+			}
+			if sources.iter().any(|e| e.path == path) {
+			    eprint!("{} is already being read, so it can't be included in itself\n", path.display());
+			} else if import && imported.contains(&path) {
+			    eprint!("Already imported\n");
+			} else {
+			    match File::open(&path) {
+				Result::Ok(file) => {
+				    if import { imported.push(path.clone()); }
+				    let saved: Saved = Saved{default: default.clone(), current_track: current_track, cursor: cursor, chord_length: chord_length, default_block: default_block, current_mode: current_mode, arpeggio: arpeggio};
+				    sources.push(Source{lines: Box::new(std::io::BufReader::new(file).lines()), path: path, offset: offset, first_note: notes.len(), library: import, called: Option::None, saved: Option::Some(saved)});
+				},
+				Result::Err(err) => { eprint!("Error while opening file: {}\n", err); }
+			    }
+			}
+		    },
+		    "LET" => {
+			eprint!("Variables\n");
+			for piece in &pieces[1..] {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    variables.retain(|(e, _)| *e != halves[0]);
+			    variables.push((halves[0].clone(), halves[1].clone()));
+			}
+		    },
+		    "MACRO" => {
+			// MACRO <name> <parameters, each with an optional default like root=C4>
+			let name: &str = pieces.get(1).map(|e| e.as_str()).unwrap_or("");
+			eprint!("Macro {}\n", name);
+			let parameters: Vec<(String, Option<String>)> = pieces.iter().skip(2).map(|e| match e.split_once('=') {
+			    Option::Some((parameter, value)) => { (parameter.to_string(), Option::Some(value.to_string())) },
+			    Option::None => { (e.clone(), Option::None) }
+			}).collect();
+			recording = Option::Some(Macro{name: name.to_string(), parameters: parameters, lines: Vec::<String>::new()});
+		    },
+		    "CALL" => {
+			let name: &str = pieces.get(1).map(|e| e.as_str()).unwrap_or("");
+			eprint!("Call {}\n", name);
+			let arguments: Vec<(String, String)> = pieces.iter().skip(2).filter_map(|e| e.split_once('=')).map(|(key, value)| (key.to_string(), value.to_string())).collect();
+			for (key, value) in &arguments {
+			    eprint!("\t{}\t{}\n", key, value);
+			}
+			let offset: f64 = arguments.iter().find(|(key, _)| key == "at").map_or(0.0, |(_, value)| meter.parse_position(value).unwrap().0);
+			match macros.iter().find(|e| e.name == name) {
+			    Option::Some(_) if sources.iter().any(|e| e.called.as_deref() == Option::Some(name)) => {
+				eprint!("{} is already being called, so it can't call itself\n", name);
+			    },
+			    Option::Some(called) => {
+				let mut values: Vec<(String, String)> = Vec::<(String, String)>::new();
+				for (parameter, default) in &called.parameters {
+				    match arguments.iter().find(|(key, _)| key == parameter).map(|(_, value)| value).or(default.as_ref()) {
+					Option::Some(value) => { values.push((parameter.clone(), value.clone())); },
+					Option::None => { eprint!("No value for {}\n", parameter); }
+				    }
+				}
+				for (key, _) in &arguments {
+				    if key != "at" && !called.parameters.iter().any(|(e, _)| e == key) {
+					eprint!("Unrecognised option: {}\n", key);
+				    }
+				}
+				let lines: Vec<std::io::Result<String>> = called.lines.iter().map(|e| Result::Ok(expander.substitute(e, &values))).collect();
+				let path: std::path::PathBuf = sources.last().unwrap().path.clone();
+				sources.push(Source{lines: Box::new(lines.into_iter()), path: path, offset: offset, first_note: notes.len(), library: library, called: Option::Some(name.to_string()), saved: Option::None});
+			    },
+			    Option::None => { eprint!("No macro called {}\n", name); }
+			}
+		    },
+		    "SCRIPT" => {
+			eprint!("Script\n");
+			let mut script: script::Script = script::Script::new();
+			for piece in &pieces[1..] {
+			    let halves: Vec<String> = sep1.split(piece.as_str()).into_iter().map(|e| e.to_string()).collect();
+			    eprint!("\t{}\t{}\n", halves[0], halves[1]);
+			    match halves[0].as_str() {
+				"seed" => { script.seed = halves[1].parse().unwrap(); },
+				"at" | "time" => { script.offset = meter.parse_position(halves[1].as_str()).unwrap().0; },
+				huh => { eprint!("Unrecognised option: {}\n", huh); }
+			    }
+			}
+			scripting = Option::Some(script);
+		    },
 		    "" => {}
 		        // Circumvent the log from below--Empty lines are fine.
 		    ,
@@ -542,25 +1459,346 @@ fn print_wave( file: File ) -> () {
 	}
     }
 
+    for (range, flam, from_start) in grace_notes {
+	let early: f64 = flam.beats(meta_data.tempo).min(from_start);
+	for note in &mut notes[range] {
+	    note.time -= early;
+	}
+    }
+    if let Option::Some(length) = length {
+	meta_data.length = meter.parse_length(length.as_str()).unwrap();
+    }
+    notes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+    Song{meta_data: meta_data, notes: notes, tracks: tracks, master: master, stems: stems, meter: meter, instruments: instruments, samples: Vec::<sample::Sample>::new()}
+}
+
+/**
+Turns a score read from another notation into a song, playing every part with the
+DEFAULT note options from the command line. Each part gets its own stem.
+*/
+fn song_from_score (score: &score::Score, options: &Options) -> Song {
+    let default: Note = default_note(options);
+    if !score.title.is_empty() {
+	eprint!("Title: {}\n", score.title);
+    }
+
+    let mut notes: Vec<Note> = Vec::<Note>::new();
+    let mut stems: Vec<String> = Vec::<String>::new();
+    for part in &score.parts {
+	let stem: usize = stem_index(&mut stems, part.name.clone());
+	for score_note in &part.notes {
+	    let mut note: Note = default.clone();
+	    note.time = score_note.time;
+	    note.duration = score_note.duration;
+	    note.frequency = score_note.frequency;
+	    note.volume *= score_note.volume;
+	    note.stem = stem;
+	    note.instrument = Option::Some(part.name.clone());
+	    notes.push(note);
+	}
+    }
+    imported_song(notes, stems, Vec::<sample::Sample>::new(), score.tempo, score.meter.clone(), &default)
+}
+
+/**
+Turns what a tracker module played into a song, with a stem for every channel.
+The notes play the module's samples, with the rest of the DEFAULT note options
+from the command line.
+*/
+fn song_from_module (module: tracker::Playback, options: &Options) -> Song {
+    let default: Note = default_note(options);
+    if !module.title.is_empty() {
+	eprint!("Title: {}\n", module.title);
+    }
+    let beats = |seconds: f64| -> f64 { seconds * module.tempo / 60.0 };
+    let curve = |points: &[(f64, f64)], scale: f64| -> envelope::Curve {
+	let mut curve: envelope::Curve = envelope::Curve::constant(points[0].1 * scale);
+	for (time, value) in &points[1..] {
+	    curve.push(beats(*time), value * scale, envelope::Segment::Hold);
+	}
+	curve
+    };
+
+    let mut notes: Vec<Note> = Vec::<Note>::new();
+    let mut stems: Vec<String> = Vec::<String>::new();
+    let mut samples: Vec<std::rc::Rc<sample::Sample>> = Vec::<std::rc::Rc<sample::Sample>>::new(); // Each once, however many notes play it
+    for channel in 0..module.channels {
+	stem_index(&mut stems, format!("channel_{}", channel + 1));
+    }
+    for played in &module.notes {
+	let mut note: Note = default.clone();
+	let index: usize = match samples.iter().position(|e| std::rc::Rc::ptr_eq(e, &played.sample)) {
+	    Option::Some(index) => { index },
+	    Option::None => {
+		samples.push(played.sample.clone());
+		samples.len() - 1
+	    }
+	};
+	note.wave_form = WaveForm::Sample(index);
+	note.retrigger = true;
+	note.time = beats(played.time);
+	note.duration = beats(played.duration);
+	note.frequency = played.pitch[0].1;
+	note.curves.retain(|(t, _)| !matches!(t, lfo::Target::Pitch | lfo::Target::Volume | lfo::Target::Pan));
+	note.curves.push((lfo::Target::Pitch, curve(&played.pitch, 1.0)));
+	note.curves.push((lfo::Target::Volume, curve(&played.volume, default.volume)));
+	note.curves.push((lfo::Target::Pan, curve(&played.pan, 1.0)));
+	note.stem = played.channel;
+	note.instrument = Option::Some(stems[played.channel].clone());
+	notes.push(note);
+    }
+    let samples: Vec<sample::Sample> = samples.iter().map(|e| sample::Sample::clone(e)).collect();
+    imported_song(notes, stems, samples, module.tempo, meter::Meter::new(), &default)
+}
+
+/// The note every note of an imported song starts from, with the DEFAULT note options from the command line.
+fn default_note (options: &Options) -> Note {
+    let mut default: Note = Note::new();
+    for piece in &options.default {
+	match piece.split_once('=') {
+	    Option::Some((key, value)) => {
+		if !default.set(key, value, &[], &[]) {
+		    eprint!("Unrecognised option: {}\n", key);
+		}
+	    },
+	    Option::None => { eprint!("Unrecognised option: {}\n", piece); }
+	}
+    }
+    default
+}
+
+/// Makes a song of imported notes, long enough to play them all out to the end of a bar.
+fn imported_song (mut notes: Vec<Note>, stems: Vec<String>, samples: Vec<sample::Sample>, tempo: f64, meter: meter::Meter, default: &Note) -> Song {
+    let mut meta_data: MetaData = MetaData::new();
+    meta_data.tempo = tempo;
+    // Up to the end of the last bar, with a bar more for the release if it's needed
+    let end: f64 = notes.iter().fold(0.0, |a, e| a.max(e.time + e.duration));
+    let mut bar: u32 = 1;
+    while meter.bar_start(bar) < end - 1e-9 { bar += 1; }
+    let release: f64 = default.volume_envelope.release / 1000.0 * meta_data.tempo / 60.0; // In beats
+    if meter.bar_start(bar) < end + release { bar += 1; }
+    meta_data.length = meter.bar_start(bar);
+
     notes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+    Song{meta_data: meta_data, notes: notes, tracks: vec![mixer::Track::new("main", false)], master: mixer::Track::new("master", false), stems: stems, meter: meter, instruments: Vec::<(String, Note)>::new(), samples: samples}
+}
+
+/// Groups the notes of a song by stem for writing out as notation. Drum notes have no pitch, so they're left out.
+fn score_from_song (song: &Song, title: &str) -> score::Score {
+    let mut parts: Vec<score::Part> = song.stems.iter().map(|e| score::Part{name: e.clone(), notes: Vec::<score::ScoreNote>::new()}).collect();
+    for note in &song.notes {
+	if matches!(note.wave_form, WaveForm::Drum(_)) { continue; }
+	parts[note.stem].notes.push(score::ScoreNote{time: note.time, duration: note.duration, frequency: note.frequency, volume: note.volume});
+    }
+    parts.retain(|e| !e.notes.is_empty());
+    score::Score{title: title.to_string(), tempo: song.meta_data.tempo, meter: song.meter.clone(), parts: parts}
+}
+
+/**
+Plays the mono notes of each instrument on each track as one voice, however
+many DEFAULT blocks they're spread over. Every note glides from the pitch
+the voice was last at, and a note that starts before the last one has ended is
+played legato: instead of starting again, the last note is stretched to the end
+of the new one and its pitch bent to the new one's.
+@param notes Sorted by time
+*/
+fn join_mono_notes (notes: Vec<Note>, meta_data: &MetaData) -> Vec<Note> {
+    let mut joined: Vec<Note> = Vec::<Note>::with_capacity(notes.len());
+    let mut voices: Vec<(usize, Option<String>, usize)> = Vec::<(usize, Option<String>, usize)>::new(); // The track and instrument, and the index of their last mono note
+    for mut note in notes {
+	if !note.mono {
+	    joined.push(note);
+	    continue;
+	}
+	match voices.iter().position(|(track, instrument, _)| *track == note.track && *instrument == note.instrument) {
+	    Option::Some(voice) => {
+		let last: &mut Note = &mut joined[voices[voice].2];
+		let start: f64 = note.time - last.time;
+		let from: f64 = last.pitch_at(start);
+		let glide: f64 = note.portamento_beats(from, meta_data.tempo);
+		if note.time < last.time + last.duration {
+		    last.duration = start + note.duration;
+		    last.bend_pitch(start, from, note.frequency, glide);
+		    continue;
+		}
+		if glide > 0.0 {
+		    note.bend_pitch(0.0, from, note.frequency, glide);
+		}
+		voices[voice].2 = joined.len();
+	    },
+	    Option::None => { voices.push((note.track, note.instrument.clone(), joined.len())); }
+	}
+	joined.push(note);
+    }
+    joined
+}
+
+/**
+@param song The song to render
+@param options Command line options
+@param stem Only play the notes of this stem, or all of them if none
+*/
+fn render (song: &Song, options: &Options, stem: Option<usize>) -> Vec<[f64; 2]> {
+    let meta_data: MetaData = song.meta_data;
+    let mut frames: Vec<[f64; 2]> = Vec::<[f64; 2]>::new();
+    let mut mixer: mixer::Mixer = mixer::Mixer::new(&song.tracks, &song.master, &meta_data, &options.solo);
+    let mut voices: Vec<Voice> = song.notes.iter().map(|note| Voice::new(note, &meta_data)).collect();
     
     for sample_index in 0..(meta_data.length*(SAMPLES_PER_SECOND as f64)*(60.0/meta_data.tempo)) as u128 {
 	let current_time_seconds: f64 = (sample_index as f64) / (SAMPLES_PER_SECOND as f64);
 	let current_time_beats: f64 = current_time_seconds * (meta_data.tempo / 60.0);
-	let mut audio_accumulator: f64 = 0.0;
-	for note in &notes {
+	let mut track_accumulators: Vec<[f64; 2]> = vec![[0.0; 2]; song.tracks.len()];
+	for (index, note) in song.notes.iter().enumerate() {
 	    if current_time_beats < note.time { break; }
-	    if current_time_beats > note.time + note.duration + ( note.release * meta_data.tempo / 60000.0 ) { continue; }
-	    audio_accumulator += note.clone().audio_at(current_time_seconds , meta_data);
+	    if current_time_beats > note.time + note.sounding_beats(meta_data.tempo) { continue; }
+	    if stem.is_some_and(|s| s != note.stem) { continue; }
+	    let a: [f64; 2] = note.audio_at(current_time_seconds, &meta_data, &song.samples, &mut voices[index]);
+	    track_accumulators[note.track][0] += a[0];
+	    track_accumulators[note.track][1] += a[1];
 	}
-	data_buffer.extend_from_slice(&sample_data(audio_accumulator));
+	frames.push(mixer.process(&track_accumulators, current_time_beats));
     }
+    frames
+}
 
-    print_bytes(&[82, 73, 70, 70]);
-    print_bytes(&(data_buffer.len() as u32 + 68).to_le_bytes());
-    print_bytes(&[87, 65, 86, 69, 102, 109, 116, 32, 16, 0, 0, 0, 1, 0, CHANNEL_COUNT, 0, 128, 62, 0, 0, 0, 125, 0, 0, 2, 0, 16, 0, 76, 73, 83, 84, 26, 0, 0, 0, 73, 78, 70, 79, 73, 83, 70, 84, 14, 0, 0, 0, 76, 97, 118, 102, 54, 48, 46, 49, 54, 46, 49, 48, 48, 0, 100, 97, 116, 97]);
-    print_bytes(&(data_buffer.len() as u32).to_le_bytes());
-    print_bytes(&data_buffer);
+/// Encodes rendered frames as a complete .wav file. The mix is centred on zero and full scale is 1.0.
+fn wave_file (frames: &[[f64; 2]], meta_data: &MetaData) -> Vec<u8> {
+    let mut data_buffer: Vec<u8> = Vec::<u8>::new();
+    let mut quantizer: dither::Quantizer = dither::Quantizer::new(meta_data.bits, meta_data.dither);
+    for frame in frames {
+	for (channel, a) in frame.iter().enumerate() {
+	    data_buffer.extend_from_slice(&quantizer.sample_data(*a, channel));
+	}
+    }
+
+    let block_align: u16 = CHANNEL_COUNT as u16 * meta_data.bits / 8;
+    let mut bytes: Vec<u8> = Vec::<u8>::new();
+    bytes.extend_from_slice(&[82, 73, 70, 70]);
+    bytes.extend_from_slice(&(data_buffer.len() as u32 + 68).to_le_bytes());
+    bytes.extend_from_slice(&[87, 65, 86, 69, 102, 109, 116, 32, 16, 0, 0, 0, 1, 0, CHANNEL_COUNT, 0]);
+    bytes.extend_from_slice(&(SAMPLES_PER_SECOND as u32).to_le_bytes());
+    bytes.extend_from_slice(&(SAMPLES_PER_SECOND as u32 * block_align as u32).to_le_bytes());
+    bytes.extend_from_slice(&block_align.to_le_bytes());
+    bytes.extend_from_slice(&meta_data.bits.to_le_bytes());
+    bytes.extend_from_slice(&[76, 73, 83, 84, 26, 0, 0, 0, 73, 78, 70, 79, 73, 83, 70, 84, 14, 0, 0, 0, 76, 97, 118, 102, 54, 48, 46, 49, 54, 46, 49, 48, 48, 0, 100, 97, 116, 97]);
+    bytes.extend_from_slice(&(data_buffer.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&data_buffer);
+    bytes
+}
+
+/**
+A file name for a stem that stays in the stems directory, with anything but
+letters, digits, - and _ swapped for _, and a number on the end if it's taken.
+@param taken The names used so far, in lower case for file systems that ignore it
+*/
+fn stem_file_name (name: &str, taken: &mut Vec<String>) -> String {
+    let base: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    let base: String = if base.is_empty() { "stem".to_string() } else { base };
+    let mut file_name: String = base.clone();
+    let mut number: usize = 2;
+    while taken.contains(&file_name.to_lowercase()) {
+	file_name = format!("{}_{}", base, number);
+	number += 1;
+    }
+    taken.push(file_name.to_lowercase());
+    file_name
+}
+
+fn print_wave( song: Song, options: &Options ) -> () {
+    match &options.stems {
+	Option::None => {
+	    let mut frames: Vec<[f64; 2]> = render(&song, options, Option::None);
+	    finish_mix(&mut frames, &song.meta_data, options);
+	    print_bytes(&wave_file(&frames, &song.meta_data));
+	},
+	Option::Some(directory) => {
+	    // Every stem is rendered over the whole song, so they all line up with the mix
+	    let directory: &std::path::Path = std::path::Path::new(directory);
+	    if let Err(err) = std::fs::create_dir_all(directory) {
+		eprint!("Error while creating {}: {}\n", directory.display(), err);
+		return;
+	    }
+	    let mut outputs: Vec<(String, Option<usize>)> = song.stems.iter().enumerate().map(|(i, name)| (name.clone(), Option::Some(i))).collect();
+	    outputs.push(("mix".to_string(), Option::None));
+	    let mut taken: Vec<String> = vec!["mix".to_string()];
+	    for (name, stem) in outputs.iter_mut() {
+		if stem.is_some() { *name = stem_file_name(name, &mut taken); }
+	    }
+	    for (name, stem) in outputs {
+		let path: std::path::PathBuf = directory.join(format!("{}.wav", name));
+		eprint!("Rendering {}\n", path.display());
+		let mut frames: Vec<[f64; 2]> = render(&song, options, stem);
+		if stem.is_none() {
+		    // Stems are left unprocessed so they can still be mixed
+		    finish_mix(&mut frames, &song.meta_data, options);
+		}
+		if let Err(err) = std::fs::write(&path, wave_file(&frames, &song.meta_data)) {
+		    eprint!("Error while writing {}: {}\n", path.display(), err);
+		}
+	    }
+	}
+    }
+}
+
+/// Reads a song as it was written, or a score to play with the DEFAULT note options from the command line, by the file's extension.
+fn read_song (path: &str, options: &Options) -> Option<Song> {
+    let lower: String = path.to_lowercase();
+    let score: Result<score::Score, ParseError> = if lower.ends_with(".abc") {
+	match std::fs::read_to_string(path) {
+	    Result::Ok(text) => { abc::parse(&text, options.tune) },
+	    Result::Err(err) => { eprint!("Error while opening file: {}", err); return Option::None; }
+	}
+    } else if lower.ends_with(".musicxml") || lower.ends_with(".xml") {
+	match std::fs::read_to_string(path) {
+	    Result::Ok(text) => { musicxml::parse(&text) },
+	    Result::Err(err) => { eprint!("Error while opening file: {}", err); return Option::None; }
+	}
+    } else if lower.ends_with(".mod") || lower.ends_with(".xm") {
+	return match std::fs::read(path).map(|e| tracker::parse(&e)) {
+	    Result::Ok(Ok(module)) => { Option::Some(song_from_module(module, options)) },
+	    Result::Ok(Err(_)) => { eprint!("Nothing to play in {}\n", path); Option::None },
+	    Result::Err(err) => { eprint!("Error while opening file: {}", err); Option::None }
+	};
+    } else if lower.ends_with(".json") || lower.ends_with(".toml") {
+	return match std::fs::read_to_string(path) {
+	    Result::Ok(text) => { convert::read(&text, convert::Format::of(path)).ok() },
+	    Result::Err(err) => { eprint!("Error while opening file: {}", err); Option::None }
+	};
+    } else if lower.ends_with(".mxl") {
+	match std::fs::read(path) {
+	    Result::Ok(bytes) => { musicxml::unzip(&bytes).and_then(|e| musicxml::parse(&e)) },
+	    Result::Err(err) => { eprint!("Error while opening file: {}", err); return Option::None; }
+	}
+    } else {
+	return match File::open(path) {
+	    Result::Ok(file) => { Option::Some(parse_song(file, std::path::Path::new(path))) },
+	    Result::Err(err) => { eprint!("Error while opening file: {}", err); Option::None }
+	};
+    };
+    match score {
+	Ok(score) => { Option::Some(song_from_score(&score, options)) },
+	Err(_) => { eprint!("Nothing to play in {}\n", path); Option::None }
+    }
+}
+
+/// Reads a song and gets it ready to play.
+fn load_song (path: &str, options: &Options) -> Option<Song> {
+    let mut song: Song = read_song(path, options)?;
+    for (index, track) in song.tracks.iter().enumerate().filter(|(_, t)| t.bus) {
+	let count: usize = song.notes.iter().filter(|e| e.track == index).count();
+	if count > 0 {
+	    eprint!("{} notes are on the bus {}, which only plays what other tracks send to it, so they're silent\n", count, track.name);
+	}
+    }
+    for name in &options.solo {
+	if !song.tracks.iter().any(|e| e.name == *name) {
+	    eprint!("No track called {} to solo\n", name);
+	}
+    }
+    song.notes = join_mono_notes(song.notes, &song.meta_data);
+    Option::Some(song)
 }
 
 fn print_bytes (bytes: &[u8]) -> () {
@@ -580,12 +1818,97 @@ fn print_bytes (bytes: &[u8]) -> () {
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() == 1 {
-	println!( "Please provide a file to make a .wav file from." );
+    let mut options: Options = Options::new();
+    let mut path: Option<String> = Option::None;
+    let mut converting: bool = false;
+    let mut output: Option<String> = Option::None; // The file to convert to
+    let mut i: usize = 1;
+    while i < args.len() {
+	match args[i].as_str() {
+	    "render" | "export" if i == 1 => {},
+	    "convert" if i == 1 => { converting = true; },
+	    "--musicxml" | "--mxl" | "--lilypond" => { options.export = Option::Some(args[i][2..].to_string()); },
+	    "--stems" => {
+		i += 1;
+		match args.get(i) {
+		    Option::Some(directory) => { options.stems = Option::Some(directory.clone()); },
+		    Option::None => { eprint!("--stems needs a directory\n"); }
+		}
+	    },
+	    "--normalize" | "--peak" => {
+		let (unit, target): (&str, &mut Option<f64>) = if args[i] == "--peak" { ("dBTP", &mut options.peak) } else { ("LUFS", &mut options.normalize) };
+		i += 1;
+		match args.get(i).map(|e| parse_level(e, unit)) {
+		    Option::Some(Ok(level)) => { *target = Option::Some(level); },
+		    _ => { eprint!("{} needs a level in {}\n", args[i - 1], unit); }
+		}
+	    },
+	    "--default" => {
+		i += 1;
+		match args.get(i) {
+		    Option::Some(pieces) => { options.default.extend(pieces.split_whitespace().map(|e| e.to_string())); },
+		    Option::None => { eprint!("--default needs note options, like \"wave=tri r=200\"\n"); }
+		}
+	    },
+	    "--tune" => {
+		i += 1;
+		match args.get(i).map(|e| e.parse::<u32>()) {
+		    Option::Some(Ok(number)) => { options.tune = Option::Some(number); },
+		    _ => { eprint!("--tune needs the X: number of a tune\n"); }
+		}
+	    },
+	    "--solo" => {
+		i += 1;
+		match args.get(i) {
+		    Option::Some(names) => { options.solo.extend(names.split(',').map(|e| e.to_string())); },
+		    Option::None => { eprint!("--solo needs a track name\n"); }
+		}
+	    },
+	    argument if converting && path.is_some() => { output = Option::Some(argument.to_string()); },
+	    argument => { path = Option::Some(argument.to_string()); }
+	}
+	i += 1;
+    }
+    let path: String = match path {
+	Option::Some(path) => { path },
+	Option::None => {
+	    println!( "Please provide a file to make a .wav file from." );
+	    return;
+	}
+    };
+    if converting {
+	let output: String = match output {
+	    Option::Some(output) => { output },
+	    Option::None => {
+		eprint!("convert needs a file to write to, like song.json, song.toml or song.txt\n");
+		return;
+	    }
+	};
+	let song: Song = match read_song(&path, &options) {
+	    Option::Some(song) => { song },
+	    Option::None => { return; }
+	};
+	if let Ok(text) = convert::write(&song, convert::Format::of(&output)) {
+	    if let Err(err) = std::fs::write(&output, text) {
+		eprint!("Error while writing {}: {}\n", output, err);
+	    }
+	}
 	return;
     }
-    match File::open(args[1].clone()) {
-	Result::Ok(file) => { print_wave( file ); },
-	Result::Err(err) => { eprint!("Error while opening file: {}", err); }
+    let song: Song = match load_song(&path, &options) {
+	Option::Some(song) => { song },
+	Option::None => { return; }
+    };
+    match options.export.as_deref() {
+	Option::None => { print_wave( song, &options ); },
+	Option::Some(format) => {
+	    let title: String = std::path::Path::new(&path).file_stem().map_or(String::new(), |e| e.to_string_lossy().into_owned());
+	    let score: score::Score = score_from_song(&song, &title);
+	    match format {
+		"mxl" => { print_bytes(&musicxml::write_mxl(&score)); },
+		"lilypond" => { print_bytes(lilypond::write(&score).as_bytes()); },
+		_ => { print_bytes(musicxml::write(&score).as_bytes()); }
+	    }
+	}
     }
 }
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ParseError;
+
+/**
+The time signatures of a song, for positions written as `bar:beat`. Bars and
+beats count from 1 as in sheet music, and beats are in the time signature's own
+unit, so beat 4 of a 6/8 bar is the fourth eighth note. Everywhere else a beat
+is a quarter note.
+*/
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Meter {
+    changes: Vec<(u32, f64, u32, u32)>, // The first bar, its start in beats, and the time signature from then on
+}
+
+impl Meter {
+    pub fn new () -> Self {
+	Self{changes: vec![(1, 0.0, 4, 4)]}
+    }
+    /// Changes the time signature from the start of some bar up to the next change, which still starts on its own bar.
+    pub fn set (&mut self, bar: u32, numerator: u32, denominator: u32) -> () {
+	let bar: u32 = bar.max(1);
+	self.changes.retain(|e| e.0 != bar);
+	let index: usize = self.changes.iter().position(|e| e.0 > bar).unwrap_or(self.changes.len());
+	self.changes.insert(index, (bar, 0.0, numerator, denominator));
+	// The bars before each change may have changed length
+	for i in 1..self.changes.len() {
+	    let (first, start, n, d): (u32, f64, u32, u32) = self.changes[i - 1];
+	    self.changes[i].1 = start + (self.changes[i].0 - first) as f64 * n as f64 * 4.0 / d as f64;
+	}
+    }
+    /// Parses a time signature like `6/8`.
+    pub fn parse_signature (s: &str) -> Result<(u32, u32), ParseError> {
+	match s.split_once('/') {
+	    Option::Some((numerator, denominator)) => {
+		match (numerator.parse::<u32>(), denominator.parse::<u32>()) {
+		    (Ok(n), Ok(d)) if n > 0 && d > 0 => { Ok((n, d)) },
+		    _ => { Err(ParseError) }
+		}
+	    },
+	    Option::None => { Err(ParseError) }
+	}
+    }
+    /// The change in force during a bar.
+    fn change_at (&self, bar: u32) -> &(u32, f64, u32, u32) {
+	self.changes.iter().rev().find(|e| e.0 <= bar).unwrap_or(&self.changes[0])
+    }
+    /// Length of a bar in beats.
+    pub fn bar_length (&self, bar: u32) -> f64 {
+	let (_, _, numerator, denominator): (u32, f64, u32, u32) = *self.change_at(bar);
+	numerator as f64 * 4.0 / denominator as f64
+    }
+    /// Where a bar starts, in beats.
+    pub fn bar_start (&self, bar: u32) -> f64 {
+	let (first, start, _, _): (u32, f64, u32, u32) = *self.change_at(bar);
+	start + (bar.max(1) - first) as f64 * self.bar_length(bar)
+    }
+    /// The first bar of every time signature, and the signature.
+    pub fn changes (&self) -> Vec<(u32, (u32, u32))> {
+	self.changes.iter().map(|e| (e.0, (e.2, e.3))).collect()
+    }
+    /// The time signature in force during a bar.
+    pub fn signature (&self, bar: u32) -> (u32, u32) {
+	let (_, _, numerator, denominator): (u32, f64, u32, u32) = *self.change_at(bar);
+	(numerator, denominator)
+    }
+    /**
+    Parses a position, either `bar:beat` or plain beats.
+    @return The position in beats, and whether it was given as `bar:beat` (and so isn't relative to anything)
+    */
+    pub fn parse_position (&self, s: &str) -> Result<(f64, bool), ParseError> {
+	match s.split_once(':') {
+	    Option::Some((bar, beat)) => {
+		let bar: u32 = bar.parse().map_err(|_| ParseError)?;
+		let beat: f64 = if beat.is_empty() { 1.0 } else { beat.parse().map_err(|_| ParseError)? };
+		let (_, denominator): (u32, u32) = self.signature(bar);
+		Ok((self.bar_start(bar) + (beat - 1.0) * 4.0 / denominator as f64, true))
+	    },
+	    Option::None => { s.parse().map(|e| (e, false)).map_err(|_| ParseError) }
+	}
+    }
+    /**
+    Parses a length, in beats or bars, like `32`, `8bars` or `8:1` (up to the start of bar 8).
+    @return The length in beats
+    */
+    pub fn parse_length (&self, s: &str) -> Result<f64, ParseError> {
+	match s.strip_suffix("bars").or_else(|| s.strip_suffix("bar")) {
+	    Option::Some(bars) => {
+		let bars: f64 = bars.parse().map_err(|_| ParseError)?;
+		let whole: u32 = bars.floor() as u32;
+		Ok(self.bar_start(whole + 1) + bars.fract() * self.bar_length(whole + 1))
+	    },
+	    Option::None => { self.parse_position(s).map(|e| e.0) }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_in_any_order () {
+	let mut meter: Meter = Meter::new();
+	meter.set(5, 6, 8);
+	meter.set(3, 3, 4);
+	assert_eq!(meter.changes(), vec![(1, (4, 4)), (3, (3, 4)), (5, (6, 8))]);
+	// Two bars of 4/4, two of 3/4 and then 6/8
+	assert_eq!(meter.bar_start(3), 8.0);
+	assert_eq!(meter.bar_start(5), 14.0);
+	assert_eq!(meter.bar_start(6), 17.0);
+	meter.set(1, 2, 4);
+	assert_eq!(meter.bar_start(5), 10.0);
+	assert_eq!(meter.parse_position("5:4").unwrap(), (11.5, true));
+    }
+}
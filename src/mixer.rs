@@ -0,0 +1,249 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{MetaData, ParseError, envelope, fx, parse_bool};
+
+/*
+Every note belongs to a track. Track 0 is the implicit track that notes outside
+of any `TRACK` section go to. Buses are tracks that hold no notes of their own
+and are only fed by the sends of other tracks. The master is a track as well,
+which everything ends up in.
+*/
+
+/// What a song level automation lane controls on a track.
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Lane {
+    Volume,
+    Pan,
+    Cutoff, // Of a lowpass filter after the inserts, which is only there if automated
+}
+
+impl FromStr for Lane {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	match s {
+	    "volume" | "vol" | "gain" => { Ok(Lane::Volume) },
+	    "pan" => { Ok(Lane::Pan) },
+	    "cutoff" | "filter" => { Ok(Lane::Cutoff) },
+	    _ => { Err(ParseError) }
+	}
+    }
+}
+
+impl std::fmt::Display for Lane {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let name: &str = match self {
+	    Lane::Volume => { "volume" },
+	    Lane::Pan => { "pan" },
+	    Lane::Cutoff => { "cutoff" }
+	};
+	write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Track {
+    pub name: String,
+    pub bus: bool,
+    volume: f64, // Scalar
+    pan: f64, // From -1 (left) to 1 (right)
+    mute: bool,
+    solo: bool,
+    pub fx: Vec<fx::EffectSpec>, // Inserts
+    sends: Vec<(String, f64)>, // Bus name and send level, post fader
+    pub lanes: Vec<(Lane, envelope::Curve)>, // Over the song, in beats
+}
+
+impl Track {
+    pub fn new (name: &str, bus: bool) -> Self {
+	Self{name: name.to_string(), bus: bus, volume: 1.0, pan: 0.0, mute: false, solo: false, fx: Vec::<fx::EffectSpec>::new(), sends: Vec::<(String, f64)>::new(), lanes: Vec::<(Lane, envelope::Curve)>::new()}
+    }
+    pub fn set (&mut self, key: &str, value: &str) -> () {
+	match key {
+	    "volume" | "vol" | "gain" => { self.volume = value.parse().unwrap(); },
+	    "pan" => { self.pan = value.parse().unwrap(); },
+	    "mute" => { self.mute = parse_bool(value).unwrap(); },
+	    "solo" => { self.solo = parse_bool(value).unwrap(); },
+	    send if send.starts_with("send_") => {
+		let bus: String = send["send_".len()..].to_string();
+		let level: f64 = value.parse().unwrap();
+		match self.sends.iter_mut().find(|(name, _)| *name == bus) {
+		    Option::Some(existing) => { existing.1 = level; },
+		    Option::None => { self.sends.push((bus, level)); }
+		}
+	    },
+	    huh => { eprint!("Unrecognised option: {}\n", huh); }
+	}
+    }
+    /// The options `set` takes to make this track, as keys and values.
+    pub fn options (&self) -> Vec<(String, String)> {
+	let mut options: Vec<(String, String)> = vec![
+	    ("volume".to_string(), self.volume.to_string()),
+	    ("pan".to_string(), self.pan.to_string()),
+	    ("mute".to_string(), self.mute.to_string()),
+	    ("solo".to_string(), self.solo.to_string())
+	];
+	options.extend(self.sends.iter().map(|(bus, level)| (format!("send_{}", bus), level.to_string())));
+	options
+    }
+}
+
+/// Balance pan law, so a centred track keeps its level in both channels.
+pub fn pan_gains (pan: f64) -> [f64; 2] {
+    let pan: f64 = pan.clamp(-1.0, 1.0);
+    [(1.0 - pan).min(1.0), (1.0 + pan).min(1.0)]
+}
+
+struct Strip {
+    audible: bool,
+    volume: f64,
+    pan: f64,
+    chains: [Vec<fx::Effect>; 2],
+    lanes: Vec<(Lane, envelope::Curve)>,
+    filters: [fx::Lowpass; 2],
+    sends: Vec<(usize, f64)>,
+}
+
+impl Strip {
+    fn new (track: &Track, audible: bool, meta_data: &MetaData) -> Self {
+	let chains: [Vec<fx::Effect>; 2] = [track.fx.iter().map(|e| e.build(meta_data, 0)).collect(), track.fx.iter().map(|e| e.build(meta_data, 1)).collect()];
+	Self{audible: audible, volume: track.volume, pan: track.pan, chains: chains, lanes: track.lanes.clone(), filters: [fx::Lowpass::new(); 2], sends: Vec::<(usize, f64)>::new()}
+    }
+    /**
+    @param input Left and right
+    @param time In beats since the start of the song
+    */
+    fn process (&mut self, input: [f64; 2], time: f64) -> [f64; 2] {
+	if !self.audible { return [0.0; 2]; }
+	let mut volume: f64 = self.volume;
+	let mut pan: f64 = self.pan;
+	let mut cutoff: Option<f64> = Option::None;
+	for (lane, curve) in &self.lanes {
+	    let value: f64 = curve.value_at(time);
+	    match lane {
+		Lane::Volume => { volume = value; },
+		Lane::Pan => { pan = value; },
+		Lane::Cutoff => { cutoff = Option::Some(value); }
+	    }
+	}
+	let gains: [f64; 2] = pan_gains(pan);
+	let mut output: [f64; 2] = input;
+	for (channel, chain) in self.chains.iter_mut().enumerate() {
+	    for effect in chain {
+		output[channel] = effect.process(output[channel]);
+	    }
+	    if let Option::Some(cutoff) = cutoff {
+		output[channel] = self.filters[channel].process(output[channel], cutoff, 0.707);
+	    }
+	    output[channel] *= gains[channel] * volume;
+	}
+	output
+    }
+}
+
+pub struct Mixer {
+    strips: Vec<Strip>,
+    buses: Vec<usize>, // Indices of the strips that are buses, in order
+    master: Strip,
+}
+
+impl Mixer {
+    /**
+    Soloing a bus keeps the tracks that send to it playing, and a bus plays while
+    any track that sends to it does, so soloing a track keeps its reverb.
+    @param tracks All tracks and buses of the song, track 0 first
+    @param master The track everything is summed into
+    @param solo Names of tracks soloed from the command line, on top of the ones soloed in the song
+    */
+    pub fn new (tracks: &[Track], master: &Track, meta_data: &MetaData, solo: &[String]) -> Self {
+	let soloed = |t: &Track| -> bool { t.solo || solo.contains(&t.name) };
+	let soloing: bool = tracks.iter().any(soloed);
+	let sends_to = |t: &Track, bus: &Track| -> bool { t.sends.iter().any(|(name, _)| *name == bus.name) };
+	let track_audible: Vec<bool> = tracks.iter().map(|t| {
+	    !t.bus && !t.mute && (!soloing || soloed(t) || tracks.iter().any(|b| b.bus && soloed(b) && sends_to(t, b)))
+	}).collect();
+	let strips: Vec<Strip> = tracks.iter().enumerate().map(|(index, track)| {
+	    let audible: bool = if track.bus {
+		!track.mute && (!soloing || soloed(track) || tracks.iter().zip(&track_audible).any(|(t, a)| *a && sends_to(t, track)))
+	    } else {
+		track_audible[index]
+	    };
+	    let mut strip: Strip = Strip::new(track, audible, meta_data);
+	    for (bus, level) in &track.sends {
+		match tracks.iter().position(|t| t.bus && t.name == *bus) {
+		    Option::Some(index) if !track.bus => { strip.sends.push((index, *level)); },
+		    Option::Some(_) => { eprint!("Buses can't send to other buses: {}\n", track.name); },
+		    Option::None => { eprint!("No bus called {} to send to from {}\n", bus, track.name); }
+		}
+	    }
+	    strip
+	}).collect();
+	Self{
+	    buses: tracks.iter().enumerate().filter(|(_, t)| t.bus).map(|(i, _)| i).collect(),
+	    strips: strips,
+	    master: Strip::new(master, true, meta_data),
+	}
+    }
+    /**
+    @param track_inputs The summed notes of every track, indexed like the tracks
+    @param time In beats since the start of the song
+    @return The master output
+    */
+    pub fn process (&mut self, track_inputs: &[[f64; 2]], time: f64) -> [f64; 2] {
+	let mut bus_inputs: Vec<[f64; 2]> = vec![[0.0; 2]; self.strips.len()];
+	let mut output: [f64; 2] = [0.0; 2];
+	for (index, strip) in self.strips.iter_mut().enumerate() {
+	    if self.buses.contains(&index) { continue; }
+	    let a: [f64; 2] = strip.process(track_inputs[index], time);
+	    for (bus, level) in &strip.sends {
+		bus_inputs[*bus][0] += a[0] * level;
+		bus_inputs[*bus][1] += a[1] * level;
+	    }
+	    output[0] += a[0];
+	    output[1] += a[1];
+	}
+	for bus in &self.buses {
+	    let a: [f64; 2] = self.strips[*bus].process(bus_inputs[*bus], time);
+	    output[0] += a[0];
+	    output[1] += a[1];
+	}
+	self.master.process(output, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A track `a` that sends to the bus `verb` and a track `b` that doesn't, fed 1 and 2, with 8 going into the bus itself, mixed.
+    fn mix (set: &[(&str, &str, &str)], solo: &[&str]) -> f64 {
+	let mut tracks: Vec<Track> = vec![Track::new("main", false), Track::new("a", false), Track::new("b", false), Track::new("verb", true)];
+	tracks[1].set("send_verb", "1");
+	for (name, key, value) in set {
+	    tracks.iter_mut().find(|e| e.name == *name).unwrap().set(key, value);
+	}
+	let solo: Vec<String> = solo.iter().map(|e| e.to_string()).collect();
+	let mut mixer: Mixer = Mixer::new(&tracks, &Track::new("master", false), &crate::MetaData::new(), &solo);
+	mixer.process(&[[0.0; 2], [1.0; 2], [2.0; 2], [8.0; 2]], 0.0)[0]
+    }
+
+    #[test]
+    fn buses_play_sends () {
+	// The bus's own input is never played, only what's sent to it
+	assert_eq!(mix(&[], &[]), 1.0 + 2.0 + 1.0);
+    }
+
+    #[test]
+    fn mute () {
+	assert_eq!(mix(&[("verb", "mute", "yes")], &[]), 1.0 + 2.0);
+	assert_eq!(mix(&[("a", "mute", "yes")], &[]), 2.0);
+    }
+
+    #[test]
+    fn solo () {
+	assert_eq!(mix(&[("b", "solo", "yes")], &[]), 2.0);
+	assert_eq!(mix(&[], &["a"]), 1.0 + 1.0);
+	assert_eq!(mix(&[], &["verb"]), 1.0 + 1.0);
+	assert_eq!(mix(&[("verb", "mute", "yes")], &["a"]), 1.0);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::ParseError;
+
+/**
+Parses a melody in Music Macro Language, like `o4 l8 c d e f g4 a b > c2`.
+
+- `c` to `b` play a note, `r` (or `p`) rests. Each can be followed by `+` or `#`
+  for sharp, `-` for flat, a length (`4` is a quarter note), and dots.
+- `&` or `^` ties on another length, like `c4&8` or `c4&c8`.
+- `o` sets the octave, `>` and `<` go up and down one. An octave runs from c up
+  to b, and `o4 c` is the same note as `pitch=C4`, so `o4 a` is the A above it.
+- `l` sets the length of notes without one.
+
+Spaces and `|` are ignored.
+@return The time after the start of the melody, length and frequency of every note (all in beats and Hz), and the melody's length
+*/
+pub fn parse (s: &str) -> Result<(Vec<(f64, f64, f64)>, f64), ParseError> {
+    let chars: Vec<char> = s.chars().filter(|c| !matches!(c, ' ' | '\t' | '|')).collect();
+    let mut i: usize = 0;
+    let mut octave: i32 = 4;
+    let mut default_length: f64 = 1.0; // In beats
+    let mut time: f64 = 0.0;
+    let mut notes: Vec<(f64, f64, f64)> = Vec::<(f64, f64, f64)>::new();
+
+    // Reads a number, if there is one
+    let number = |i: &mut usize| -> Option<u32> {
+	let start: usize = *i;
+	while *i < chars.len() && chars[*i].is_ascii_digit() { *i += 1; }
+	chars[start..*i].iter().collect::<String>().parse().ok()
+    };
+    // Reads a length and its dots, defaulting to the current length
+    let length = |i: &mut usize, default_length: f64| -> Result<f64, ParseError> {
+	let mut beats: f64 = match number(i) {
+	    Option::Some(0) => { return Err(ParseError); },
+	    Option::Some(n) => { 4.0 / n as f64 },
+	    Option::None => { default_length }
+	};
+	let mut dot: f64 = beats / 2.0;
+	while *i < chars.len() && chars[*i] == '.' {
+	    beats += dot;
+	    dot /= 2.0;
+	    *i += 1;
+	}
+	Ok(beats)
+    };
+
+    while i < chars.len() {
+	let c: char = chars[i].to_ascii_lowercase();
+	i += 1;
+	match c {
+	    'a'..='g' | 'r' | 'p' => {
+		let mut semitone: Option<i32> = match c { 'c' => { Option::Some(0) }, 'd' => { Option::Some(2) }, 'e' => { Option::Some(4) }, 'f' => { Option::Some(5) }, 'g' => { Option::Some(7) }, 'a' => { Option::Some(9) }, 'b' => { Option::Some(11) }, _ => { Option::None } };
+		if let Option::Some(semitone) = semitone.as_mut() {
+		    while i < chars.len() && matches!(chars[i], '+' | '#' | '-') {
+			*semitone += if chars[i] == '-' { -1 } else { 1 };
+			i += 1;
+		    }
+		}
+		let mut beats: f64 = length(&mut i, default_length)?;
+		// Ties, optionally repeating the note name
+		while i < chars.len() && matches!(chars[i], '&' | '^') {
+		    i += 1;
+		    if i < chars.len() && chars[i].to_ascii_lowercase() == c {
+			i += 1;
+			while i < chars.len() && matches!(chars[i], '+' | '#' | '-') { i += 1; }
+		    }
+		    beats += length(&mut i, default_length)?;
+		}
+		if let Option::Some(semitone) = semitone {
+		    // C of octave n is the C named n, three semitones above the A named n, where A0 is 27.5 Hz
+		    notes.push((time, beats, 27.5 * 2.0_f64.powf(octave as f64 + (semitone + 3) as f64 / 12.0)));
+		}
+		time += beats;
+	    },
+	    'o' => { octave = number(&mut i).ok_or(ParseError)? as i32; },
+	    'l' => { default_length = length(&mut i, default_length)?; },
+	    '>' => { octave += 1; },
+	    '<' => { octave -= 1; },
+	    _ => { return Err(ParseError); }
+	}
+    }
+    Ok((notes, time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octaves_match_pitch_names () {
+	let (notes, _): (Vec<(f64, f64, f64)>, f64) = parse("o4 c a").unwrap();
+	assert!((notes[0].2 - crate::pitch_to_frequency("C4").unwrap()).abs() < 1e-9);
+	assert!((notes[1].2 - 880.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn scale_rises () {
+	let (notes, length): (Vec<(f64, f64, f64)>, f64) = parse("o4 l8 c d e f g4 a b > c2").unwrap();
+	assert_eq!(notes.len(), 8);
+	assert!(notes.windows(2).all(|e| e[1].2 > e[0].2));
+	assert!((notes[7].2 / notes[0].2 - 2.0).abs() < 1e-9);
+	assert_eq!(length, 6.0);
+    }
+
+    #[test]
+    fn accidentals_lengths_and_ties () {
+	let (notes, length): (Vec<(f64, f64, f64)>, f64) = parse("l4 c+8. d-16 e4&8 r2 < b").unwrap();
+	assert_eq!(notes.iter().map(|e| (e.0, e.1)).collect::<Vec<(f64, f64)>>(), vec![(0.0, 0.75), (0.75, 0.25), (1.0, 1.5), (4.5, 1.0)]);
+	assert!((notes[0].2 - notes[1].2).abs() < 1e-9);
+	assert!(notes[3].2 < notes[0].2);
+	assert_eq!(length, 5.5);
+	assert!(parse("c0").is_err());
+	assert!(parse("x").is_err());
+    }
+}
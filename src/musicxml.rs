@@ -0,0 +1,424 @@
+use crate::ParseError;
+use crate::meter::Meter;
+use crate::score::{Measure, Part, Score, ScoreNote, Written, DIVISIONS, bass_clef, dynamic_marking, dynamic_volume, midi_to_frequency};
+use crate::xml::{Element, escape};
+use crate::zip;
+
+/*
+MusicXML (https://www.w3.org/2021/06/musicxml40/), the partwise kind notation
+editors write. Reading covers parts and their voices, ties, tuplets, time
+signatures, dynamics and tempo marks. Repeats are played once, and grace and cue
+notes are skipped.
+*/
+
+/// MIDI note number of a `<pitch>`.
+fn pitch (element: &Element) -> Option<f64> {
+    let step: f64 = match element.child("step")?.text().as_str() {
+	"C" => { 0.0 }, "D" => { 2.0 }, "E" => { 4.0 }, "F" => { 5.0 }, "G" => { 7.0 }, "A" => { 9.0 }, "B" => { 11.0 },
+	_ => { return Option::None; }
+    };
+    let octave: f64 = element.value("octave")?;
+    Option::Some(12.0 * (octave + 1.0) + step + element.value("alter").unwrap_or(0.0))
+}
+
+/// Quarter notes per minute from a `<metronome>`, like a dotted quarter at 60.
+fn metronome (element: &Element) -> Option<f64> {
+    let mut beats: f64 = match element.child("beat-unit")?.text().as_str() {
+	"long" => { 16.0 }, "breve" => { 8.0 }, "whole" => { 4.0 }, "half" => { 2.0 }, "quarter" => { 1.0 },
+	"eighth" => { 0.5 }, "16th" => { 0.25 }, "32nd" => { 0.125 },
+	_ => { return Option::None; }
+    };
+    let mut dot: f64 = beats / 2.0;
+    for _ in element.elements().filter(|e| e.name == "beat-unit-dot") {
+	beats += dot;
+	dot /= 2.0;
+    }
+    element.value::<f64>("per-minute").map(|e| e * beats)
+}
+
+/// Reads the root file of a compressed .mxl archive.
+pub fn unzip (bytes: &[u8]) -> Result<String, ParseError> {
+    let names: Vec<String> = zip::names(bytes)?;
+    let container: Option<String> = zip::read(bytes, "META-INF/container.xml").ok()
+	.and_then(|e| crate::xml::parse(&String::from_utf8_lossy(&e)).ok())
+	.and_then(|e| e.child("rootfiles").and_then(|e| e.child("rootfile")).and_then(|e| e.attribute("full-path")).map(|e| e.to_string()));
+    let root: String = match container {
+	Option::Some(root) => { root },
+	Option::None => { names.into_iter().find(|e| !e.starts_with("META-INF") && (e.ends_with(".xml") || e.ends_with(".musicxml"))).ok_or(ParseError)? }
+    };
+    Ok(String::from_utf8_lossy(&zip::read(bytes, &root)?).into_owned())
+}
+
+/**
+Reads a score. Tempo changes are folded into the times of the notes, so the
+score keeps the first tempo throughout.
+*/
+pub fn parse (text: &str) -> Result<Score, ParseError> {
+    let root: Element = crate::xml::parse(text)?;
+    if root.name != "score-partwise" { return Err(ParseError); }
+    let title: String = root.child("work").and_then(|e| e.child("work-title")).or_else(|| root.child("movement-title")).map(|e| e.text()).unwrap_or(String::new());
+    let names: Vec<(String, String)> = root.child("part-list").map(|list| list.elements().filter(|e| e.name == "score-part").map(|e| {
+	let id: String = e.attribute("id").unwrap_or("").to_string();
+	let name: String = e.child("part-name").map(|e| e.text()).filter(|e| !e.is_empty()).unwrap_or(id.clone());
+	(id, name)
+    }).collect()).unwrap_or(Vec::<(String, String)>::new());
+
+    let mut meter: Meter = Meter::new();
+    let mut tempos: Vec<(f64, f64)> = Vec::<(f64, f64)>::new(); // Beats and quarter notes per minute
+    let mut parts: Vec<Part> = Vec::<Part>::new();
+    for (index, part) in root.elements().filter(|e| e.name == "part").enumerate() {
+	let id: &str = part.attribute("id").unwrap_or("");
+	let name: String = names.iter().find(|e| e.0 == id).map(|e| e.1.clone()).unwrap_or(id.to_string());
+	let mut notes: Vec<ScoreNote> = Vec::<ScoreNote>::new();
+	let mut dynamics: Vec<(f64, f64)> = vec![(0.0, 1.0)]; // Beats and volume scalar
+	let mut ties: Vec<(f64, usize)> = Vec::<(f64, usize)>::new(); // Pitches and notes waiting for the rest of a tie
+	let mut divisions: f64 = 1.0; // Per beat
+	let mut measure_start: f64 = 0.0; // In beats
+	for (bar, measure) in part.elements().filter(|e| e.name == "measure").enumerate() {
+	    let mut time: f64 = measure_start;
+	    let mut measure_end: f64 = measure_start;
+	    let mut chord_start: f64 = measure_start; // Of the last note, for notes in a chord with it
+	    for element in measure.elements() {
+		match element.name.as_str() {
+		    "attributes" => {
+			divisions = element.value("divisions").unwrap_or(divisions);
+			if let Option::Some(signature) = element.child("time") {
+			    match (signature.value::<u32>("beats"), signature.value::<u32>("beat-type")) {
+				(Option::Some(n), Option::Some(d)) if index == 0 && n > 0 && d > 0 => { meter.set(bar as u32 + 1, n, d); },
+				_ => {}
+			    }
+			}
+		    },
+		    "note" => {
+			if element.has("grace") { continue; }
+			let duration: f64 = element.value::<f64>("duration").unwrap_or(0.0) / divisions;
+			let start: f64 = if element.has("chord") { chord_start } else { time };
+			chord_start = start;
+			time = start + duration;
+			measure_end = measure_end.max(time);
+			if element.has("cue") { continue; }
+			let midi: f64 = match element.child("pitch").and_then(pitch) { Option::Some(midi) => { midi }, Option::None => { continue; } };
+			let tie_types: Vec<&str> = element.elements().filter(|e| e.name == "tie").filter_map(|e| e.attribute("type")).collect();
+			// Carry on a tied note instead of playing it again
+			let mut index: Option<usize> = Option::None;
+			if tie_types.contains(&"stop") {
+			    if let Option::Some(i) = ties.iter().position(|e| e.0 == midi) {
+				let tied: usize = ties.remove(i).1;
+				notes[tied].duration = start + duration - notes[tied].time;
+				index = Option::Some(tied);
+			    }
+			}
+			let index: usize = match index {
+			    Option::Some(index) => { index },
+			    Option::None => {
+				notes.push(ScoreNote{time: start, duration: duration, frequency: midi_to_frequency(midi), volume: 1.0});
+				notes.len() - 1
+			    }
+			};
+			if tie_types.contains(&"start") {
+			    ties.push((midi, index));
+			}
+		    },
+		    "backup" => { time -= element.value::<f64>("duration").unwrap_or(0.0) / divisions; },
+		    "forward" => {
+			time += element.value::<f64>("duration").unwrap_or(0.0) / divisions;
+			measure_end = measure_end.max(time);
+		    },
+		    "direction" | "sound" => {
+			let sound: Option<&Element> = if element.name == "sound" { Option::Some(element) } else { element.child("sound") };
+			let mut tempo: Option<f64> = sound.and_then(|e| e.attribute("tempo")).and_then(|e| e.parse().ok());
+			let mut volume: Option<f64> = sound.and_then(|e| e.attribute("dynamics")).and_then(|e| e.parse::<f64>().ok()).map(|e| e / 100.0 * dynamic_volume("f").unwrap());
+			for kind in element.elements().filter(|e| e.name == "direction-type").flat_map(|e| e.elements()) {
+			    match kind.name.as_str() {
+				"metronome" => { tempo = tempo.or(metronome(kind)); },
+				"dynamics" => { volume = volume.or(kind.elements().find_map(|e| dynamic_volume(&e.name))); },
+				_ => {}
+			    }
+			}
+			// A direction can sit a little way off from where it's written
+			let at: f64 = time + element.value::<f64>("offset").unwrap_or(0.0) / divisions;
+			if let Option::Some(tempo) = tempo.filter(|e| *e > 0.0) {
+			    tempos.push((at, tempo));
+			}
+			if let Option::Some(volume) = volume {
+			    dynamics.push((at, volume));
+			}
+		    },
+		    _ => {}
+		}
+	    }
+	    measure_start = measure_end;
+	}
+	dynamics.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+	for note in notes.iter_mut() {
+	    note.volume = dynamics.iter().rev().find(|e| e.0 <= note.time + 1e-9).map_or(1.0, |e| e.1);
+	}
+	parts.push(Part{name: name, notes: notes});
+    }
+
+    // Stretch the beats after each tempo change to play at the first tempo
+    tempos.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let tempo: f64 = tempos.first().map_or(120.0, |e| e.1);
+    let warp = |beats: f64| -> f64 {
+	let mut warped: f64 = 0.0;
+	let mut last: (f64, f64) = (0.0, tempo);
+	for change in tempos.iter().filter(|e| e.0 < beats) {
+	    warped += (change.0 - last.0) * tempo / last.1;
+	    last = *change;
+	}
+	warped + (beats - last.0) * tempo / last.1
+    };
+    for part in parts.iter_mut() {
+	for note in part.notes.iter_mut() {
+	    let end: f64 = warp(note.time + note.duration);
+	    note.time = warp(note.time);
+	    note.duration = end - note.time;
+	}
+    }
+    Ok(Score{title: title, tempo: tempo, meter: meter, parts: parts})
+}
+
+/// Step, alter and octave of a MIDI note number, spelt with sharps.
+fn spell (midi: i32) -> (char, i32, i32) {
+    let (step, alter): (char, i32) = match midi.rem_euclid(12) {
+	0 => { ('C', 0) }, 1 => { ('C', 1) }, 2 => { ('D', 0) }, 3 => { ('D', 1) }, 4 => { ('E', 0) }, 5 => { ('F', 0) },
+	6 => { ('F', 1) }, 7 => { ('G', 0) }, 8 => { ('G', 1) }, 9 => { ('A', 0) }, 10 => { ('A', 1) }, _ => { ('B', 0) }
+    };
+    (step, alter, midi.div_euclid(12) - 1)
+}
+
+fn type_name (denominator: u32) -> &'static str {
+    match denominator {
+	1 => { "whole" }, 2 => { "half" }, 4 => { "quarter" }, 8 => { "eighth" }, 16 => { "16th" }, 32 => { "32nd" }, _ => { "64th" }
+    }
+}
+
+/// Writes the notes, chords and rests of one voice in a bar.
+fn write_voice (xml: &mut String, voice: &[Written], number: usize, tied: &mut bool) -> () {
+    for written in voice {
+	let pitches: Vec<Option<i32>> = if written.pitches.is_empty() { vec![Option::None] } else { written.pitches.iter().map(|e| Option::Some(*e)).collect() };
+	for (i, pitch) in pitches.iter().enumerate() {
+	    xml.push_str("      <note>\n");
+	    if i > 0 { xml.push_str("        <chord/>\n"); }
+	    match pitch {
+		Option::Some(midi) => {
+		    let (step, alter, octave): (char, i32, i32) = spell(*midi);
+		    xml.push_str(&format!("        <pitch><step>{}</step>", step));
+		    if alter != 0 { xml.push_str(&format!("<alter>{}</alter>", alter)); }
+		    xml.push_str(&format!("<octave>{}</octave></pitch>\n", octave));
+		},
+		Option::None if written.whole_bar => { xml.push_str("        <rest measure=\"yes\"/>\n"); },
+		Option::None => { xml.push_str("        <rest/>\n"); }
+	    }
+	    xml.push_str(&format!("        <duration>{}</duration>\n", written.value.length));
+	    if *tied && pitch.is_some() { xml.push_str("        <tie type=\"stop\"/>\n"); }
+	    if written.tie { xml.push_str("        <tie type=\"start\"/>\n"); }
+	    xml.push_str(&format!("        <voice>{}</voice>\n", number));
+	    if !written.whole_bar {
+		xml.push_str(&format!("        <type>{}</type>\n", type_name(written.value.denominator)));
+		if written.value.dotted { xml.push_str("        <dot/>\n"); }
+		if written.value.triplet { xml.push_str("        <time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification>\n"); }
+	    }
+	    if (*tied && pitch.is_some()) || written.tie {
+		xml.push_str("        <notations>");
+		if *tied && pitch.is_some() { xml.push_str("<tied type=\"stop\"/>"); }
+		if written.tie { xml.push_str("<tied type=\"start\"/>"); }
+		xml.push_str("</notations>\n");
+	    }
+	    xml.push_str("      </note>\n");
+	}
+	*tied = written.tie;
+    }
+}
+
+/// Writes a score out as an uncompressed MusicXML document.
+pub fn write (score: &Score) -> String {
+    let mut xml: String = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    xml.push_str("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n");
+    xml.push_str("<score-partwise version=\"4.0\">\n");
+    if !score.title.is_empty() {
+	xml.push_str(&format!("  <work><work-title>{}</work-title></work>\n", escape(&score.title)));
+    }
+    xml.push_str("  <identification><encoding><software>wav_gen</software></encoding></identification>\n");
+    xml.push_str("  <part-list>\n");
+    for (i, part) in score.parts.iter().enumerate() {
+	xml.push_str(&format!("    <score-part id=\"P{}\"><part-name>{}</part-name></score-part>\n", i + 1, escape(&part.name)));
+    }
+    xml.push_str("  </part-list>\n");
+    for (i, part) in score.parts.iter().enumerate() {
+	xml.push_str(&format!("  <part id=\"P{}\">\n", i + 1));
+	let measures: Vec<Measure> = score.measures(part);
+	let bass: bool = bass_clef(&measures);
+	let mut ties: Vec<bool> = Vec::<bool>::new(); // Whether each voice was tied into the next bar
+	// Where the volume changes, in beats, counting unmarked music as mf
+	let mut notes: Vec<ScoreNote> = part.notes.clone();
+	notes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+	let mut changes: Vec<(f64, f64)> = Vec::<(f64, f64)>::new();
+	let mut volume: f64 = 1.0;
+	for note in notes {
+	    if note.volume != volume {
+		changes.push((note.time, note.volume));
+		volume = note.volume;
+	    }
+	}
+	for (m, measure) in measures.iter().enumerate() {
+	    xml.push_str(&format!("    <measure number=\"{}\">\n", measure.number));
+	    let new_signature: bool = m == 0 || measures[m - 1].signature != measure.signature;
+	    if new_signature {
+		xml.push_str("      <attributes>\n");
+		if m == 0 { xml.push_str(&format!("        <divisions>{}</divisions>\n", DIVISIONS)); }
+		xml.push_str(&format!("        <time><beats>{}</beats><beat-type>{}</beat-type></time>\n", measure.signature.0, measure.signature.1));
+		if m == 0 {
+		    if bass {
+			xml.push_str("        <clef><sign>F</sign><line>4</line></clef>\n");
+		    } else {
+			xml.push_str("        <clef><sign>G</sign><line>2</line></clef>\n");
+		    }
+		}
+		xml.push_str("      </attributes>\n");
+	    }
+	    if m == 0 {
+		xml.push_str(&format!("      <direction placement=\"above\">\n        <direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>{}</per-minute></metronome></direction-type>\n        <sound tempo=\"{}\"/>\n      </direction>\n", score.tempo, score.tempo));
+	    }
+	    let bar: (f64, f64) = (score.meter.bar_start(measure.number), score.meter.bar_start(measure.number + 1));
+	    for (time, volume) in changes.iter().filter(|e| e.0 >= bar.0 - 1e-9 && (e.0 < bar.1 - 1e-9 || m + 1 == measures.len())) {
+		// The marking for players, and the exact volume for playback
+		xml.push_str(&format!("      <direction placement=\"below\">\n        <direction-type><dynamics><{}/></dynamics></direction-type>\n", dynamic_marking(*volume)));
+		let offset: i64 = ((time - bar.0) * DIVISIONS as f64).round() as i64;
+		if offset != 0 { xml.push_str(&format!("        <offset>{}</offset>\n", offset)); }
+		xml.push_str(&format!("        <sound dynamics=\"{}\"/>\n      </direction>\n", volume / dynamic_volume("f").unwrap() * 100.0));
+	    }
+	    ties.resize(measure.voices.len(), false);
+	    for (v, voice) in measure.voices.iter().enumerate() {
+		if v > 0 {
+		    xml.push_str(&format!("      <backup><duration>{}</duration></backup>\n", measure.length));
+		}
+		write_voice(&mut xml, voice, v + 1, &mut ties[v]);
+	    }
+	    if m + 1 == measures.len() {
+		xml.push_str("      <barline location=\"right\"><bar-style>light-heavy</bar-style></barline>\n");
+	    }
+	    xml.push_str("    </measure>\n");
+	}
+	xml.push_str("  </part>\n");
+    }
+    xml.push_str("</score-partwise>\n");
+    xml
+}
+
+/// Writes a score out as a compressed .mxl archive.
+pub fn write_mxl (score: &Score) -> Vec<u8> {
+    let container: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<container>\n  <rootfiles>\n    <rootfile full-path=\"score.musicxml\" media-type=\"application/vnd.recordare.musicxml+xml\"/>\n  </rootfiles>\n</container>\n";
+    let score: String = write(score);
+    zip::write(&[("mimetype", b"application/vnd.recordare.musicxml".as_slice()), ("META-INF/container.xml", container.as_bytes()), ("score.musicxml", score.as_bytes())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score () -> Score {
+	let mut meter: Meter = Meter::new();
+	meter.set(2, 3, 4);
+	let note = |time: f64, duration: f64, midi: f64, volume: f64| -> ScoreNote { ScoreNote{time: time, duration: duration, frequency: midi_to_frequency(midi), volume: volume} };
+	Score{title: "Tom & Jerry's <tune>".to_string(), tempo: 90.0, meter: meter, parts: vec![
+	    // A tie over the bar line, a dotted note, a rest and a chord, getting quieter partway through a bar
+	    Part{name: "Lead".to_string(), notes: vec![note(0.0, 1.0, 60.0, 1.0), note(1.0, 0.5, 62.0, 1.0), note(1.5, 1.5, 64.0, 0.6), note(3.0, 2.0, 65.0, 0.6), note(6.0, 1.0, 67.0, 1.4), note(6.0, 1.0, 71.0, 1.4)]},
+	    Part{name: "Bass".to_string(), notes: vec![note(0.0, 4.0, 36.0, 0.8), note(4.0, 3.0, 43.0, 1.0)]}
+	]}
+    }
+
+    /// A note's time, duration, MIDI note number and volume.
+    type Sounded = (f64, f64, i32, f64);
+
+    /// Parts' names, and their notes in order, rounded off.
+    fn notes (score: &Score) -> Vec<(String, Vec<Sounded>)> {
+	let round = |x: f64| -> f64 { (x * 1e6).round() / 1e6 };
+	score.parts.iter().map(|part| {
+	    let mut notes: Vec<Sounded> = part.notes.iter().map(|e| (round(e.time), round(e.duration), (69.0 + 12.0 * (e.frequency / 440.0).log2()).round() as i32, round(e.volume))).collect();
+	    notes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+	    (part.name.clone(), notes)
+	}).collect()
+    }
+
+    #[test]
+    fn write_and_parse () {
+	let written: Score = score();
+	let parsed: Score = parse(&write(&written)).unwrap();
+	assert_eq!(parsed.title, written.title);
+	assert_eq!(parsed.tempo, written.tempo);
+	for bar in 1..4 {
+	    assert_eq!(parsed.meter.signature(bar), written.meter.signature(bar));
+	}
+	assert_eq!(notes(&parsed), notes(&written));
+    }
+
+    #[test]
+    fn write_and_parse_compressed () {
+	let written: Score = score();
+	let parsed: Score = parse(&unzip(&write_mxl(&written)).unwrap()).unwrap();
+	assert_eq!(notes(&parsed), notes(&written));
+    }
+
+    /// Two voices, a triplet, a tempo change and dynamics, written the way MuseScore writes them.
+    const MUSESCORE: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">
+<score-partwise version="4.0">
+  <work><work-title>Fixture</work-title></work>
+  <part-list>
+    <score-part id="P1"><part-name>Piano</part-name></score-part>
+  </part-list>
+  <part id="P1">
+    <measure number="1">
+      <attributes>
+	<divisions>6</divisions>
+	<key><fifths>0</fifths></key>
+	<time><beats>4</beats><beat-type>4</beat-type></time>
+	<clef><sign>G</sign><line>2</line></clef>
+      </attributes>
+      <direction placement="above">
+	<direction-type><metronome parentheses="no"><beat-unit>quarter</beat-unit><per-minute>120</per-minute></metronome></direction-type>
+	<sound tempo="120"/>
+      </direction>
+      <direction placement="below">
+	<direction-type><dynamics><p/></dynamics></direction-type>
+	<sound dynamics="50"/>
+      </direction>
+      <note><pitch><step>C</step><octave>5</octave></pitch><duration>12</duration><voice>1</voice><type>half</type><stem>down</stem></note>
+      <note><pitch><step>E</step><octave>5</octave></pitch><duration>4</duration><voice>1</voice><type>quarter</type><time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification><notations><tuplet type="start" bracket="yes"/></notations></note>
+      <note><pitch><step>D</step><octave>5</octave></pitch><duration>4</duration><voice>1</voice><type>quarter</type><time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification></note>
+      <note><pitch><step>C</step><octave>5</octave></pitch><duration>4</duration><voice>1</voice><type>quarter</type><time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification><notations><tuplet type="stop"/></notations></note>
+      <backup><duration>24</duration></backup>
+      <forward><duration>12</duration><voice>2</voice></forward>
+      <note><pitch><step>G</step><octave>3</octave></pitch><duration>12</duration><voice>2</voice><type>half</type><stem>down</stem></note>
+    </measure>
+    <measure number="2">
+      <direction placement="above">
+	<direction-type><metronome parentheses="no"><beat-unit>quarter</beat-unit><per-minute>60</per-minute></metronome></direction-type>
+	<sound tempo="60"/>
+      </direction>
+      <note><pitch><step>C</step><octave>4</octave></pitch><duration>12</duration><voice>1</voice><type>half</type></note>
+      <direction placement="below">
+	<direction-type><dynamics><ff/></dynamics></direction-type>
+      </direction>
+      <note><pitch><step>D</step><octave>4</octave></pitch><duration>12</duration><voice>1</voice><type>half</type></note>
+      <barline location="right"><bar-style>light-heavy</bar-style></barline>
+    </measure>
+  </part>
+</score-partwise>
+"#;
+
+    #[test]
+    fn parse_musescore () {
+	let parsed: Score = parse(MUSESCORE).unwrap();
+	assert_eq!(parsed.title, "Fixture");
+	assert_eq!(parsed.tempo, 120.0);
+	// The second bar is at half the speed, so its beats last twice as long
+	let third: f64 = (2.0_f64 / 3.0 * 1e6).round() / 1e6;
+	assert_eq!(notes(&parsed), vec![("Piano".to_string(), vec![
+	    (0.0, 2.0, 72, 0.6), (2.0, third, 76, 0.6), (2.0, 2.0, 55, 0.6), (2.666667, third, 74, 0.6), (3.333333, third, 72, 0.6),
+	    (4.0, 4.0, 60, 0.6), (8.0, 4.0, 62, 1.4)
+	])]);
+    }
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+/**
+A recorded wave played back at the note's pitch, like the instruments of
+tracker modules.
+*/
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub data: Vec<f32>, // From -1 to 1
+    pub loop_start: usize, // In frames
+    pub loop_length: usize, // In frames, 0 for a one shot
+    pub ping_pong: bool, // The loop plays forwards and then backwards
+    pub cycle: f64, // Frames per cycle of the note's frequency
+}
+
+impl Sample {
+    /// How far into the sample a position is, after looping, or none once a one shot has finished.
+    fn frame (&self, position: f64) -> Option<f64> {
+	let loop_end: f64 = (self.loop_start + self.loop_length) as f64;
+	if self.loop_length < 2 || position < loop_end {
+	    return if position < self.data.len() as f64 { Option::Some(position.max(0.0)) } else { Option::None };
+	}
+	let length: f64 = self.loop_length as f64;
+	let mut into: f64 = (position - self.loop_start as f64) % if self.ping_pong { 2.0 * length } else { length };
+	if into >= length {
+	    // On the way back
+	    into = (2.0 * length - into - 1.0).max(0.0);
+	}
+	Option::Some(self.loop_start as f64 + into)
+    }
+    /**
+    @param phase In cycles of the note's frequency since it started
+    @return The level, from 0 to 1 like the other wave forms
+    */
+    pub fn audio_at (&self, phase: f64) -> f64 {
+	let level: f64 = match self.frame(phase * self.cycle) {
+	    Option::Some(frame) => {
+		let i: usize = frame as usize;
+		let a: f64 = self.data[i] as f64;
+		let b: f64 = match self.frame(i as f64 + 1.0) { Option::Some(next) => { self.data[next as usize] as f64 }, Option::None => { 0.0 } };
+		a + (b - a) * frame.fract()
+	    },
+	    Option::None => { 0.0 }
+	};
+	(level + 1.0) * 0.5
+    }
+    /// Seconds a one shot lasts at some frequency, or forever if it loops.
+    pub fn seconds (&self, frequency: f64) -> f64 {
+	if self.loop_length >= 2 { f64::INFINITY } else { self.data.len() as f64 / (self.cycle * frequency) }
+    }
+}
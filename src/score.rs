@@ -0,0 +1,213 @@
+use crate::meter::Meter;
+
+/**
+Music read from another notation, before it's turned into a song. Times and
+durations are in beats (quarter notes) like everywhere else.
+*/
+pub struct Score {
+    pub title: String,
+    pub tempo: f64, // Quarter notes per minute
+    pub meter: Meter,
+    pub parts: Vec<Part>,
+}
+
+/// One instrument or voice of a score, which becomes a stem of the song.
+pub struct Part {
+    pub name: String,
+    pub notes: Vec<ScoreNote>,
+}
+
+#[derive(Copy, Clone)]
+pub struct ScoreNote {
+    pub time: f64, // In beats
+    pub duration: f64, // In beats
+    pub frequency: f64, // In Hz
+    pub volume: f64, // Scalar for the instrument's volume, from the dynamics
+}
+
+impl Score {
+    /// Where the last note of any part ends, in beats.
+    pub fn end (&self) -> f64 {
+	self.parts.iter().flat_map(|e| e.notes.iter()).fold(0.0, |a, e| a.max(e.time + e.duration))
+    }
+}
+
+/// Frequency of a MIDI note number, where 60 is middle C.
+pub fn midi_to_frequency (midi: f64) -> f64 {
+    440.0 * 2.0_f64.powf((midi - 69.0) / 12.0)
+}
+
+/// Scalar for the instrument's volume from a dynamic marking like `p`, where unmarked music is `mf`.
+pub fn dynamic_volume (marking: &str) -> Option<f64> {
+    match marking {
+	"pppp" => { Option::Some(0.25) },
+	"ppp" => { Option::Some(0.35) },
+	"pp" => { Option::Some(0.45) },
+	"p" => { Option::Some(0.6) },
+	"mp" => { Option::Some(0.8) },
+	"mf" => { Option::Some(1.0) },
+	"f" => { Option::Some(1.2) },
+	"ff" => { Option::Some(1.4) },
+	"fff" => { Option::Some(1.6) },
+	"ffff" => { Option::Some(1.8) },
+	_ => { Option::None }
+    }
+}
+
+/// The dynamic marking closest to a volume scalar, the other way from `dynamic_volume`.
+pub fn dynamic_marking (volume: f64) -> &'static str {
+    ["pppp", "ppp", "pp", "p", "mp", "mf", "f", "ff", "fff", "ffff"].into_iter()
+	.min_by(|a, b| (dynamic_volume(a).unwrap() - volume).abs().partial_cmp(&(dynamic_volume(b).unwrap() - volume).abs()).unwrap())
+	.unwrap()
+}
+
+/// MIDI note number of a frequency, with cents as the fraction.
+pub fn frequency_to_midi (frequency: f64) -> f64 {
+    69.0 + 12.0 * (frequency / 440.0).log2()
+}
+
+/// Divisions of a beat on the grid notes are written on, fine enough for 32nd notes and 16th note triplets.
+pub const DIVISIONS: u32 = 24;
+
+/// A length that can be written as one note or rest.
+#[derive(Copy, Clone)]
+pub struct NoteValue {
+    pub length: u32, // In divisions
+    pub denominator: u32, // 1 for a whole note, 2 for a half note, and so on
+    pub dotted: bool,
+    pub triplet: bool, // Three in the time of two
+}
+
+const NOTE_VALUES: [NoteValue; 17] = [
+    NoteValue{length: 96, denominator: 1, dotted: false, triplet: false},
+    NoteValue{length: 72, denominator: 2, dotted: true, triplet: false},
+    NoteValue{length: 64, denominator: 1, dotted: false, triplet: true},
+    NoteValue{length: 48, denominator: 2, dotted: false, triplet: false},
+    NoteValue{length: 36, denominator: 4, dotted: true, triplet: false},
+    NoteValue{length: 32, denominator: 2, dotted: false, triplet: true},
+    NoteValue{length: 24, denominator: 4, dotted: false, triplet: false},
+    NoteValue{length: 18, denominator: 8, dotted: true, triplet: false},
+    NoteValue{length: 16, denominator: 4, dotted: false, triplet: true},
+    NoteValue{length: 12, denominator: 8, dotted: false, triplet: false},
+    NoteValue{length: 9, denominator: 16, dotted: true, triplet: false},
+    NoteValue{length: 8, denominator: 8, dotted: false, triplet: true},
+    NoteValue{length: 6, denominator: 16, dotted: false, triplet: false},
+    NoteValue{length: 4, denominator: 16, dotted: false, triplet: true},
+    NoteValue{length: 3, denominator: 32, dotted: false, triplet: false},
+    NoteValue{length: 2, denominator: 32, dotted: false, triplet: true},
+    NoteValue{length: 1, denominator: 64, dotted: false, triplet: true},
+];
+
+/// Splits a length into note values to tie together, longest first.
+fn note_values (mut length: u32) -> Vec<NoteValue> {
+    let mut values: Vec<NoteValue> = Vec::<NoteValue>::new();
+    while length > 0 {
+	let value: NoteValue = *NOTE_VALUES.iter().find(|e| e.length <= length).unwrap();
+	values.push(value);
+	length -= value.length;
+    }
+    values
+}
+
+/// A note, chord or rest of one voice as it's written down.
+pub struct Written {
+    pub value: NoteValue,
+    pub pitches: Vec<i32>, // MIDI note numbers, none for a rest
+    pub tie: bool, // Tied on to the next one
+    pub whole_bar: bool, // A rest filling the whole bar
+}
+
+/// One bar of a part as it's written down.
+pub struct Measure {
+    pub number: u32,
+    pub length: u32, // In divisions
+    pub signature: (u32, u32),
+    pub voices: Vec<Vec<Written>>,
+}
+
+/// Whether a part sits low enough to write on the bass clef, from its bars.
+pub fn bass_clef (measures: &[Measure]) -> bool {
+    let pitches: Vec<i32> = measures.iter().flat_map(|e| e.voices.iter()).flatten().flat_map(|e| e.pitches.iter().copied()).collect();
+    !pitches.is_empty() && pitches.iter().sum::<i32>() < 60 * pitches.len() as i32
+}
+
+impl Score {
+    /// How many bars it takes to hold every note.
+    pub fn bars (&self) -> u32 {
+	let end: f64 = self.end();
+	let mut bar: u32 = 1;
+	while self.meter.bar_start(bar + 1) < end - 1e-9 { bar += 1; }
+	bar
+    }
+    /**
+    Lays a part out for notation. Notes are rounded to the grid, notes with the
+    same start and end are joined into chords, and chords that overlap are put
+    in separate voices. Every voice is filled out with rests, and split into
+    tied note values at bar lines.
+    */
+    pub fn measures (&self, part: &Part) -> Vec<Measure> {
+	let grid = |beats: f64| -> i64 { (beats * DIVISIONS as f64).round() as i64 };
+	let mut chords: Vec<(i64, i64, Vec<i32>)> = Vec::<(i64, i64, Vec<i32>)>::new(); // Start and end in divisions, and the pitches
+	for note in &part.notes {
+	    let start: i64 = grid(note.time);
+	    let end: i64 = grid(note.time + note.duration).max(start + 1);
+	    let pitch: i32 = frequency_to_midi(note.frequency).round() as i32;
+	    match chords.iter_mut().find(|e| e.0 == start && e.1 == end) {
+		Option::Some(chord) => { if !chord.2.contains(&pitch) { chord.2.push(pitch); } },
+		Option::None => { chords.push((start, end, vec![pitch])); }
+	    }
+	}
+	chords.sort_by_key(|e| e.0);
+	let mut voices: Vec<Vec<(i64, i64, Vec<i32>)>> = Vec::<Vec<(i64, i64, Vec<i32>)>>::new();
+	for mut chord in chords {
+	    chord.2.sort();
+	    match voices.iter_mut().find(|e| e.last().is_some_and(|last| last.1 <= chord.0)) {
+		Option::Some(voice) => { voice.push(chord); },
+		Option::None => { voices.push(vec![chord]); }
+	    }
+	}
+	// Highest first, as the top voice is the first
+	let mean = |voice: &Vec<(i64, i64, Vec<i32>)>| -> f64 {
+	    let pitches: Vec<i32> = voice.iter().flat_map(|e| e.2.iter().copied()).collect();
+	    pitches.iter().sum::<i32>() as f64 / pitches.len() as f64
+	};
+	voices.sort_by(|a, b| mean(b).partial_cmp(&mean(a)).unwrap());
+	if voices.is_empty() { voices.push(Vec::<(i64, i64, Vec<i32>)>::new()); }
+
+	let mut measures: Vec<Measure> = Vec::<Measure>::new();
+	for bar in 1..=self.bars() {
+	    let start: i64 = grid(self.meter.bar_start(bar));
+	    let end: i64 = grid(self.meter.bar_start(bar + 1));
+	    let mut written: Vec<Vec<Written>> = Vec::<Vec<Written>>::new();
+	    for voice in &voices {
+		// The notes and the rests between them, clipped to the bar
+		let mut pieces: Vec<(i64, i64, Vec<i32>, bool)> = Vec::<(i64, i64, Vec<i32>, bool)>::new();
+		let mut time: i64 = start;
+		for (chord_start, chord_end, pitches) in voice.iter().filter(|e| e.1 > start && e.0 < end) {
+		    if *chord_start > time { pieces.push((time, *chord_start, Vec::<i32>::new(), false)); }
+		    let piece_start: i64 = (*chord_start).max(time);
+		    pieces.push((piece_start, (*chord_end).min(end), pitches.clone(), *chord_end > end));
+		    time = (*chord_end).min(end);
+		}
+		if time < end { pieces.push((time, end, Vec::<i32>::new(), false)); }
+
+		let mut values: Vec<Written> = Vec::<Written>::new();
+		if pieces.len() == 1 && pieces[0].2.is_empty() {
+		    values.push(Written{value: NoteValue{length: (end - start) as u32, denominator: 1, dotted: false, triplet: false}, pitches: Vec::<i32>::new(), tie: false, whole_bar: true});
+		} else {
+		    for (piece_start, piece_end, pitches, carries_on) in pieces {
+			let split: Vec<NoteValue> = note_values((piece_end - piece_start) as u32);
+			let count: usize = split.len();
+			for (i, value) in split.into_iter().enumerate() {
+			    let tie: bool = !pitches.is_empty() && (i + 1 < count || carries_on);
+			    values.push(Written{value: value, pitches: pitches.clone(), tie: tie, whole_bar: false});
+			}
+		    }
+		}
+		written.push(values);
+	    }
+	    measures.push(Measure{number: bar, length: (end - start) as u32, signature: self.meter.signature(bar), voices: written});
+	}
+	measures
+    }
+}
@@ -0,0 +1,190 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+
+use crate::{Note, ParseError, pitch_to_frequency};
+
+/*
+SCRIPT sections, for parts that are easier to work out than to write down. The
+lines up to END_SCRIPT are a Rhai script, run once when the section ends, and
+everything it writes out is read as if it had been in the song instead, moved
+later by the section's at=, so its notes get the instruments, tracks and cursor
+of the lines around them.
+
+    note(#{time: 0, pitch: "C4", dur: "1/8", inst: "lead"})   a NOTE line with these options
+    line("CHORD")                                              any other line
+    rand()  rand(a, b)  rand_int(a, b)  choose(array)          from the seeded generator
+    scale("C4", "dorian")  scale("C4", "dorian", octaves)      frequencies, root to root
+    pitch("A4")                                                a frequency
+    instruments()  instrument(name)                            names, and an instrument's options
+
+The random numbers come from seed=, so a song renders the same every time, and
+the LET variables so far are there as constants. print() goes to the log, as
+the WAV goes out on stdout.
+*/
+
+/// Semitones above the root of each scale, not counting the octave.
+fn steps (mode: &str) -> Result<&'static [i32], ParseError> {
+    match mode {
+	"major" | "ionian" => { Ok(&[0, 2, 4, 5, 7, 9, 11]) },
+	"minor" | "aeolian" => { Ok(&[0, 2, 3, 5, 7, 8, 10]) },
+	"dorian" => { Ok(&[0, 2, 3, 5, 7, 9, 10]) },
+	"phrygian" => { Ok(&[0, 1, 3, 5, 7, 8, 10]) },
+	"lydian" => { Ok(&[0, 2, 4, 6, 7, 9, 11]) },
+	"mixolydian" => { Ok(&[0, 2, 4, 5, 7, 9, 10]) },
+	"locrian" => { Ok(&[0, 1, 3, 5, 6, 8, 10]) },
+	"harmonic_minor" => { Ok(&[0, 2, 3, 5, 7, 8, 11]) },
+	"melodic_minor" => { Ok(&[0, 2, 3, 5, 7, 9, 11]) },
+	"pentatonic" | "major_pentatonic" => { Ok(&[0, 2, 4, 7, 9]) },
+	"minor_pentatonic" => { Ok(&[0, 3, 5, 7, 10]) },
+	"blues" => { Ok(&[0, 3, 5, 6, 7, 10]) },
+	"whole_tone" => { Ok(&[0, 2, 4, 6, 8, 10]) },
+	"chromatic" => { Ok(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]) },
+	_ => { Err(ParseError) }
+    }
+}
+
+/**
+The frequencies of a scale going up from its root, ending on the root of the octave above the last.
+@param root A pitch name like `C4`
+*/
+fn scale (root: &str, mode: &str, octaves: i64) -> Result<Array, Box<EvalAltResult>> {
+    let root: f64 = pitch_to_frequency(root).map_err(|_| format!("No pitch called {}", root))?;
+    let steps: &[i32] = steps(mode).map_err(|_| format!("No scale called {}", mode))?;
+    let mut frequencies: Array = Array::new();
+    for octave in 0..octaves.max(1) as i32 {
+	for step in steps {
+	    frequencies.push(Dynamic::from_float(root * 2.0_f64.powf((octave * 12 + step) as f64 / 12.0)));
+	}
+    }
+    frequencies.push(Dynamic::from_float(root * 2.0_f64.powf(octaves.max(1) as f64)));
+    Ok(frequencies)
+}
+
+/// Xorshift, like the dither's, but seeded by the song.
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn new (seed: u64) -> Self {
+	// Xorshift gets stuck at 0, and small seeds take a while to get going
+	let state: u64 = (seed ^ 0x9E3779B97F4A7C15).wrapping_mul(0xBF58476D1CE4E5B9);
+	Self{state: if state == 0 { 0x9E3779B97F4A7C15 } else { state }}
+    }
+    /// From 0 up to but not including 1.
+    fn next (&mut self) -> f64 {
+	self.state ^= self.state << 13;
+	self.state ^= self.state >> 7;
+	self.state ^= self.state << 17;
+	(self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A value as it would be written in an option.
+fn option_text (value: &Dynamic) -> String {
+    match value.as_float() {
+	Result::Ok(number) => { number.to_string() },
+	Result::Err(_) => { value.to_string() }
+    }
+}
+
+/// A variable as the script sees it, a number if it looks like one.
+fn variable_value (value: &str) -> Dynamic {
+    if let Result::Ok(number) = value.parse::<i64>() {
+	Dynamic::from_int(number)
+    } else if let Result::Ok(number) = value.parse::<f64>() {
+	Dynamic::from_float(number)
+    } else {
+	Dynamic::from(value.to_string())
+    }
+}
+
+/// A SCRIPT section, saved until END_SCRIPT.
+pub struct Script {
+    pub seed: u64,
+    pub offset: f64, // In beats, added to the times of the notes it writes
+    pub code: String,
+}
+
+impl Script {
+    pub fn new () -> Self {
+	Self{seed: 0, offset: 0.0, code: String::new()}
+    }
+    /**
+    Runs the script.
+    @param variables The LET variables so far
+    @param instruments The instruments so far
+    @return The lines it wrote, to be read as part of the song
+    */
+    pub fn run (&self, variables: &[(String, String)], instruments: &[(String, Note)]) -> Result<Vec<String>, ParseError> {
+	let written: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::<String>::new()));
+	let random: Rc<RefCell<Random>> = Rc::new(RefCell::new(Random::new(self.seed)));
+	let instruments: Rc<Vec<(String, Map)>> = Rc::new(instruments.iter().map(|(name, note)| {
+	    (name.clone(), note.options().into_iter().map(|(key, value)| (key.into(), variable_value(&value))).collect())
+	}).collect());
+
+	let mut engine: Engine = Engine::new();
+	engine.on_print(|text| eprint!("{}\n", text));
+	engine.on_debug(|text, _, position| eprint!("{} {}\n", position, text));
+	// A loop that never ends shouldn't hang the render
+	engine.set_max_operations(100_000_000);
+
+	let w = written.clone();
+	engine.register_fn("note", move |options: Map| {
+	    let mut line: String = "NOTE".to_string();
+	    for (key, value) in options {
+		line.push_str(&format!(" {}={}", key, option_text(&value)));
+	    }
+	    w.borrow_mut().push(line);
+	});
+	let w = written.clone();
+	engine.register_fn("line", move |line: &str| { w.borrow_mut().push(line.to_string()); });
+
+	let r = random.clone();
+	engine.register_fn("rand", move || r.borrow_mut().next());
+	let r = random.clone();
+	engine.register_fn("rand", move |low: f64, high: f64| low + (high - low) * r.borrow_mut().next());
+	let r = random.clone();
+	engine.register_fn("rand", move |low: i64, high: i64| low as f64 + (high - low) as f64 * r.borrow_mut().next());
+	let r = random.clone();
+	engine.register_fn("rand_int", move |low: i64, high: i64| {
+	    // Both ends included, like a die
+	    low + ((high - low + 1) as f64 * r.borrow_mut().next()).floor() as i64
+	});
+	let r = random.clone();
+	engine.register_fn("choose", move |items: Array| {
+	    if items.is_empty() { return Dynamic::UNIT; }
+	    let index: usize = ((items.len() as f64 * r.borrow_mut().next()) as usize).min(items.len() - 1);
+	    items[index].clone()
+	});
+
+	engine.register_fn("scale", |root: &str, mode: &str| scale(root, mode, 1));
+	engine.register_fn("scale", scale);
+	engine.register_fn("pitch", |name: &str| -> Result<f64, Box<EvalAltResult>> {
+	    pitch_to_frequency(name).map_err(|_| format!("No pitch called {}", name).into())
+	});
+	let i = instruments.clone();
+	engine.register_fn("instruments", move || -> Array { i.iter().map(|(name, _)| Dynamic::from(name.clone())).collect() });
+	let i = instruments.clone();
+	engine.register_fn("instrument", move |name: &str| -> Result<Map, Box<EvalAltResult>> {
+	    match i.iter().find(|(e, _)| e == name) {
+		Option::Some((_, options)) => { Ok(options.clone()) },
+		Option::None => { Err(format!("No instrument called {}", name).into()) }
+	    }
+	});
+
+	let mut scope: Scope = Scope::new();
+	for (name, value) in variables {
+	    scope.push_constant(name.as_str(), variable_value(value));
+	}
+	match engine.run_with_scope(&mut scope, &self.code) {
+	    Result::Ok(()) => { Ok(written.take()) },
+	    Result::Err(err) => {
+		eprint!("Error in script: {}\n", err);
+		Err(ParseError)
+	    }
+	}
+    }
+}
@@ -0,0 +1,80 @@
+use crate::parse_note_value;
+use crate::fx::DelayTime;
+
+/**
+One lane of a step sequencer, like `"x...x...x..x.x.."`. Each character is a
+step on the grid: `x` a hit, `X` an accent, `g` (or `o`) a ghost note, `f` a
+flam and `.` (or `-`) a rest. Spaces and `|` are ignored, to split up bars.
+*/
+#[derive(Copy, Clone)]
+pub struct Lane {
+    grid: f64, // Beats per step
+    swing: f64, // From 0.5 (straight) to 1, the share of each pair of steps taken by the first
+    accent: f64, // Volume scalar
+    ghost: f64, // Volume scalar
+    flam: DelayTime, // How long before the hit its grace note comes
+    flam_volume: f64, // Volume scalar of the grace note
+    repeat: u64, // How many times to play the pattern, one after another
+}
+
+impl Lane {
+    pub fn new () -> Self {
+	Self{grid: 0.25, swing: 0.5, accent: 1.5, ghost: 0.4, flam: DelayTime::Milliseconds(20.0), flam_volume: 0.6, repeat: 1}
+    }
+    /**
+    @return False if the option isn't a lane option
+    */
+    pub fn set (&mut self, key: &str, value: &str) -> bool {
+	match key {
+	    "grid" | "step" => { self.grid = parse_note_value(value).unwrap(); },
+	    "swing" => {
+		self.swing = match value.strip_suffix('%') {
+		    Option::Some(percent) => { percent.parse::<f64>().unwrap() / 100.0 },
+		    Option::None => { value.parse().unwrap() }
+		};
+	    },
+	    "accent" => { self.accent = value.parse().unwrap(); },
+	    "ghost" => { self.ghost = value.parse().unwrap(); },
+	    "flam" => { self.flam = value.parse().unwrap(); },
+	    "flam_volume" => { self.flam_volume = value.parse().unwrap(); },
+	    "repeat" | "times" => { self.repeat = value.parse().unwrap(); },
+	    _ => { return false; }
+	}
+	true
+    }
+    /// How long the lane plays for in beats, with all its repeats.
+    pub fn length (&self, pattern: &str) -> f64 {
+	pattern.chars().filter(|c| !matches!(c, ' ' | '|')).count() as f64 * self.grid * self.repeat as f64
+    }
+    /**
+    Flams in ms can only be placed once the tempo is known, so grace notes come on
+    their step, with how long before it they should be moved, up to the start of the lane.
+    @param pattern The steps
+    @return The time in beats after the start of the lane and the volume scalar of every hit, and for grace notes the flam
+    */
+    pub fn hits (&self, pattern: &str) -> Vec<(f64, f64, Option<DelayTime>)> {
+	let steps: Vec<char> = pattern.chars().filter(|c| !matches!(c, ' ' | '|')).collect();
+	let mut hits: Vec<(f64, f64, Option<DelayTime>)> = Vec::<(f64, f64, Option<DelayTime>)>::new();
+	for r in 0..self.repeat {
+	    for (i, step) in steps.iter().enumerate() {
+		let index: usize = r as usize * steps.len() + i;
+		let mut time: f64 = index as f64 * self.grid;
+		if index % 2 == 1 {
+		    time += (self.swing - 0.5) * 2.0 * self.grid;
+		}
+		match step {
+		    'x' => { hits.push((time, 1.0, Option::None)); },
+		    'X' => { hits.push((time, self.accent, Option::None)); },
+		    'g' | 'o' => { hits.push((time, self.ghost, Option::None)); },
+		    'f' | 'F' => {
+			hits.push((time, self.flam_volume, Option::Some(self.flam)));
+			hits.push((time, if *step == 'F' { self.accent } else { 1.0 }, Option::None));
+		    },
+		    '.' | '-' | '_' => {},
+		    huh => { eprint!("Unrecognised step: {}\n", huh); }
+		}
+	    }
+	}
+	hits
+    }
+}
@@ -0,0 +1,707 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::ParseError;
+use crate::sample::Sample;
+
+/*
+ProTracker MOD and FastTracker 2 XM modules, played row by row and tick by tick
+like a tracker would, into notes with their pitch, volume and panning over time.
+The effects covered are arpeggio (0), portamento (1, 2, 3 and 5), vibrato (4
+and 6), panning (8), volume slides (5, 6 and A), position jumps and pattern
+breaks (B and D), volume (C), fine slides and note cuts (E1, E2, EA, EB and EC)
+and speed (F), along with the volume column and volume envelopes of XM
+instruments. Anything else is ignored.
+*/
+
+const C4_RATE: f64 = 8363.0; // Hz a sample plays at for C-4 (C-2 in ProTracker), which is played as middle C
+const C4_PERIOD: f64 = 428.0; // Amiga period of C-4
+const KEY_OFF: u8 = 96;
+
+#[derive(Copy, Clone)]
+struct Cell {
+    note: Option<u8>, // Semitones from C-0, or KEY_OFF
+    instrument: usize, // From 1, 0 for none
+    volume: u8, // The XM volume column, 0 for nothing
+    effect: u8,
+    param: u8,
+}
+
+#[derive(Clone)]
+struct SampleInfo {
+    sample: Rc<Sample>,
+    volume: f64, // From 0 to 64
+    finetune: f64, // In 1/128ths of a semitone
+    relative: i32, // Semitones to transpose by
+    pan: Option<f64>, // From -1 to 1, or the channel's own
+}
+
+/// Volume envelope of an XM instrument.
+struct Envelope {
+    points: Vec<(u32, f64)>, // Tick and level from 0 to 1
+    sustain: Option<u32>, // Tick to hold at until the key is released
+    repeat: Option<(u32, u32)>, // Ticks to loop between
+}
+
+impl Envelope {
+    fn level (&self, tick: u32) -> f64 {
+	match self.points.iter().position(|e| e.0 > tick) {
+	    Option::Some(0) => { self.points[0].1 },
+	    Option::Some(i) => {
+		let (a, b): ((u32, f64), (u32, f64)) = (self.points[i - 1], self.points[i]);
+		a.1 + (b.1 - a.1) * (tick - a.0) as f64 / (b.0 - a.0) as f64
+	    },
+	    Option::None => { self.points.last().map_or(1.0, |e| e.1) }
+	}
+    }
+    /// The tick after this one.
+    fn advance (&self, tick: u32, released: bool) -> u32 {
+	if !released && self.sustain == Option::Some(tick) { return tick; }
+	match self.repeat {
+	    Option::Some((start, end)) if tick >= end => { start },
+	    _ => { tick + 1 }
+	}
+    }
+}
+
+struct Instrument {
+    samples: Vec<SampleInfo>,
+    keymap: [u8; 96], // Which sample plays each note
+    envelope: Option<Envelope>,
+    fadeout: f64, // Taken off the volume every tick after the key is released, from 1
+}
+
+struct Module {
+    title: String,
+    channels: usize,
+    orders: Vec<usize>,
+    patterns: Vec<Vec<Vec<Cell>>>, // Rows of cells for every channel
+    instruments: Vec<Instrument>,
+    xm: bool,
+    linear: bool, // XM linear frequencies instead of Amiga periods
+    speed: u32, // Ticks per row
+    bpm: u32, // Sets the length of a tick, 2.5 s / bpm
+    pans: Vec<f64>, // Starting pan of each channel
+}
+
+/// A note as the module played it, with times in seconds.
+pub struct TrackerNote {
+    pub channel: usize,
+    pub sample: Rc<Sample>,
+    pub time: f64,
+    pub duration: f64,
+    pub pitch: Vec<(f64, f64)>, // Time since the note started and frequency
+    pub volume: Vec<(f64, f64)>, // Time since the note started and level from 0 to 1
+    pub pan: Vec<(f64, f64)>, // Time since the note started and pan from -1 to 1
+}
+
+/// What a module played.
+pub struct Playback {
+    pub title: String,
+    pub tempo: f64, // Beats per minute, where a beat is 4 rows at the starting speed
+    pub channels: usize,
+    pub notes: Vec<TrackerNote>,
+}
+
+fn text (bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end_matches(['\0', ' ']).to_string()
+}
+
+fn u16_be (bytes: &[u8], at: usize) -> Result<usize, ParseError> {
+    bytes.get(at..at + 2).map(|e| u16::from_be_bytes([e[0], e[1]]) as usize).ok_or(ParseError)
+}
+
+fn u16_le (bytes: &[u8], at: usize) -> Result<usize, ParseError> {
+    bytes.get(at..at + 2).map(|e| u16::from_le_bytes([e[0], e[1]]) as usize).ok_or(ParseError)
+}
+
+fn u32_le (bytes: &[u8], at: usize) -> Result<usize, ParseError> {
+    bytes.get(at..at + 4).map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]]) as usize).ok_or(ParseError)
+}
+
+fn byte (bytes: &[u8], at: usize) -> Result<u8, ParseError> {
+    bytes.get(at).copied().ok_or(ParseError)
+}
+
+/// Makes a sample, keeping its loop inside it.
+fn sample (data: Vec<f32>, loop_start: usize, loop_length: usize, ping_pong: bool) -> Rc<Sample> {
+    let loop_start: usize = loop_start.min(data.len());
+    let loop_length: usize = loop_length.min(data.len() - loop_start);
+    Rc::new(Sample{data: data, loop_start: loop_start, loop_length: loop_length, ping_pong: ping_pong, cycle: C4_RATE / crate::score::midi_to_frequency(60.0)})
+}
+
+fn parse_mod (bytes: &[u8]) -> Result<Module, ParseError> {
+    let digit = |c: &u8| -> usize { (c - b'0') as usize };
+    let channels: usize = match bytes.get(1080..1084).unwrap_or(&[]) {
+	b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" | b"4CHN" | b"N.T." => { 4 },
+	b"FLT8" | b"OCTA" | b"CD81" => { 8 },
+	[n, b'C', b'H', b'N'] if n.is_ascii_digit() => { digit(n) },
+	[n, m, b'C', b'H'] if n.is_ascii_digit() && m.is_ascii_digit() => { digit(n) * 10 + digit(m) },
+	_ => { 0 }
+    };
+    // The oldest modules have 15 samples and no signature
+    let (sample_count, channels, orders_at, patterns_at): (usize, usize, usize, usize) = if channels == 0 { (15, 4, 470, 600) } else { (31, channels, 950, 1084) };
+    let length: usize = byte(bytes, orders_at)? as usize;
+    let table: &[u8] = bytes.get(orders_at + 2..orders_at + 130).ok_or(ParseError)?;
+    let orders: Vec<usize> = table[..length.min(128)].iter().map(|e| *e as usize).collect();
+    let pattern_count: usize = table.iter().max().map_or(0, |e| *e as usize + 1);
+
+    let mut patterns: Vec<Vec<Vec<Cell>>> = Vec::<Vec<Vec<Cell>>>::new();
+    for p in 0..pattern_count {
+	let mut rows: Vec<Vec<Cell>> = Vec::<Vec<Cell>>::new();
+	for row in 0..64 {
+	    let mut cells: Vec<Cell> = Vec::<Cell>::new();
+	    for channel in 0..channels {
+		let at: usize = patterns_at + ((p * 64 + row) * channels + channel) * 4;
+		let data: &[u8] = bytes.get(at..at + 4).ok_or(ParseError)?;
+		let period: usize = ((data[0] as usize & 0x0f) << 8) | data[1] as usize;
+		let note: Option<u8> = if period == 0 { Option::None } else { Option::Some((48.0 + 12.0 * (C4_PERIOD / period as f64).log2()).round().clamp(0.0, 95.0) as u8) };
+		cells.push(Cell{note: note, instrument: ((data[0] & 0xf0) | (data[2] >> 4)) as usize, volume: 0, effect: data[2] & 0x0f, param: data[3]});
+	    }
+	    rows.push(cells);
+	}
+	patterns.push(rows);
+    }
+
+    let mut instruments: Vec<Instrument> = Vec::<Instrument>::new();
+    let mut at: usize = patterns_at + pattern_count * 64 * channels * 4;
+    for i in 0..sample_count {
+	let header: usize = 20 + i * 30;
+	let length: usize = u16_be(bytes, header + 22)? * 2;
+	let finetune: i32 = ((byte(bytes, header + 24)? & 0x0f) as i32 ^ 8) - 8;
+	let volume: f64 = (byte(bytes, header + 25)? as f64).min(64.0);
+	let loop_start: usize = u16_be(bytes, header + 26)? * 2;
+	let loop_length: usize = u16_be(bytes, header + 28)? * 2;
+	let data: Vec<f32> = bytes.get(at..(at + length).min(bytes.len())).unwrap_or(&[]).iter().map(|e| *e as i8 as f32 / 128.0).collect();
+	at += length;
+	let info: SampleInfo = SampleInfo{sample: sample(data, loop_start, if loop_length > 2 { loop_length } else { 0 }, false), volume: volume, finetune: finetune as f64 * 16.0, relative: 0, pan: Option::None};
+	instruments.push(Instrument{samples: vec![info], keymap: [0; 96], envelope: Option::None, fadeout: 0.0});
+    }
+    // Amiga channels are hard left and right, which is softened here
+    let pans: Vec<f64> = (0..channels).map(|e| if e % 4 == 0 || e % 4 == 3 { -0.5 } else { 0.5 }).collect();
+    Ok(Module{title: text(&bytes[..20]), channels: channels, orders: orders, patterns: patterns, instruments: instruments, xm: false, linear: false, speed: 6, bpm: 125, pans: pans})
+}
+
+/// Undoes the delta coding of XM sample data.
+fn xm_sample (bytes: &[u8], sixteen_bit: bool) -> Vec<f32> {
+    if sixteen_bit {
+	let mut level: i16 = 0;
+	bytes.chunks_exact(2).map(|e| { level = level.wrapping_add(i16::from_le_bytes([e[0], e[1]])); level as f32 / 32768.0 }).collect()
+    } else {
+	let mut level: i8 = 0;
+	bytes.iter().map(|e| { level = level.wrapping_add(*e as i8); level as f32 / 128.0 }).collect()
+    }
+}
+
+/// A sample's header in an XM instrument, read before any of the instrument's sample data.
+struct XmSampleHeader {
+    length: usize, // In bytes
+    loop_start: usize, // In bytes
+    loop_length: usize, // In bytes
+    volume: u8, // From 0 to 64
+    finetune: i8, // In 1/128ths of a semitone
+    kind: u8, // Loop type in the low bits, 0x10 for 16 bits
+    pan: u8, // From 0 to 255
+    relative: i8, // Semitones to transpose by
+}
+
+fn parse_xm (bytes: &[u8]) -> Result<Module, ParseError> {
+    let header_size: usize = u32_le(bytes, 60)?;
+    let length: usize = u16_le(bytes, 64)?;
+    let channels: usize = u16_le(bytes, 68)?;
+    let pattern_count: usize = u16_le(bytes, 70)?;
+    let instrument_count: usize = u16_le(bytes, 72)?;
+    let linear: bool = u16_le(bytes, 74)? & 1 == 1;
+    let speed: u32 = u16_le(bytes, 76)? as u32;
+    let bpm: u32 = u16_le(bytes, 78)? as u32;
+    let orders: Vec<usize> = bytes.get(80..80 + length.min(256)).ok_or(ParseError)?.iter().map(|e| *e as usize).collect();
+
+    let mut at: usize = 60 + header_size;
+    let mut patterns: Vec<Vec<Vec<Cell>>> = Vec::<Vec<Vec<Cell>>>::new();
+    for _ in 0..pattern_count {
+	let rows: usize = u16_le(bytes, at + 5)?;
+	let packed: usize = u16_le(bytes, at + 7)?;
+	let mut data: &[u8] = bytes.get(at + u32_le(bytes, at)?..).ok_or(ParseError)?.get(..packed).ok_or(ParseError)?;
+	at += u32_le(bytes, at)? + packed;
+	let mut cells: Vec<Vec<Cell>> = vec![vec![Cell{note: Option::None, instrument: 0, volume: 0, effect: 0, param: 0}; channels]; rows];
+	for row in cells.iter_mut() {
+	    for cell in row.iter_mut() {
+		if data.is_empty() { break; }
+		// Either all five bytes, or a byte saying which follow
+		let flags: u8 = if data[0] & 0x80 != 0 {
+		    let flags: u8 = data[0] & 0x1f;
+		    data = &data[1..];
+		    flags
+		} else {
+		    0x1f
+		};
+		let mut next = || -> Result<u8, ParseError> {
+		    let value: u8 = *data.first().ok_or(ParseError)?;
+		    data = &data[1..];
+		    Ok(value)
+		};
+		let note: u8 = if flags & 1 != 0 { next()? } else { 0 };
+		cell.note = match note { 0 => { Option::None }, 97 => { Option::Some(KEY_OFF) }, n => { Option::Some((n - 1).min(95)) } };
+		if flags & 2 != 0 { cell.instrument = next()? as usize; }
+		if flags & 4 != 0 { cell.volume = next()?; }
+		if flags & 8 != 0 { cell.effect = next()?; }
+		if flags & 16 != 0 { cell.param = next()?; }
+	    }
+	}
+	patterns.push(cells);
+    }
+
+    let mut instruments: Vec<Instrument> = Vec::<Instrument>::new();
+    for _ in 0..instrument_count {
+	let size: usize = u32_le(bytes, at)?;
+	let sample_count: usize = u16_le(bytes, at + 27)?;
+	let mut instrument: Instrument = Instrument{samples: Vec::<SampleInfo>::new(), keymap: [0; 96], envelope: Option::None, fadeout: 0.0};
+	if sample_count == 0 {
+	    at += size;
+	    instruments.push(instrument);
+	    continue;
+	}
+	let sample_header_size: usize = u32_le(bytes, at + 29)?;
+	instrument.keymap.copy_from_slice(bytes.get(at + 33..at + 129).ok_or(ParseError)?);
+	let point_count: usize = (byte(bytes, at + 225)? as usize).min(12);
+	let kind: u8 = byte(bytes, at + 233)?;
+	if kind & 1 != 0 && point_count > 0 {
+	    let mut points: Vec<(u32, f64)> = Vec::<(u32, f64)>::new();
+	    for p in 0..point_count {
+		points.push((u16_le(bytes, at + 129 + p * 4)? as u32, u16_le(bytes, at + 131 + p * 4)?.min(64) as f64 / 64.0));
+	    }
+	    let tick = |point: u8| -> Option<u32> { points.get(point as usize).map(|e| e.0) };
+	    let sustain: Option<u32> = if kind & 2 != 0 { tick(byte(bytes, at + 227)?) } else { Option::None };
+	    let repeat: Option<(u32, u32)> = if kind & 4 != 0 { tick(byte(bytes, at + 228)?).zip(tick(byte(bytes, at + 229)?)) } else { Option::None };
+	    instrument.envelope = Option::Some(Envelope{points: points, sustain: sustain, repeat: repeat});
+	}
+	instrument.fadeout = u16_le(bytes, at + 239)? as f64 / 65536.0;
+	at += size;
+
+	let mut headers: Vec<XmSampleHeader> = Vec::<XmSampleHeader>::new();
+	for _ in 0..sample_count {
+	    headers.push(XmSampleHeader{
+		length: u32_le(bytes, at)?,
+		loop_start: u32_le(bytes, at + 4)?,
+		loop_length: u32_le(bytes, at + 8)?,
+		volume: byte(bytes, at + 12)?,
+		finetune: byte(bytes, at + 13)? as i8,
+		kind: byte(bytes, at + 14)?,
+		pan: byte(bytes, at + 15)?,
+		relative: byte(bytes, at + 16)? as i8,
+	    });
+	    at += sample_header_size;
+	}
+	for XmSampleHeader{length, loop_start, loop_length, volume, finetune, kind, pan, relative} in headers {
+	    let sixteen_bit: bool = kind & 0x10 != 0;
+	    let data: Vec<f32> = xm_sample(bytes.get(at..(at + length).min(bytes.len())).unwrap_or(&[]), sixteen_bit);
+	    at += length;
+	    let width: usize = if sixteen_bit { 2 } else { 1 };
+	    let looping: bool = kind & 3 != 0;
+	    instrument.samples.push(SampleInfo{
+		sample: sample(data, loop_start / width, if looping { loop_length / width } else { 0 }, kind & 3 == 2),
+		volume: (volume as f64).min(64.0),
+		finetune: finetune as f64,
+		relative: relative as i32,
+		pan: Option::Some((pan as f64 - 128.0) / 128.0),
+	    });
+	}
+	instruments.push(instrument);
+    }
+    Ok(Module{title: text(bytes.get(17..37).ok_or(ParseError)?), channels: channels, orders: orders, patterns: patterns, instruments: instruments, xm: true, linear: linear, speed: speed.max(1), bpm: bpm.max(1), pans: vec![0.0; channels]})
+}
+
+/// What a channel is playing.
+struct Channel {
+    note: Option<usize>, // Index of the note it's playing
+    instrument: usize, // From 1, 0 for none
+    sample: Option<SampleInfo>,
+    period: f64, // Amiga period, or XM linear period
+    target: f64, // Period the tone portamento slides to
+    volume: f64, // From 0 to 64
+    pan: f64,
+    memory: [u8; 16], // The last parameter of each effect, for ones that reuse it
+    vibrato: (u32, u32, u32), // Position from 0 to 63, speed and depth
+    envelope_tick: u32,
+    released: bool,
+    fade: f64, // Of the volume after the key is released, from 1 to 0
+}
+
+impl Module {
+    fn period (&self, note: f64, finetune: f64) -> f64 {
+	if self.linear { 7680.0 - note * 64.0 - finetune / 2.0 } else { C4_PERIOD * 2.0_f64.powf(-(note - 48.0 + finetune / 128.0) / 12.0) }
+    }
+    /// Frequency to play a sample at for a period, as the sample's cycles are set to play it at the right pitch.
+    fn frequency (&self, period: f64) -> f64 {
+	let rate: f64 = if self.linear { C4_RATE * 2.0_f64.powf((4608.0 - period) / 768.0) } else { C4_RATE * C4_PERIOD / period.max(1.0) };
+	rate * crate::score::midi_to_frequency(60.0) / C4_RATE
+    }
+    /// How much the slides move the period by per unit of their parameter.
+    fn slide (&self) -> f64 {
+	if self.linear { 4.0 } else { 1.0 }
+    }
+    fn sample_for (&self, instrument: usize, note: u8) -> Option<SampleInfo> {
+	let instrument: &Instrument = self.instruments.get(instrument.checked_sub(1)?)?;
+	instrument.samples.get(instrument.keymap[note as usize % 96] as usize).cloned()
+    }
+}
+
+fn end_note (notes: &mut [TrackerNote], channel: &mut Channel, time: f64) -> () {
+    if let Option::Some(index) = channel.note.take() {
+	notes[index].duration = time - notes[index].time;
+    }
+}
+
+/// Adds a point to a curve if the value has changed.
+fn record (points: &mut Vec<(f64, f64)>, time: f64, value: f64) -> () {
+    if points.last().is_none_or(|e| (e.1 - value).abs() > 1e-9) {
+	points.push((time, value));
+    }
+}
+
+/// Plays the module from the first order until it ends or starts repeating.
+fn play (module: &Module) -> Playback {
+    let mut notes: Vec<TrackerNote> = Vec::<TrackerNote>::new();
+    let mut channels: Vec<Channel> = (0..module.channels).map(|e| Channel{note: Option::None, instrument: 0, sample: Option::None, period: 0.0, target: 0.0, volume: 0.0, pan: module.pans[e], memory: [0; 16], vibrato: (0, 0, 0), envelope_tick: 0, released: false, fade: 1.0}).collect();
+    let mut speed: u32 = module.speed;
+    let mut bpm: u32 = module.bpm;
+    let tempo: f64 = 6.0 * bpm as f64 / speed as f64;
+    let mut time: f64 = 0.0; // In seconds
+    let mut visited: HashSet<(usize, usize)> = HashSet::<(usize, usize)>::new();
+    let (mut order, mut row): (usize, usize) = (0, 0);
+    'rows: while order < module.orders.len() {
+	let pattern: &Vec<Vec<Cell>> = match module.patterns.get(module.orders[order]) {
+	    Option::Some(pattern) if row < pattern.len() => { pattern },
+	    Option::Some(_) => { order += 1; row = 0; continue; },
+	    Option::None => { break; }
+	};
+	if !visited.insert((order, row)) { break; }
+	let mut jump: Option<usize> = Option::None;
+	let mut pattern_break: Option<usize> = Option::None;
+
+	// The first tick of the row starts notes and sets things
+	for (c, channel) in channels.iter_mut().enumerate() {
+	    let cell: Cell = pattern[row][c];
+	    // Portamentos and vibratos carry on with their last parameter, and in XM modules slides do too
+	    let remembered: bool = matches!(cell.effect, 3 | 4) || (module.xm && matches!(cell.effect, 1 | 2 | 5 | 6 | 0xa));
+	    let param: u8 = if cell.param == 0 && remembered { channel.memory[cell.effect as usize] } else { cell.param };
+	    if cell.effect < 16 { channel.memory[cell.effect as usize] = param; }
+	    let porta: bool = matches!(cell.effect, 3 | 5) || cell.volume >= 0xf0;
+	    if cell.instrument > 0 {
+		channel.instrument = cell.instrument;
+		if let Option::Some(info) = module.sample_for(cell.instrument, cell.note.filter(|e| *e < KEY_OFF).unwrap_or(48)) {
+		    channel.volume = info.volume;
+		    if let Option::Some(pan) = info.pan { channel.pan = pan; }
+		}
+		channel.envelope_tick = 0;
+		channel.released = false;
+		channel.fade = 1.0;
+	    }
+	    match cell.note {
+		Option::Some(KEY_OFF) => {
+		    channel.released = true;
+		    if module.instruments.get(channel.instrument.wrapping_sub(1)).is_none_or(|e| e.envelope.is_none()) {
+			end_note(&mut notes, channel, time);
+		    }
+		},
+		Option::Some(note) => {
+		    if let Option::Some(info) = module.sample_for(channel.instrument, note) {
+			let period: f64 = module.period((note as i32 + info.relative) as f64, info.finetune);
+			if porta && channel.note.is_some() {
+			    channel.target = period;
+			} else {
+			    end_note(&mut notes, channel, time);
+			    channel.period = period;
+			    channel.target = period;
+			    channel.vibrato.0 = 0;
+			    channel.envelope_tick = 0;
+			    channel.released = false;
+			    channel.fade = 1.0;
+			    channel.note = Option::Some(notes.len());
+			    notes.push(TrackerNote{channel: c, sample: info.sample.clone(), time: time, duration: 0.0, pitch: Vec::<(f64, f64)>::new(), volume: Vec::<(f64, f64)>::new(), pan: Vec::<(f64, f64)>::new()});
+			    channel.sample = Option::Some(info);
+			}
+		    }
+		},
+		Option::None => {}
+	    }
+	    match cell.volume {
+		0x10..=0x50 => { channel.volume = (cell.volume - 0x10) as f64; },
+		0x80..=0x8f => { channel.volume = (channel.volume - (cell.volume & 0x0f) as f64).max(0.0); },
+		0x90..=0x9f => { channel.volume = (channel.volume + (cell.volume & 0x0f) as f64).min(64.0); },
+		0xc0..=0xcf => { channel.pan = ((cell.volume & 0x0f) as f64 * 17.0 - 128.0) / 128.0; },
+		0xf0..=0xff if cell.volume & 0x0f != 0 => { channel.memory[3] = (cell.volume & 0x0f) << 4; },
+		_ => {}
+	    }
+	    match (cell.effect, param) {
+		(4, _) => {
+		    if param >> 4 != 0 { channel.vibrato.1 = (param >> 4) as u32; }
+		    if param & 0x0f != 0 { channel.vibrato.2 = (param & 0x0f) as u32; }
+		},
+		(8, _) => { channel.pan = (param as f64 - 128.0) / 128.0; },
+		(0xb, _) => { jump = Option::Some(param as usize); },
+		(0xc, _) => { channel.volume = (param as f64).min(64.0); },
+		(0xd, _) => { pattern_break = Option::Some((param >> 4) as usize * 10 + (param & 0x0f) as usize); },
+		(0xe, _) => {
+		    let value: f64 = (param & 0x0f) as f64;
+		    match param >> 4 {
+			1 => { channel.period = (channel.period - value * module.slide()).max(1.0); },
+			2 => { channel.period += value * module.slide(); },
+			0xa => { channel.volume = (channel.volume + value).min(64.0); },
+			0xb => { channel.volume = (channel.volume - value).max(0.0); },
+			_ => {}
+		    }
+		},
+		(0xf, 0) if !module.xm => { break 'rows; },
+		(0xf, 0) => {},
+		(0xf, 1..=31) => { speed = param as u32; },
+		(0xf, _) => { bpm = param as u32; },
+		_ => {}
+	    }
+	}
+
+	for tick in 0..speed {
+	    for (c, channel) in channels.iter_mut().enumerate() {
+		let cell: Cell = pattern[row][c];
+		let param: u8 = channel.memory[(cell.effect & 0x0f) as usize];
+		let mut vibrato: f64 = 0.0; // Period offset
+		let mut arpeggio: f64 = 0.0; // Semitones
+		if tick > 0 {
+		    match cell.effect {
+			1 => { channel.period = (channel.period - param as f64 * module.slide()).max(1.0); },
+			2 => { channel.period += param as f64 * module.slide(); },
+			_ => {}
+		    }
+		    if matches!(cell.effect, 3 | 5) || cell.volume >= 0xf0 {
+			let step: f64 = channel.memory[3] as f64 * module.slide();
+			channel.period = if channel.period < channel.target { (channel.period + step).min(channel.target) } else { (channel.period - step).max(channel.target) };
+		    }
+		    if matches!(cell.effect, 4 | 6) {
+			channel.vibrato.0 = (channel.vibrato.0 + channel.vibrato.1) % 64;
+		    }
+		    if matches!(cell.effect, 5 | 6 | 0xa) {
+			let slide: f64 = if param >> 4 != 0 { (param >> 4) as f64 } else { -((param & 0x0f) as f64) };
+			channel.volume = (channel.volume + slide).clamp(0.0, 64.0);
+		    }
+		    match cell.volume {
+			0x60..=0x6f => { channel.volume = (channel.volume - (cell.volume & 0x0f) as f64).max(0.0); },
+			0x70..=0x7f => { channel.volume = (channel.volume + (cell.volume & 0x0f) as f64).min(64.0); },
+			_ => {}
+		    }
+		    if cell.effect == 0xe && cell.param >> 4 == 0xc && tick == (cell.param & 0x0f) as u32 {
+			channel.volume = 0.0;
+		    }
+		}
+		if matches!(cell.effect, 4 | 6) {
+		    let sine: f64 = (255.0 * (channel.vibrato.0 as f64 * std::f64::consts::TAU / 64.0).sin()).trunc();
+		    vibrato = sine * channel.vibrato.2 as f64 / 128.0 * module.slide();
+		}
+		if cell.effect == 0 && cell.param != 0 {
+		    arpeggio = match tick % 3 { 1 => { (cell.param >> 4) as f64 }, 2 => { (cell.param & 0x0f) as f64 }, _ => { 0.0 } };
+		}
+
+		// The volume envelope and fade out
+		let mut level: f64 = channel.volume / 64.0;
+		if let Option::Some(instrument) = module.instruments.get(channel.instrument.wrapping_sub(1)) {
+		    if let Option::Some(envelope) = &instrument.envelope {
+			level *= envelope.level(channel.envelope_tick);
+			channel.envelope_tick = envelope.advance(channel.envelope_tick, channel.released);
+			if channel.released {
+			    level *= channel.fade;
+			    channel.fade = (channel.fade - instrument.fadeout).max(0.0);
+			}
+		    }
+		}
+		if let Option::Some(index) = channel.note {
+		    let note: &mut TrackerNote = &mut notes[index];
+		    let since: f64 = time - note.time;
+		    let frequency: f64 = module.frequency(channel.period + vibrato) * 2.0_f64.powf(arpeggio / 12.0);
+		    record(&mut note.pitch, since, frequency);
+		    record(&mut note.volume, since, level);
+		    record(&mut note.pan, since, channel.pan);
+		    if channel.released && channel.fade <= 0.0 {
+			end_note(&mut notes, channel, time);
+		    }
+		}
+	    }
+	    time += 2.5 / bpm as f64;
+	}
+
+	(order, row) = match (jump, pattern_break) {
+	    (Option::Some(jump), _) => { (jump, pattern_break.unwrap_or(0)) },
+	    (Option::None, Option::Some(pattern_break)) => { (order + 1, pattern_break) },
+	    (Option::None, Option::None) => { if row + 1 < pattern.len() { (order, row + 1) } else { (order + 1, 0) } }
+	};
+    }
+    for channel in channels.iter_mut() {
+	end_note(&mut notes, channel, time);
+    }
+    // One shots stop by themselves
+    for note in notes.iter_mut() {
+	let lowest: f64 = note.pitch.iter().fold(f64::INFINITY, |a, e| a.min(e.1));
+	note.duration = note.duration.min(note.sample.seconds(lowest));
+    }
+    notes.retain(|e| e.duration > 0.0 && !e.pitch.is_empty());
+    Playback{title: module.title.clone(), tempo: tempo, channels: module.channels, notes: notes}
+}
+
+/// Reads a MOD or XM module, by its header, and plays it.
+pub fn parse (bytes: &[u8]) -> Result<Playback, ParseError> {
+    let module: Module = if bytes.starts_with(b"Extended Module: ") { parse_xm(bytes)? } else { parse_mod(bytes)? };
+    Ok(play(&module))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::score::midi_to_frequency;
+
+    const TICK: f64 = 0.02; // Seconds, at 125 bpm
+
+    /// A curve's value at a time since the note started.
+    fn at (curve: &[(f64, f64)], time: f64) -> f64 {
+	curve.iter().rev().find(|e| e.0 <= time + 1e-9).unwrap().1
+    }
+
+    fn close (a: f64, b: f64) -> () {
+	assert!((a - b).abs() < 1e-6, "{} isn't {}", a, b);
+    }
+
+    /** A one channel MOD playing one pattern at speed 6, with a looping square wave for a sample.
+    @param cells The period, effect and parameter on each row, which plays sample 1 if there's a period
+    */
+    fn mod_file (cells: &[(u16, u8, u8)]) -> Vec<u8> {
+	let mut bytes: Vec<u8> = b"test".to_vec();
+	bytes.resize(20, 0);
+	for i in 0..31 {
+	    let mut header: [u8; 30] = [0; 30];
+	    if i == 0 {
+		header[22..24].copy_from_slice(&16u16.to_be_bytes()); // Length in words
+		header[25] = 64;
+		header[28..30].copy_from_slice(&16u16.to_be_bytes()); // Loop length in words
+	    }
+	    bytes.extend_from_slice(&header);
+	}
+	bytes.extend_from_slice(&[1, 127]);
+	bytes.extend_from_slice(&[0; 128]);
+	bytes.extend_from_slice(b"1CHN");
+	for row in 0..64 {
+	    let (period, effect, param): (u16, u8, u8) = cells.get(row).copied().unwrap_or((0, 0, 0));
+	    let instrument: u8 = if period > 0 { 1 } else { 0 };
+	    bytes.extend_from_slice(&[(period >> 8) as u8, period as u8, instrument << 4 | effect, param]);
+	}
+	bytes.extend((0..32).map(|e| if e < 16 { 0x60 } else { 0xa0 }));
+	bytes
+    }
+
+    /** A one channel XM with linear frequencies playing one pattern at speed 6, with a looping sample.
+    @param cells The note, volume column, effect and parameter on each row, which plays instrument 1 if there's a note
+    */
+    fn xm_file (cells: &[(u8, u8, u8, u8)]) -> Vec<u8> {
+	let mut bytes: Vec<u8> = b"Extended Module: test".to_vec();
+	bytes.resize(37, 0);
+	bytes.push(0x1a);
+	bytes.extend_from_slice(&[0; 20]);
+	bytes.extend_from_slice(&0x104u16.to_le_bytes());
+	bytes.extend_from_slice(&276u32.to_le_bytes()); // Header size, from here
+	for value in [1u16, 0, 1, 1, 1, 1, 6, 125] { bytes.extend_from_slice(&value.to_le_bytes()); }
+	bytes.extend_from_slice(&[0; 256]);
+
+	let data: Vec<u8> = cells.iter().flat_map(|(note, volume, effect, param)| [*note, if *note > 0 { 1 } else { 0 }, *volume, *effect, *param]).collect();
+	bytes.extend_from_slice(&9u32.to_le_bytes());
+	bytes.push(0);
+	bytes.extend_from_slice(&(cells.len() as u16).to_le_bytes());
+	bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+	bytes.extend_from_slice(&data);
+
+	let mut instrument: Vec<u8> = vec![0; 263];
+	instrument[0..4].copy_from_slice(&263u32.to_le_bytes());
+	instrument[27..29].copy_from_slice(&1u16.to_le_bytes());
+	instrument[29..33].copy_from_slice(&40u32.to_le_bytes());
+	bytes.extend_from_slice(&instrument);
+	let mut header: [u8; 40] = [0; 40];
+	header[0..4].copy_from_slice(&32u32.to_le_bytes());
+	header[8..12].copy_from_slice(&32u32.to_le_bytes());
+	header[12] = 64;
+	header[14] = 1; // Looping forwards
+	header[15] = 128;
+	bytes.extend_from_slice(&header);
+	// Deltas for a square wave
+	bytes.extend((0..32).map(|e| match e { 0 => { 0x60 }, 16 => { 0x40 }, _ => { 0 } }));
+	bytes
+    }
+
+    #[test]
+    fn mod_effects () {
+	let playback: Playback = parse(&mod_file(&[
+	    (428, 0x0, 0x47), // C with an arpeggio up 4 and 7 semitones
+	    (0, 0x1, 4), // Portamento up
+	    (0, 0x2, 2), // Portamento down
+	    (381, 0x3, 8), // Tone portamento to D
+	    (0, 0x4, 0x44), // Vibrato
+	    (0, 0xa, 0x02), // Volume slide down
+	    (0, 0xf, 0), // Stop
+	])).unwrap();
+	assert_eq!(playback.channels, 1);
+	assert_eq!(playback.notes.len(), 1);
+	let note: &TrackerNote = &playback.notes[0];
+	close(note.duration, 36.0 * TICK);
+	let frequency = |period: f64| -> f64 { 428.0 / period * midi_to_frequency(60.0) };
+	let pitch = |row: usize, tick: usize| -> f64 { at(&note.pitch, (row * 6 + tick) as f64 * TICK) };
+	let volume = |row: usize, tick: usize| -> f64 { at(&note.volume, (row * 6 + tick) as f64 * TICK) };
+
+	for (tick, semitones) in [0.0, 4.0, 7.0, 0.0, 4.0, 7.0].into_iter().enumerate() {
+	    close(pitch(0, tick), frequency(428.0) * 2.0_f64.powf(semitones / 12.0));
+	}
+	close(pitch(1, 0), frequency(428.0));
+	close(pitch(1, 5), frequency(408.0));
+	close(pitch(2, 5), frequency(418.0));
+	// Periods are read as the nearest note, so D is a little under 381
+	let d: f64 = 428.0 * 2.0_f64.powf(-2.0 / 12.0);
+	close(pitch(3, 1), frequency(410.0));
+	close(pitch(3, 4), frequency(386.0));
+	close(pitch(3, 5), frequency(d));
+	close(pitch(4, 0), frequency(d));
+	close(pitch(4, 1), frequency(d + 97.0 * 4.0 / 128.0));
+	for tick in 0..6 {
+	    close(volume(4, tick), 1.0);
+	    close(volume(5, tick), (64.0 - 2.0 * tick as f64) / 64.0);
+	}
+    }
+
+    #[test]
+    fn xm_effects () {
+	let playback: Playback = parse(&xm_file(&[
+	    (49, 0, 0x0, 0x37), // C-4 with an arpeggio up 3 and 7 semitones
+	    (0, 0, 0x1, 2), // Portamento up
+	    (0, 0, 0x1, 0), // The same again
+	    (51, 0, 0x3, 4), // Tone portamento to D-4
+	    (0, 0, 0x4, 0x28), // Vibrato
+	    (0, 0x30, 0xa, 0x20), // Volume 32 and a slide up
+	    (0, 0, 0xa, 0), // The same again
+	    (0, 0, 0x2, 1), // Portamento down
+	])).unwrap();
+	assert_eq!(playback.notes.len(), 1);
+	let note: &TrackerNote = &playback.notes[0];
+	close(note.duration, 48.0 * TICK);
+	let frequency = |period: f64| -> f64 { midi_to_frequency(60.0) * 2.0_f64.powf((4608.0 - period) / 768.0) };
+	let pitch = |row: usize, tick: usize| -> f64 { at(&note.pitch, (row * 6 + tick) as f64 * TICK) };
+	let volume = |row: usize, tick: usize| -> f64 { at(&note.volume, (row * 6 + tick) as f64 * TICK) };
+
+	for (tick, semitones) in [0.0, 3.0, 7.0, 0.0, 3.0, 7.0].into_iter().enumerate() {
+	    close(pitch(0, tick), midi_to_frequency(60.0 + semitones));
+	}
+	close(pitch(1, 5), frequency(4608.0 - 5.0 * 8.0));
+	close(pitch(2, 5), frequency(4608.0 - 10.0 * 8.0));
+	close(pitch(3, 1), frequency(4528.0 - 16.0));
+	close(pitch(3, 5), frequency(4480.0));
+	close(pitch(3, 5), midi_to_frequency(62.0));
+	close(pitch(4, 1), frequency(4480.0 + 49.0 * 8.0 / 128.0 * 4.0));
+	close(pitch(7, 5), frequency(4480.0 + 5.0 * 4.0));
+	close(volume(4, 5), 1.0);
+	for tick in 0..6 {
+	    close(volume(5, tick), (32.0 + 2.0 * tick as f64) / 64.0);
+	    close(volume(6, tick), (42.0 + 2.0 * tick as f64) / 64.0);
+	}
+    }
+}
@@ -0,0 +1,227 @@
+use crate::ParseError;
+
+/**
+Just enough XML for MusicXML: elements, attributes and text, with comments,
+processing instructions and the doctype skipped. Namespaces aren't resolved.
+*/
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    pub fn attribute (&self, name: &str) -> Option<&str> {
+	self.attributes.iter().find(|e| e.0 == name).map(|e| e.1.as_str())
+    }
+    /// The child elements, in order.
+    pub fn elements (&self) -> impl Iterator<Item = &Element> {
+	self.children.iter().filter_map(|e| match e { Node::Element(element) => { Option::Some(element) }, Node::Text(_) => { Option::None } })
+    }
+    /// The first child element with this name.
+    pub fn child (&self, name: &str) -> Option<&Element> {
+	self.elements().find(|e| e.name == name)
+    }
+    pub fn has (&self, name: &str) -> bool {
+	self.child(name).is_some()
+    }
+    /// All the text inside, trimmed.
+    pub fn text (&self) -> String {
+	let mut text: String = String::new();
+	for child in &self.children {
+	    match child {
+		Node::Element(element) => { text.push_str(&element.text()); },
+		Node::Text(s) => { text.push_str(s); }
+	    }
+	}
+	text.trim().to_string()
+    }
+    /// The text of the first child element with this name, parsed.
+    pub fn value<T: std::str::FromStr> (&self, name: &str) -> Option<T> {
+	self.child(name).and_then(|e| e.text().parse().ok())
+    }
+}
+
+/// Replaces the characters that can't appear in text or attribute values with entities.
+pub fn escape (s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+fn unescape (s: &str) -> String {
+    let mut out: String = String::with_capacity(s.len());
+    let mut rest: &str = s;
+    while let Option::Some(start) = rest.find('&') {
+	out.push_str(&rest[..start]);
+	rest = &rest[start..];
+	let end: usize = match rest.find(';') { Option::Some(end) => { end }, Option::None => { break; } };
+	let entity: &str = &rest[1..end];
+	let c: Option<char> = match entity {
+	    "amp" => { Option::Some('&') },
+	    "lt" => { Option::Some('<') },
+	    "gt" => { Option::Some('>') },
+	    "quot" => { Option::Some('"') },
+	    "apos" => { Option::Some('\'') },
+	    _ => {
+		let code: Option<u32> = if let Option::Some(hex) = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+		    u32::from_str_radix(hex, 16).ok()
+		} else {
+		    entity.strip_prefix('#').and_then(|e| e.parse().ok())
+		};
+		code.and_then(char::from_u32)
+	    }
+	};
+	match c {
+	    Option::Some(c) => {
+		out.push(c);
+		rest = &rest[end + 1..];
+	    },
+	    Option::None => {
+		// Leave unknown entities as they are
+		out.push('&');
+		rest = &rest[1..];
+	    }
+	}
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parses a document into its root element.
+pub fn parse (text: &str) -> Result<Element, ParseError> {
+    // The elements still open, innermost last, under a stand in for the document
+    let mut stack: Vec<Element> = vec![Element{name: String::new(), attributes: Vec::<(String, String)>::new(), children: Vec::<Node>::new()}];
+    let mut rest: &str = text.strip_prefix('\u{feff}').unwrap_or(text);
+    while !rest.is_empty() {
+	let start: usize = rest.find('<').unwrap_or(rest.len());
+	if start > 0 {
+	    let s: &str = &rest[..start];
+	    if stack.len() > 1 && !s.trim().is_empty() {
+		stack.last_mut().unwrap().children.push(Node::Text(unescape(s)));
+	    }
+	    rest = &rest[start..];
+	    continue;
+	}
+	if let Option::Some(comment) = rest.strip_prefix("<!--") {
+	    rest = &comment[comment.find("-->").ok_or(ParseError)? + 3..];
+	} else if let Option::Some(data) = rest.strip_prefix("<![CDATA[") {
+	    let end: usize = data.find("]]>").ok_or(ParseError)?;
+	    stack.last_mut().unwrap().children.push(Node::Text(data[..end].to_string()));
+	    rest = &data[end + 3..];
+	} else if rest.starts_with("<?") {
+	    rest = &rest[rest.find("?>").ok_or(ParseError)? + 2..];
+	} else if rest.starts_with("<!") {
+	    // The doctype, which may have an internal subset in brackets
+	    let mut depth: i32 = 0;
+	    let mut end: Option<usize> = Option::None;
+	    for (i, c) in rest.char_indices() {
+		match c {
+		    '[' => { depth += 1; },
+		    ']' => { depth -= 1; },
+		    '>' if depth == 0 => { end = Option::Some(i); break; },
+		    _ => {}
+		}
+	    }
+	    rest = &rest[end.ok_or(ParseError)? + 1..];
+	} else if let Option::Some(close) = rest.strip_prefix("</") {
+	    let end: usize = close.find('>').ok_or(ParseError)?;
+	    let name: &str = close[..end].trim();
+	    let element: Element = stack.pop().ok_or(ParseError)?;
+	    if element.name != name || stack.is_empty() { return Err(ParseError); }
+	    stack.last_mut().unwrap().children.push(Node::Element(element));
+	    rest = &close[end + 1..];
+	} else {
+	    // A start tag, reading up to its end outside of quotes
+	    let mut quote: Option<char> = Option::None;
+	    let mut end: Option<usize> = Option::None;
+	    for (i, c) in rest.char_indices().skip(1) {
+		match (quote, c) {
+		    (Option::None, '"' | '\'') => { quote = Option::Some(c); },
+		    (Option::Some(q), _) if q == c => { quote = Option::None; },
+		    (Option::None, '>') => { end = Option::Some(i); break; },
+		    _ => {}
+		}
+	    }
+	    let end: usize = end.ok_or(ParseError)?;
+	    let tag: &str = &rest[1..end];
+	    let (tag, empty): (&str, bool) = match tag.strip_suffix('/') { Option::Some(tag) => { (tag, true) }, Option::None => { (tag, false) } };
+	    let name_end: usize = tag.find(char::is_whitespace).unwrap_or(tag.len());
+	    let mut element: Element = Element{name: tag[..name_end].to_string(), attributes: Vec::<(String, String)>::new(), children: Vec::<Node>::new()};
+	    let mut attributes: &str = tag[name_end..].trim_start();
+	    while let Option::Some(equals) = attributes.find('=') {
+		let name: &str = attributes[..equals].trim();
+		let value: &str = attributes[equals + 1..].trim_start();
+		let q: char = value.chars().next().ok_or(ParseError)?;
+		if q != '"' && q != '\'' { return Err(ParseError); }
+		let value_end: usize = value[1..].find(q).ok_or(ParseError)? + 1;
+		element.attributes.push((name.to_string(), unescape(&value[1..value_end])));
+		attributes = value[value_end + 1..].trim_start();
+	    }
+	    if empty {
+		stack.last_mut().unwrap().children.push(Node::Element(element));
+	    } else {
+		stack.push(element);
+	    }
+	    rest = &rest[end + 1..];
+	}
+    }
+    if stack.len() != 1 { return Err(ParseError); }
+    stack.pop().unwrap().children.into_iter().find_map(|e| match e { Node::Element(element) => { Option::Some(element) }, Node::Text(_) => { Option::None } }).ok_or(ParseError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elements_and_attributes () {
+	let root: Element = parse("<?xml version=\"1.0\"?>\n<a x=\"1\" y='two > one'><b/><c z = \"3\">text</c></a>").unwrap();
+	assert_eq!(root.name, "a");
+	assert_eq!(root.attribute("x"), Option::Some("1"));
+	assert_eq!(root.attribute("y"), Option::Some("two > one"));
+	assert!(root.has("b"));
+	assert_eq!(root.child("c").unwrap().attribute("z"), Option::Some("3"));
+	assert_eq!(root.value::<String>("c"), Option::Some("text".to_string()));
+	assert_eq!(root.elements().map(|e| e.name.as_str()).collect::<Vec<&str>>(), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn entities () {
+	let root: Element = parse("<a t=\"&quot;&amp;&apos;\">&lt;b&gt; &#65;&#x42;&#X43; &unknown; &amp</a>").unwrap();
+	assert_eq!(root.attribute("t"), Option::Some("\"&'"));
+	assert_eq!(root.text(), "<b> ABC &unknown; &amp");
+	assert_eq!(unescape(&escape("<a href=\"x\">'&'</a>")), "<a href=\"x\">'&'</a>");
+    }
+
+    #[test]
+    fn cdata () {
+	let root: Element = parse("<a>one <![CDATA[<b>&amp;</b>]]> two</a>").unwrap();
+	assert_eq!(root.text(), "one <b>&amp;</b> two");
+	assert!(!root.has("b"));
+    }
+
+    #[test]
+    fn comments_and_doctype () {
+	let text: &str = "\u{feff}<!DOCTYPE a [<!ENTITY e \"x\">]>\n<!-- <b> isn't here -->\n<a><!-- nor --><c/></a>\n<!-- after -->";
+	let root: Element = parse(text).unwrap();
+	assert_eq!(root.name, "a");
+	assert_eq!(root.elements().count(), 1);
+	assert!(root.has("c"));
+    }
+
+    #[test]
+    fn broken_documents () {
+	assert!(parse("").is_err());
+	assert!(parse("<a>").is_err());
+	assert!(parse("<a></b>").is_err());
+	assert!(parse("</a>").is_err());
+	assert!(parse("<a><!-- unclosed</a>").is_err());
+	assert!(parse("<a><![CDATA[unclosed</a>").is_err());
+	assert!(parse("<a x=1></a>").is_err());
+	assert!(parse("<a x=\"1></a>").is_err());
+    }
+}
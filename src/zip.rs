@@ -0,0 +1,332 @@
+use crate::ParseError;
+
+/*
+Zip archives, for compressed MusicXML (.mxl). Reading handles stored and
+deflated files, and writing stores them without compression.
+*/
+
+/// Reads the bits of a deflate stream, least significant first.
+struct Bits<'a> {
+    bytes: &'a [u8],
+    position: usize, // In bits
+}
+
+impl<'a> Bits<'a> {
+    fn read (&mut self, count: u32) -> Result<u32, ParseError> {
+	let mut value: u32 = 0;
+	for i in 0..count {
+	    let byte: u8 = *self.bytes.get(self.position / 8).ok_or(ParseError)?;
+	    value |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+	    self.position += 1;
+	}
+	Ok(value)
+    }
+    fn align (&mut self) -> () {
+	self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new (lengths: &[u8]) -> Self {
+	let mut counts: [u16; 16] = [0; 16];
+	for length in lengths { counts[*length as usize] += 1; }
+	counts[0] = 0;
+	let mut offsets: [u16; 16] = [0; 16];
+	for i in 1..15 { offsets[i + 1] = offsets[i] + counts[i]; }
+	let mut symbols: Vec<u16> = vec![0; lengths.len()];
+	for (symbol, length) in lengths.iter().enumerate() {
+	    if *length != 0 {
+		symbols[offsets[*length as usize] as usize] = symbol as u16;
+		offsets[*length as usize] += 1;
+	    }
+	}
+	Self{counts: counts, symbols: symbols}
+    }
+    fn decode (&self, bits: &mut Bits) -> Result<u16, ParseError> {
+	let mut code: i32 = 0; // The code read so far
+	let mut first: i32 = 0; // The first code of this length
+	let mut index: i32 = 0; // Where the codes of this length start among the symbols
+	for length in 1..16 {
+	    code |= bits.read(1)? as i32;
+	    let count: i32 = self.counts[length] as i32;
+	    if code - count < first {
+		return self.symbols.get((index + code - first) as usize).copied().ok_or(ParseError);
+	    }
+	    index += count;
+	    first = (first + count) << 1;
+	    code <<= 1;
+	}
+	Err(ParseError)
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Decompresses raw deflate data (RFC 1951).
+fn inflate (data: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let mut bits: Bits = Bits{bytes: data, position: 0};
+    let mut out: Vec<u8> = Vec::<u8>::new();
+    loop {
+	let last: bool = bits.read(1)? == 1;
+	match bits.read(2)? {
+	    0 => {
+		bits.align();
+		let start: usize = bits.position / 8;
+		let header: &[u8] = data.get(start..start + 4).ok_or(ParseError)?;
+		let length: usize = u16::from_le_bytes([header[0], header[1]]) as usize;
+		out.extend_from_slice(data.get(start + 4..start + 4 + length).ok_or(ParseError)?);
+		bits.position = (start + 4 + length) * 8;
+	    },
+	    kind @ (1 | 2) => {
+		let (literals, distances): (Huffman, Huffman) = if kind == 1 {
+		    let mut lengths: [u8; 288] = [8; 288];
+		    lengths[144..256].fill(9);
+		    lengths[256..280].fill(7);
+		    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+		} else {
+		    let literal_count: usize = bits.read(5)? as usize + 257;
+		    let distance_count: usize = bits.read(5)? as usize + 1;
+		    let code_count: usize = bits.read(4)? as usize + 4;
+		    let order: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+		    let mut code_lengths: [u8; 19] = [0; 19];
+		    for i in 0..code_count { code_lengths[order[i]] = bits.read(3)? as u8; }
+		    let code: Huffman = Huffman::new(&code_lengths);
+		    let mut lengths: Vec<u8> = Vec::<u8>::with_capacity(literal_count + distance_count);
+		    while lengths.len() < literal_count + distance_count {
+			let (value, repeat): (u8, u32) = match code.decode(&mut bits)? {
+			    symbol @ 0..=15 => { (symbol as u8, 1) },
+			    16 => { (*lengths.last().ok_or(ParseError)?, 3 + bits.read(2)?) },
+			    17 => { (0, 3 + bits.read(3)?) },
+			    _ => { (0, 11 + bits.read(7)?) }
+			};
+			for _ in 0..repeat { lengths.push(value); }
+		    }
+		    if lengths.len() > literal_count + distance_count { return Err(ParseError); }
+		    (Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..]))
+		};
+		loop {
+		    let symbol: usize = literals.decode(&mut bits)? as usize;
+		    if symbol < 256 {
+			out.push(symbol as u8);
+		    } else if symbol == 256 {
+			break;
+		    } else {
+			let symbol: usize = symbol - 257;
+			if symbol >= 29 { return Err(ParseError); }
+			let length: usize = LENGTH_BASE[symbol] as usize + bits.read(LENGTH_EXTRA[symbol] as u32)? as usize;
+			let symbol: usize = distances.decode(&mut bits)? as usize;
+			if symbol >= 30 { return Err(ParseError); }
+			let distance: usize = DISTANCE_BASE[symbol] as usize + bits.read(DISTANCE_EXTRA[symbol] as u32)? as usize;
+			if distance > out.len() { return Err(ParseError); }
+			for _ in 0..length { out.push(out[out.len() - distance]); }
+		    }
+		}
+	    },
+	    _ => { return Err(ParseError); }
+	}
+	if last { break; }
+    }
+    Ok(out)
+}
+
+fn u16_at (bytes: &[u8], at: usize) -> Result<usize, ParseError> {
+    bytes.get(at..at + 2).map(|e| u16::from_le_bytes([e[0], e[1]]) as usize).ok_or(ParseError)
+}
+
+fn u32_at (bytes: &[u8], at: usize) -> Result<usize, ParseError> {
+    bytes.get(at..at + 4).map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]]) as usize).ok_or(ParseError)
+}
+
+/// The names of the files in an archive, with where their entries in the central directory start.
+fn entries (bytes: &[u8]) -> Result<Vec<(String, usize)>, ParseError> {
+    // The end of central directory record is last, before a comment of up to 64 kB
+    let end: usize = (0..bytes.len().saturating_sub(21)).rev().take(65536 + 22).find(|i| bytes[*i..].starts_with(&[0x50, 0x4b, 0x05, 0x06])).ok_or(ParseError)?;
+    let count: usize = u16_at(bytes, end + 10)?;
+    let mut at: usize = u32_at(bytes, end + 16)?;
+    let mut entries: Vec<(String, usize)> = Vec::<(String, usize)>::new();
+    for _ in 0..count {
+	if !bytes.get(at..).is_some_and(|e| e.starts_with(&[0x50, 0x4b, 0x01, 0x02])) { return Err(ParseError); }
+	let name_length: usize = u16_at(bytes, at + 28)?;
+	let name: &[u8] = bytes.get(at + 46..at + 46 + name_length).ok_or(ParseError)?;
+	entries.push((String::from_utf8_lossy(name).into_owned(), at));
+	at += 46 + name_length + u16_at(bytes, at + 30)? + u16_at(bytes, at + 32)?;
+    }
+    Ok(entries)
+}
+
+/// The names of the files in an archive.
+pub fn names (bytes: &[u8]) -> Result<Vec<String>, ParseError> {
+    entries(bytes).map(|e| e.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Reads a file out of an archive.
+pub fn read (bytes: &[u8], name: &str) -> Result<Vec<u8>, ParseError> {
+    let entry: usize = entries(bytes)?.into_iter().find(|e| e.0 == name).ok_or(ParseError)?.1;
+    let method: usize = u16_at(bytes, entry + 10)?;
+    let size: usize = u32_at(bytes, entry + 20)?;
+    let local: usize = u32_at(bytes, entry + 42)?;
+    if !bytes.get(local..).is_some_and(|e| e.starts_with(&[0x50, 0x4b, 0x03, 0x04])) { return Err(ParseError); }
+    let start: usize = local + 30 + u16_at(bytes, local + 26)? + u16_at(bytes, local + 28)?;
+    let data: &[u8] = bytes.get(start..start + size).ok_or(ParseError)?;
+    match method {
+	0 => { Ok(data.to_vec()) },
+	8 => { inflate(data) },
+	_ => { Err(ParseError) }
+    }
+}
+
+fn crc32 (bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffffffff;
+    for byte in bytes {
+	crc ^= *byte as u32;
+	for _ in 0..8 {
+	    crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+	}
+    }
+    !crc
+}
+
+/// Makes an archive of these files, stored uncompressed.
+pub fn write (files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::<u8>::new();
+    let mut directory: Vec<u8> = Vec::<u8>::new();
+    for (name, data) in files {
+	let offset: u32 = bytes.len() as u32;
+	// Version needed, flags (UTF-8 names), method, time and date
+	let mut common: Vec<u8> = vec![20, 0, 0, 8, 0, 0, 0, 0, 0x21, 0];
+	common.extend_from_slice(&crc32(data).to_le_bytes());
+	common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+	common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+	common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+	common.extend_from_slice(&[0, 0]); // Extra field length
+
+	bytes.extend_from_slice(&[0x50, 0x4b, 0x03, 0x04]);
+	bytes.extend_from_slice(&common);
+	bytes.extend_from_slice(name.as_bytes());
+	bytes.extend_from_slice(data);
+
+	directory.extend_from_slice(&[0x50, 0x4b, 0x01, 0x02, 20, 0]);
+	directory.extend_from_slice(&common);
+	directory.extend_from_slice(&[0; 10]); // Comment length, disk, attributes
+	directory.extend_from_slice(&offset.to_le_bytes());
+	directory.extend_from_slice(name.as_bytes());
+    }
+    let directory_start: u32 = bytes.len() as u32;
+    bytes.extend_from_slice(&directory);
+    bytes.extend_from_slice(&[0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0]);
+    bytes.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&directory_start.to_le_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made with Python's zlib, as raw deflate streams
+    const STORED: [u8; 18] = [0x01, 0x0d, 0x00, 0xf2, 0xff, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64];
+    const FIXED: [u8; 15] = [0x4b, 0x4c, 0x4a, 0x4e, 0x84, 0x21, 0x85, 0x8c, 0xd4, 0x9c, 0x9c, 0x7c, 0x08, 0x09, 0x00];
+    const DYNAMIC_TEXT: &str = "Zip archives, for compressed MusicXML (.mxl). Reading handles stored and deflated files, and writing stores them without compression. Just enough XML for MusicXML: elements, attributes and text, with comments, processing instructions and the doctype skipped.";
+    const DYNAMIC: [u8; 169] = [
+	0x3d, 0x8e, 0x4b, 0x0e, 0xc2, 0x30, 0x0c, 0x05, 0xaf, 0xf2, 0x96, 0x20, 0x55, 0x3d, 0x00, 0x47,
+	0x40, 0xb0, 0x61, 0x85, 0xd8, 0x95, 0xc4, 0x25, 0x16, 0x69, 0x12, 0xc5, 0x0e, 0x9f, 0xdb, 0x13,
+	0x57, 0xc0, 0xd2, 0xd6, 0x78, 0xc6, 0x17, 0x2e, 0x98, 0xaa, 0x0b, 0xfc, 0x20, 0x19, 0x30, 0xe7,
+	0x0a, 0x97, 0x97, 0x52, 0x49, 0x84, 0x3c, 0x8e, 0x4d, 0xd8, 0x9d, 0x8f, 0x07, 0x6c, 0xc6, 0xe5,
+	0x15, 0xb7, 0x23, 0x4e, 0x34, 0x79, 0x4e, 0x37, 0x84, 0x29, 0xf9, 0x48, 0x02, 0xd1, 0x5c, 0x3b,
+	0xd7, 0x27, 0x78, 0x9a, 0xe3, 0xa4, 0x7d, 0x98, 0x39, 0x9a, 0xca, 0x76, 0xcf, 0xca, 0x6a, 0xf8,
+	0x8a, 0x09, 0x34, 0xd0, 0x82, 0x27, 0x6b, 0xc8, 0x4d, 0xff, 0x19, 0xce, 0x69, 0xc4, 0xbe, 0x89,
+	0x82, 0x52, 0x6e, 0xb7, 0x00, 0xeb, 0xd9, 0x1f, 0xbf, 0xf8, 0x0e, 0x14, 0x69, 0xa1, 0xa4, 0x26,
+	0x55, 0xad, 0x7c, 0x6d, 0xda, 0x65, 0xe6, 0x57, 0x7a, 0xe9, 0xb0, 0x1a, 0x4d, 0xf7, 0x65, 0x4a,
+	0xcd, 0xce, 0xbc, 0xbd, 0xcb, 0x49, 0xb4, 0x36, 0xa7, 0xbd, 0xf1, 0x3d, 0x08, 0x04, 0x9f, 0x9d,
+	0xbe, 0x0b, 0x41, 0xee, 0x5c, 0x0a, 0xf9, 0xf1, 0x03,
+    ];
+    // Made with Python's zipfile writing to a pipe, so the sizes and CRC come after the data
+    const DESCRIPTOR_ZIP: [u8; 138] = [
+	0x50, 0x4b, 0x03, 0x04, 0x14, 0x00, 0x08, 0x00, 0x08, 0x00, 0x00, 0x00, 0x21, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x61, 0x2e,
+	0x74, 0x78, 0x74, 0x2b, 0x2e, 0x29, 0x4a, 0x4d, 0xcc, 0x4d, 0x4d, 0x51, 0x28, 0x46, 0x67, 0x00,
+	0x00, 0x50, 0x4b, 0x07, 0x08, 0x24, 0xe4, 0x03, 0x8b, 0x0e, 0x00, 0x00, 0x00, 0x1a, 0x00, 0x00,
+	0x00, 0x50, 0x4b, 0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x08, 0x00, 0x08, 0x00, 0x00, 0x00, 0x21,
+	0x00, 0x24, 0xe4, 0x03, 0x8b, 0x0e, 0x00, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x61,
+	0x2e, 0x74, 0x78, 0x74, 0x50, 0x4b, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00,
+	0x33, 0x00, 0x00, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn stored_block () {
+	assert_eq!(inflate(&STORED).unwrap(), b"hello, stored");
+    }
+
+    #[test]
+    fn fixed_block () {
+	assert_eq!(inflate(&FIXED).unwrap(), b"abcabcabcabc hello hello");
+    }
+
+    #[test]
+    fn dynamic_block () {
+	assert_eq!(inflate(&DYNAMIC).unwrap(), DYNAMIC_TEXT.as_bytes());
+    }
+
+    #[test]
+    fn blocks_one_after_another () {
+	// A stored block that isn't the last, then the fixed one
+	let mut data: Vec<u8> = vec![0x00, 0x06, 0x00, 0xf9, 0xff];
+	data.extend_from_slice(b"first ");
+	data.extend_from_slice(&FIXED);
+	assert_eq!(inflate(&data).unwrap(), b"first abcabcabcabc hello hello");
+    }
+
+    #[test]
+    fn broken_streams () {
+	assert!(inflate(&[]).is_err());
+	assert!(inflate(&STORED[..10]).is_err());
+	assert!(inflate(&DYNAMIC[..80]).is_err());
+	// Block type 3 is reserved
+	assert!(inflate(&[0x07]).is_err());
+	// A fixed block copying from before the start
+	assert!(inflate(&[0x03, 0x02, 0x00]).is_err());
+    }
+
+    #[test]
+    fn data_descriptor () {
+	assert_eq!(names(&DESCRIPTOR_ZIP).unwrap(), vec!["a.txt".to_string()]);
+	assert_eq!(read(&DESCRIPTOR_ZIP, "a.txt").unwrap(), b"streamed streamed streamed");
+	assert!(read(&DESCRIPTOR_ZIP, "b.txt").is_err());
+    }
+
+    #[test]
+    fn write_and_read () {
+	let archive: Vec<u8> = write(&[("mimetype", b"text/plain".as_slice()), ("dir/empty", b"".as_slice()), ("dir/song.txt", b"NOTE time=0\n".as_slice())]);
+	assert_eq!(names(&archive).unwrap(), vec!["mimetype".to_string(), "dir/empty".to_string(), "dir/song.txt".to_string()]);
+	assert_eq!(read(&archive, "mimetype").unwrap(), b"text/plain");
+	assert_eq!(read(&archive, "dir/empty").unwrap(), b"");
+	assert_eq!(read(&archive, "dir/song.txt").unwrap(), b"NOTE time=0\n");
+    }
+
+    #[test]
+    fn crc () {
+	assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn broken_archives () {
+	assert!(names(b"not a zip").is_err());
+	assert!(names(&DESCRIPTOR_ZIP[..100]).is_err());
+	let mut truncated: Vec<u8> = DESCRIPTOR_ZIP.to_vec();
+	truncated.drain(30..60);
+	assert!(read(&truncated, "a.txt").is_err());
+    }
+}